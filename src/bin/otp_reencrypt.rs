@@ -0,0 +1,37 @@
+extern crate api;
+use api::model;
+use dotenvy::dotenv;
+use tokio_postgres::NoTls;
+
+// Encrypt every otp secret with the current OTP_ENCRYPTION_KEY, to run after a key
+// rotation before removing the previous key from OTP_ENCRYPTION_PREVIOUS_KEYS
+#[actix_web::main]
+async fn main() {
+    match dotenv() {
+        Ok(_) => println!("Loaded .env file"),
+        Err(_) => println!("No .env file found"),
+    }
+    let db_config = model::db::DbConfig::new();
+    let dbpool = match model::db::DbConfig::get_tls_connector() {
+        Some(connector) => db_config
+            .pg
+            .create_pool(None, connector)
+            .expect("Failed to create pool"),
+        None => db_config
+            .pg
+            .create_pool(None, NoTls)
+            .expect("Failed to create pool without tls"),
+    };
+    let otp_keys = model::otp::OtpKeys::new().expect("Failed to load otp keys");
+    match model::user::User::encrypt_otp_secrets(dbpool, &otp_keys, true).await {
+        Ok(nbr) => println!(
+            "{} otp secrets encrypted with the key {}",
+            nbr,
+            otp_keys.current_kid()
+        ),
+        Err(e) => {
+            eprintln!("Error encrypting otp secrets: {}", e);
+            std::process::exit(1);
+        }
+    }
+}
@@ -1,58 +1,81 @@
 use super::super::route::auth::info::AuthType;
-use actix_web::{http::header::ContentType, HttpRequest, HttpResponse};
+use crate::model::{token::TokenKeys, trusted_proxy::TrustedProxies};
+use actix_web::{
+    http::header::{ContentType, USER_AGENT},
+    web, HttpRequest, HttpResponse,
+};
 
+// Extract the bearer token and its type, the Authorization-type header is only a hint,
+// without it the type is detected from the token
 pub fn extract_authorization_type_header(
     req: &HttpRequest,
 ) -> Result<(&str, AuthType), HttpResponse> {
     let header = req.headers();
-    let token_type = match header.get("Authorization-type") {
-        Some(token_type) => match token_type.to_str() {
-            Ok(token_type) => {
-                if token_type.to_lowercase().eq("oidc") {
-                    AuthType::Oidc
-                } else if token_type.to_lowercase().eq("buildin") {
-                    AuthType::BuildIn
+    let token = match header.get("Authorization") {
+        Some(token) => match token.to_str() {
+            Ok(token) => {
+                if let Some(end) = token.strip_prefix("Bearer ") {
+                    end
                 } else {
                     return Err(HttpResponse::Unauthorized()
                         .content_type(ContentType::plaintext())
-                        .body("Invalid token type"));
+                        .body("Invalid token"));
                 }
             }
             Err(err) => {
                 return Err(HttpResponse::Unauthorized()
                     .content_type(ContentType::plaintext())
-                    .body(format!("Invalid token type: {}", err)))
+                    .body(format!("Invalid token: {}", err)))
             }
         },
         None => {
             return Err(HttpResponse::Unauthorized()
                 .content_type(ContentType::plaintext())
-                .body("No token type provided"))
+                .body("No token provided"))
         }
     };
 
-    let token = match header.get("Authorization") {
-        Some(token) => match token.to_str() {
-            Ok(token) => {
-                if let Some(end) = token.strip_prefix("Bearer ") {
-                    end
+    let token_type = match header.get("Authorization-type") {
+        Some(token_type) => match token_type.to_str() {
+            Ok(token_type) => {
+                if token_type.to_lowercase().eq("oidc") {
+                    AuthType::Oidc
+                } else if token_type.to_lowercase().eq("buildin") {
+                    AuthType::BuildIn
                 } else {
                     return Err(HttpResponse::Unauthorized()
                         .content_type(ContentType::plaintext())
-                        .body("Invalid token"));
+                        .body("Invalid token type"));
                 }
             }
             Err(err) => {
                 return Err(HttpResponse::Unauthorized()
                     .content_type(ContentType::plaintext())
-                    .body(format!("Invalid token: {}", err)))
+                    .body(format!("Invalid token type: {}", err)))
             }
         },
-        None => {
-            return Err(HttpResponse::Unauthorized()
-                .content_type(ContentType::plaintext())
-                .body("No token provided"))
-        }
+        None => match req.app_data::<web::Data<TokenKeys>>() {
+            Some(token_keys) if token_keys.is_buildin_token(token) => AuthType::BuildIn,
+            _ => AuthType::Oidc,
+        },
     };
     Ok((token, token_type))
 }
+
+// User agent and ip of the client, used to describe a session and count the failed logins.
+// The forwarded headers are only read when the peer is a trusted proxy
+pub fn extract_client_info(req: &HttpRequest) -> (Option<String>, Option<String>) {
+    let user_agent = req
+        .headers()
+        .get(USER_AGENT)
+        .and_then(|user_agent| user_agent.to_str().ok())
+        .map(|user_agent| user_agent.to_string());
+    let ip = req.peer_addr().map(|peer| {
+        match req.app_data::<web::Data<TrustedProxies>>() {
+            Some(trusted_proxies) => trusted_proxies.client_ip(peer.ip(), req.headers()),
+            None => peer.ip(),
+        }
+        .to_string()
+    });
+    (user_agent, ip)
+}
@@ -1,3 +1,6 @@
+use std::{fs, io::ErrorKind, path::Path};
+
+use openssl::sha::sha1;
 use regex::Regex;
 
 pub fn validate_email(email: String) -> bool {
@@ -5,31 +8,107 @@ pub fn validate_email(email: String) -> bool {
     re.is_match(&email)
 }
 
-// validate password
-// 1. length >= 3
-// 2. length <= 20
-// 3. at least one number
-// 4. at least one lowercase letter
-// 5. at least one uppercase letter
-pub fn validate_password(password: String) -> bool {
-    if password.len() < 3 || password.len() > 20 {
-        return false;
-    }
-    if !password.chars().any(|c| c.is_numeric()) {
-        return false;
-    }
-    if !password.chars().any(|c| c.is_lowercase()) {
-        return false;
-    }
-    if !password.chars().any(|c| c.is_uppercase()) {
-        return false;
-    }
-    true
-}
-
 // validate name and surname
 // 1. length >= 2
 pub fn validate_name(name: String) -> bool {
     let re = Regex::new(r"^[a-zA-Z-\s]{2,}$").unwrap();
     re.is_match(&name)
 }
+
+// check if the password is in a breach corpus using the HIBP k-anonymity layout,
+// one file per 5 hex characters prefix of the SHA-1 (named PREFIX or PREFIX.txt)
+// with a SUFFIX:COUNT line per hash, a missing prefix file means not breached
+pub fn is_breached_password(
+    corpus_dir: &Path,
+    password: &str,
+    min_count: u64,
+) -> Result<bool, String> {
+    let hash: String = sha1(password.as_bytes())
+        .iter()
+        .map(|byte| format!("{:02X}", byte))
+        .collect();
+    let (prefix, suffix) = hash.split_at(5);
+    // Only a missing file falls back to the .txt one, an unreadable file is an error
+    let content = match fs::read_to_string(corpus_dir.join(prefix)).or_else(|err| {
+        if err.kind() == ErrorKind::NotFound {
+            fs::read_to_string(corpus_dir.join(format!("{}.txt", prefix)))
+        } else {
+            Err(err)
+        }
+    }) {
+        Ok(content) => content,
+        Err(err) if err.kind() == ErrorKind::NotFound => return Ok(false),
+        Err(err) => return Err(format!("Can't read breach corpus {}: {}", prefix, err)),
+    };
+    Ok(content.lines().any(|line| {
+        let (line_suffix, count) = line.trim().split_once(':').unwrap_or((line.trim(), "1"));
+        line_suffix.eq_ignore_ascii_case(suffix)
+            && count.trim().parse::<u64>().unwrap_or(1) >= min_count
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::is_breached_password;
+    use std::{fs, path::PathBuf};
+
+    // SHA-1 of "password" is 5BAA61E4C9B93F3F0682250B6CF8331B7EE68FD8
+    const PREFIX: &str = "5BAA6";
+    const SUFFIX: &str = "1E4C9B93F3F0682250B6CF8331B7EE68FD8";
+
+    fn corpus_dir(name: &str) -> PathBuf {
+        let dir =
+            std::env::temp_dir().join(format!("breach-corpus-{}-{}", name, uuid::Uuid::new_v4()));
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[test]
+    fn breached_with_the_count() {
+        let dir = corpus_dir("count");
+        fs::write(
+            dir.join(PREFIX),
+            format!("0018A45C4D1DEF81644B54AB7F969B88D65:3\r\n{}:12\r\n", SUFFIX),
+        )
+        .unwrap();
+        assert_eq!(is_breached_password(&dir, "password", 1), Ok(true));
+        assert_eq!(is_breached_password(&dir, "password", 12), Ok(true));
+        assert_eq!(is_breached_password(&dir, "password", 13), Ok(false));
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn suffix_case_and_missing_count() {
+        let dir = corpus_dir("case");
+        fs::write(dir.join(PREFIX), SUFFIX.to_lowercase()).unwrap();
+        assert_eq!(is_breached_password(&dir, "password", 1), Ok(true));
+        assert_eq!(is_breached_password(&dir, "password", 2), Ok(false));
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn missing_prefix_is_not_breached() {
+        let dir = corpus_dir("missing");
+        assert_eq!(is_breached_password(&dir, "password", 1), Ok(false));
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn falls_back_to_the_txt_file() {
+        let dir = corpus_dir("txt");
+        fs::write(dir.join(format!("{}.txt", PREFIX)), format!("{}:1", SUFFIX)).unwrap();
+        assert_eq!(is_breached_password(&dir, "password", 1), Ok(true));
+        assert_eq!(is_breached_password(&dir, "Password", 1), Ok(false));
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn unreadable_prefix_is_an_error() {
+        let dir = corpus_dir("unreadable");
+        // The .txt file is only used when the prefix file doesn't exist
+        fs::create_dir(dir.join(PREFIX)).unwrap();
+        fs::write(dir.join(format!("{}.txt", PREFIX)), format!("{}:1", SUFFIX)).unwrap();
+        assert!(is_breached_password(&dir, "password", 1).is_err());
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -1,6 +1,7 @@
 use crate::helper::tracing::init_telemetry;
 use crate::route::apidoc::ApiDoc;
 use crate::route::health::{health, hello};
+use crate::route::well_known::jwks;
 use actix_cors::Cors;
 use actix_web::dev::Service as _;
 use actix_web::http::header;
@@ -41,8 +42,42 @@ async fn main() -> std::io::Result<()> {
             .create_pool(None, NoTls)
             .expect("Failed to create pool without tls"),
     };
+    println!("Loading token keys");
+    let token_keys = model::token::TokenKeys::new().expect("Failed to load token keys");
+    let token_lifetime =
+        model::token::TokenLifetime::new().expect("Failed to load token lifetimes");
+    let otp_config = model::otp::OtpConfig::new().expect("Failed to load otp config");
+    let trusted_device_config = model::trusted_device::TrustedDeviceConfig::new()
+        .expect("Failed to load trusted device config");
+    let password_reset_config = model::password_reset::PasswordResetConfig::new()
+        .expect("Failed to load password reset config");
+    let mailer = model::mailer::Mailer::new().expect("Failed to load mailer config");
+    let email_verification_config = model::email_verification::EmailVerificationConfig::new()
+        .expect("Failed to load email verification config");
+    let password_policy =
+        model::password_policy::PasswordPolicy::new().expect("Failed to load password policy");
+    let password_hash_config = model::password_hash::PasswordHashConfig::new()
+        .expect("Failed to load password hash config");
+    let login_attempt_config = model::login_attempt::LoginAttemptConfig::new()
+        .expect("Failed to load login attempt config");
+    let registration_config =
+        model::registration::RegistrationConfig::new().expect("Failed to load registration config");
+    let trusted_proxies =
+        model::trusted_proxy::TrustedProxies::new().expect("Failed to load trusted proxies");
+    let webauthn =
+        web::Data::new(model::webauthn::new_webauthn().expect("Failed to load webauthn config"));
+    let passkey_decoy =
+        model::webauthn::PasskeyDecoy::new().expect("Failed to load passkey decoy config");
     println!("Initializing database schema");
-    model::db::on_database_init(dbpool.clone()).await;
+    model::db::on_database_init(dbpool.clone(), &token_keys, &otp_config.keys).await;
+    println!("Loading token denylist");
+    let token_denylist =
+        model::revocation::TokenDenylist::new().expect("Failed to load token denylist config");
+    token_denylist
+        .sync(dbpool.clone())
+        .await
+        .expect("Failed to load token denylist");
+    token_denylist.start_sync(dbpool.clone());
 
     println!("Initializing OpenApi");
     let openapi = ApiDoc::openapi();
@@ -84,10 +119,26 @@ async fn main() -> std::io::Result<()> {
         App::new()
             .app_data(web::Data::new(dbpool.clone()))
             .app_data(web::Data::new(oidc_handler.clone()))
+            .app_data(web::Data::new(token_keys.clone()))
+            .app_data(web::Data::new(token_lifetime.clone()))
+            .app_data(web::Data::new(otp_config.clone()))
+            .app_data(web::Data::new(trusted_device_config.clone()))
+            .app_data(web::Data::new(password_reset_config.clone()))
+            .app_data(web::Data::new(mailer.clone()))
+            .app_data(web::Data::new(email_verification_config.clone()))
+            .app_data(web::Data::new(password_policy.clone()))
+            .app_data(web::Data::new(password_hash_config.clone()))
+            .app_data(web::Data::new(login_attempt_config.clone()))
+            .app_data(web::Data::new(registration_config.clone()))
+            .app_data(web::Data::new(trusted_proxies.clone()))
+            .app_data(web::Data::new(token_denylist.clone()))
+            .app_data(webauthn.clone())
+            .app_data(web::Data::new(passkey_decoy.clone()))
             .wrap(cors)
             .wrap(prometheus.clone())
             .service(health)
             .service(hello)
+            .service(jwks)
             .service(
                 route::init::init_api()
                     .wrap_fn(|mut req, srv| {
@@ -1,3 +1,5 @@
+use super::otp::OtpKeys;
+use super::token::TokenKeys;
 use openssl::ssl::{SslConnector, SslMethod, SslVerifyMode};
 //use tokio_postgres::NoTls;
 
@@ -73,7 +75,11 @@ impl DbConfig {
     }
 }
 
-pub async fn on_database_init(pool: deadpool_postgres::Pool) {
+pub async fn on_database_init(
+    pool: deadpool_postgres::Pool,
+    token_keys: &TokenKeys,
+    otp_keys: &OtpKeys,
+) {
     let client = pool.get().await.unwrap();
 
     let install_addon = "
@@ -90,13 +96,68 @@ pub async fn on_database_init(pool: deadpool_postgres::Pool) {
             panic!("Error creating table users: {}", e);
         }
     }
-    match super::token::RefreshToken::create_table(pool.clone()).await {
+    match super::user::User::encrypt_otp_secrets(pool.clone(), otp_keys, false).await {
+        Ok(nbr) => println!("{} otp secrets encrypted", nbr),
+        Err(e) => {
+            panic!("Error encrypting otp secrets: {}", e);
+        }
+    }
+    match super::token::RefreshToken::create_table(pool.clone(), token_keys).await {
         Ok(_) => println!("Table refresh_tokens created"),
         Err(e) => {
             panic!("Error creating table refresh_tokens: {}", e);
         }
     }
 
+    match super::recovery_code::RecoveryCode::create_table(pool.clone()).await {
+        Ok(_) => println!("Table otp_recovery_codes created"),
+        Err(e) => {
+            panic!("Error creating table otp_recovery_codes: {}", e);
+        }
+    }
+    match super::revocation::TokenDenylist::create_table(pool.clone()).await {
+        Ok(_) => println!("Table revoked_tokens created"),
+        Err(e) => {
+            panic!("Error creating table revoked_tokens: {}", e);
+        }
+    }
+    match super::webauthn::WebauthnCredential::create_table(pool.clone()).await {
+        Ok(_) => println!("Table webauthn_credentials created"),
+        Err(e) => {
+            panic!("Error creating table webauthn_credentials: {}", e);
+        }
+    }
+    match super::trusted_device::TrustedDevice::create_table(pool.clone()).await {
+        Ok(_) => println!("Table trusted_devices created"),
+        Err(e) => {
+            panic!("Error creating table trusted_devices: {}", e);
+        }
+    }
+    match super::password_reset::PasswordReset::create_table(pool.clone()).await {
+        Ok(_) => println!("Table password_reset_tokens created"),
+        Err(e) => {
+            panic!("Error creating table password_reset_tokens: {}", e);
+        }
+    }
+    match super::email_verification::EmailVerification::create_table(pool.clone()).await {
+        Ok(_) => println!("Table email_verification_tokens created"),
+        Err(e) => {
+            panic!("Error creating table email_verification_tokens: {}", e);
+        }
+    }
+    match super::registration::Invitation::create_table(pool.clone()).await {
+        Ok(_) => println!("Table invitations created"),
+        Err(e) => {
+            panic!("Error creating table invitations: {}", e);
+        }
+    }
+    match super::login_attempt::LoginAttempt::create_table(pool.clone()).await {
+        Ok(_) => println!("Table login_attempts created"),
+        Err(e) => {
+            panic!("Error creating table login_attempts: {}", e);
+        }
+    }
+
     println!("Database initialized")
 }
 
@@ -0,0 +1,313 @@
+use std::env;
+
+use actix_web::{
+    http::{header::RETRY_AFTER, StatusCode},
+    HttpResponse,
+};
+use serde::{Deserialize, Serialize};
+use utoipa::ToSchema;
+
+// Failures are counted per account (LOGIN_MAX_FAILURES) and per ip
+// (LOGIN_IP_MAX_FAILURES). Each failure of an account lock it for LOGIN_BACKOFF_BASE
+// seconds doubled at each new failure, once a threshold is reached the account or
+// the ip is locked LOGIN_LOCKOUT_DURATION seconds. Failures older than
+// LOGIN_FAILURE_WINDOW seconds are forgotten.
+#[derive(Clone)]
+pub struct LoginAttemptConfig {
+    pub max_failures: i32,
+    pub ip_max_failures: i32,
+    pub backoff_base: i64,
+    pub lockout: i64,
+    pub window: i64,
+}
+
+impl LoginAttemptConfig {
+    pub fn new() -> Result<LoginAttemptConfig, String> {
+        let max_failures = LoginAttemptConfig::parse_env("LOGIN_MAX_FAILURES", 5)?;
+        let ip_max_failures = LoginAttemptConfig::parse_env("LOGIN_IP_MAX_FAILURES", 20)?;
+        let backoff_base = LoginAttemptConfig::parse_env("LOGIN_BACKOFF_BASE", 1)?;
+        let lockout = LoginAttemptConfig::parse_env("LOGIN_LOCKOUT_DURATION", 900)?;
+        let window = LoginAttemptConfig::parse_env("LOGIN_FAILURE_WINDOW", 3600)?;
+        if max_failures == 0 || ip_max_failures == 0 || window == 0 {
+            return Err(
+                "LOGIN_MAX_FAILURES, LOGIN_IP_MAX_FAILURES and LOGIN_FAILURE_WINDOW must be positive"
+                    .to_string(),
+            );
+        }
+        Ok(LoginAttemptConfig {
+            max_failures: max_failures as i32,
+            ip_max_failures: ip_max_failures as i32,
+            backoff_base,
+            lockout,
+            window,
+        })
+    }
+
+    fn parse_env(name: &str, default: i64) -> Result<i64, String> {
+        match env::var(name) {
+            Ok(value) => match value.parse::<i64>() {
+                Ok(value) if (0..=i32::MAX as i64).contains(&value) => Ok(value),
+                _ => Err(format!("{} must be a positive number", name)),
+            },
+            Err(_) => Ok(default),
+        }
+    }
+
+    // Seconds to wait after the n-th consecutive failure of an account
+    pub fn lock_duration(&self, failures: i32) -> i64 {
+        if failures >= self.max_failures {
+            return self.lockout;
+        }
+        let exponent = (failures - 1).clamp(0, 30) as u32;
+        self.backoff_base
+            .saturating_mul(1 << exponent)
+            .min(self.lockout)
+    }
+
+    // An ip can be shared by several users, it is only locked once the threshold is reached
+    pub fn ip_lock_duration(&self, failures: i32) -> i64 {
+        if failures >= self.ip_max_failures {
+            self.lockout
+        } else {
+            0
+        }
+    }
+}
+
+#[derive(ToSchema, Clone, Serialize, Deserialize)]
+pub struct LoginLocked {
+    pub message: String,
+    // Seconds before the next attempt, also sent in the Retry-After header
+    pub retry_after: i64,
+}
+
+// Attempt counted by LoginAttempt::reserve, with the lock it set on each row
+pub struct LoginReservation {
+    slots: Vec<(String, chrono::DateTime<chrono::Utc>)>,
+}
+
+// Counters shared by every replica, a row is either an account (by email, so
+// an unknown email is locked the same way) or an ip
+pub struct LoginAttempt;
+
+impl LoginAttempt {
+    pub async fn create_table(pool: deadpool_postgres::Pool) -> Result<u64, tokio_postgres::Error> {
+        let client = pool.get().await.unwrap();
+
+        let create_table = "
+      CREATE TABLE IF NOT EXISTS login_attempts (
+        key VARCHAR PRIMARY KEY,
+        failures INT NOT NULL,
+        last_failure_at TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+        locked_until TIMESTAMPTZ
+      );";
+        client.execute(create_table, &[]).await
+    }
+
+    pub fn account_key(email: &str) -> String {
+        format!("account:{}", email.to_lowercase())
+    }
+
+    fn ip_key(ip: &str) -> String {
+        format!("ip:{}", ip)
+    }
+
+    fn keys(email: &str, ip: Option<&str>) -> Vec<String> {
+        let mut keys = vec![LoginAttempt::account_key(email)];
+        if let Some(ip) = ip {
+            keys.push(LoginAttempt::ip_key(ip));
+        }
+        keys
+    }
+
+    // Seconds before the account and the ip can try again, None if none is locked
+    pub async fn locked_for(
+        pool: deadpool_postgres::Pool,
+        email: &str,
+        ip: Option<&str>,
+    ) -> Result<Option<i64>, tokio_postgres::Error> {
+        let client = pool.get().await.unwrap();
+
+        let locked_for = "
+        SELECT CEIL(MAX(EXTRACT(EPOCH FROM locked_until - NOW())))::BIGINT
+        FROM login_attempts
+        WHERE key = ANY($1) AND locked_until > NOW()";
+        let row = client
+            .query_one(locked_for, &[&LoginAttempt::keys(email, ip)])
+            .await?;
+        Ok(row.get(0))
+    }
+
+    // Count the attempt as a failure before the credentials are checked, the rows are
+    // locked so parallel attempts are counted one after the other and the backoff
+    // can't be skipped. Return the seconds to wait instead if the account or the ip
+    // is locked, a valid attempt is given back with release.
+    pub async fn reserve(
+        pool: deadpool_postgres::Pool,
+        config: &LoginAttemptConfig,
+        email: &str,
+        ip: Option<&str>,
+    ) -> Result<Result<LoginReservation, i64>, tokio_postgres::Error> {
+        let mut client = pool.get().await.unwrap();
+        let transaction = client.transaction().await?;
+        let account_key = LoginAttempt::account_key(email);
+        let keys = LoginAttempt::keys(email, ip);
+
+        let delete_stale = "
+        DELETE FROM login_attempts
+        WHERE last_failure_at < NOW() - $1::BIGINT * INTERVAL '1 second'
+        AND (locked_until IS NULL OR locked_until < NOW())";
+        transaction.execute(delete_stale, &[&config.window]).await?;
+        let insert_missing = "
+        INSERT INTO login_attempts (key, failures)
+        SELECT UNNEST($1::VARCHAR[]), 0
+        ON CONFLICT (key) DO NOTHING";
+        transaction.execute(insert_missing, &[&keys]).await?;
+        let lock_rows = "
+        SELECT key, failures, CEIL(EXTRACT(EPOCH FROM locked_until - NOW()))::BIGINT
+        FROM login_attempts
+        WHERE key = ANY($1)
+        ORDER BY key
+        FOR UPDATE";
+        let rows = transaction.query(lock_rows, &[&keys]).await?;
+        let retry_after = rows
+            .iter()
+            .filter_map(|row| row.get::<_, Option<i64>>(2))
+            .filter(|retry_after| *retry_after > 0)
+            .max();
+        if let Some(retry_after) = retry_after {
+            return Ok(Err(retry_after));
+        }
+
+        let record_failure = "
+        UPDATE login_attempts
+        SET failures = $2, last_failure_at = NOW(),
+          locked_until = NOW() + $3::BIGINT * INTERVAL '1 second'
+        WHERE key = $1
+        RETURNING locked_until";
+        let mut slots = Vec::new();
+        for row in rows {
+            let key: String = row.get(0);
+            let failures = row.get::<_, i32>(1) + 1;
+            let duration = if key == account_key {
+                config.lock_duration(failures)
+            } else {
+                config.ip_lock_duration(failures)
+            };
+            let locked_until = transaction
+                .query_one(record_failure, &[&key, &failures, &duration])
+                .await?
+                .get(0);
+            slots.push((key, locked_until));
+        }
+        transaction.commit().await?;
+        Ok(Ok(LoginReservation { slots }))
+    }
+
+    // Give back a reserved attempt once the credentials are valid, the lock it set is
+    // removed unless a later attempt changed it
+    pub async fn release(
+        pool: deadpool_postgres::Pool,
+        reservation: &LoginReservation,
+    ) -> Result<(), tokio_postgres::Error> {
+        let client = pool.get().await.unwrap();
+
+        let release = "
+        UPDATE login_attempts
+        SET failures = GREATEST(failures - 1, 0),
+          locked_until = CASE WHEN locked_until = $2 THEN NULL ELSE locked_until END
+        WHERE key = $1";
+        for (key, locked_until) in &reservation.slots {
+            client.execute(release, &[key, locked_until]).await?;
+        }
+        Ok(())
+    }
+
+    // Clear the failures of the account, the ip counter is kept as it can be
+    // shared by several accounts
+    pub async fn reset(
+        pool: deadpool_postgres::Pool,
+        email: &str,
+    ) -> Result<u64, tokio_postgres::Error> {
+        let client = pool.get().await.unwrap();
+
+        let reset = "DELETE FROM login_attempts WHERE key = $1";
+        client
+            .execute(reset, &[&LoginAttempt::account_key(email)])
+            .await
+    }
+
+    // Clear the failures of an ip, only done on demand by an admin
+    pub async fn reset_ip(
+        pool: deadpool_postgres::Pool,
+        ip: &str,
+    ) -> Result<u64, tokio_postgres::Error> {
+        let client = pool.get().await.unwrap();
+
+        let reset = "DELETE FROM login_attempts WHERE key = $1";
+        client.execute(reset, &[&LoginAttempt::ip_key(ip)]).await
+    }
+
+    pub fn locked_response(retry_after: i64) -> HttpResponse {
+        HttpResponse::build(StatusCode::LOCKED)
+            .insert_header((RETRY_AFTER, retry_after.to_string()))
+            .json(LoginLocked {
+                message: "Too many failed attempts".to_string(),
+                retry_after,
+            })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::LoginAttemptConfig;
+
+    fn config() -> LoginAttemptConfig {
+        LoginAttemptConfig {
+            max_failures: 5,
+            ip_max_failures: 20,
+            backoff_base: 1,
+            lockout: 900,
+            window: 3600,
+        }
+    }
+
+    #[test]
+    fn lock_duration_doubles_until_the_lockout() {
+        let config = config();
+        let durations: Vec<i64> = (1..=6).map(|n| config.lock_duration(n)).collect();
+        assert_eq!(durations, vec![1, 2, 4, 8, 900, 900]);
+    }
+
+    #[test]
+    fn lock_duration_is_capped_by_the_lockout() {
+        let config = LoginAttemptConfig {
+            max_failures: 50,
+            backoff_base: 60,
+            ..config()
+        };
+        assert_eq!(config.lock_duration(4), 480);
+        assert_eq!(config.lock_duration(5), 900);
+        assert_eq!(config.lock_duration(49), 900);
+    }
+
+    #[test]
+    fn lock_duration_without_backoff() {
+        let config = LoginAttemptConfig {
+            backoff_base: 0,
+            ..config()
+        };
+        assert_eq!(config.lock_duration(0), 0);
+        assert_eq!(config.lock_duration(4), 0);
+        assert_eq!(config.lock_duration(5), 900);
+    }
+
+    #[test]
+    fn ip_is_only_locked_at_the_threshold() {
+        let config = config();
+        assert_eq!(config.ip_lock_duration(1), 0);
+        assert_eq!(config.ip_lock_duration(19), 0);
+        assert_eq!(config.ip_lock_duration(20), 900);
+        assert_eq!(config.ip_lock_duration(25), 900);
+    }
+}
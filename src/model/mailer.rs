@@ -0,0 +1,105 @@
+use std::env;
+
+use lettre::{
+    message::{header::ContentType, Mailbox},
+    transport::smtp::authentication::Credentials,
+    AsyncFileTransport, AsyncSmtpTransport, AsyncTransport, Message, Tokio1Executor,
+};
+
+#[derive(Clone)]
+enum MailTransport {
+    Smtp(AsyncSmtpTransport<Tokio1Executor>),
+    // Each mail is written as an .eml file in the directory
+    File(AsyncFileTransport<Tokio1Executor>),
+    // Only the recipient and the subject are logged, for development, the body
+    // holds single use links
+    Log,
+}
+
+// The transport is selected with MAILER (smtp, file or log), smtp by default so a
+// deployment without SMTP_HOST fails at startup instead of losing the mails
+#[derive(Clone)]
+pub struct Mailer {
+    from: Mailbox,
+    transport: MailTransport,
+}
+
+impl Mailer {
+    pub fn new() -> Result<Mailer, String> {
+        let from = env::var("MAIL_FROM")
+            .unwrap_or_else(|_| "Rust API <noreply@localhost>".to_string())
+            .parse::<Mailbox>()
+            .map_err(|err| format!("MAIL_FROM is not a valid mailbox: {}", err))?;
+        let transport = match env::var("MAILER")
+            .unwrap_or_else(|_| "smtp".to_string())
+            .as_str()
+        {
+            "smtp" => MailTransport::Smtp(Mailer::smtp_transport()?),
+            "file" => {
+                let dir = env::var("MAILER_FILE_DIR").unwrap_or_else(|_| "mails".to_string());
+                std::fs::create_dir_all(&dir)
+                    .map_err(|err| format!("Can't create MAILER_FILE_DIR {}: {}", dir, err))?;
+                MailTransport::File(AsyncFileTransport::new(dir))
+            }
+            "log" => MailTransport::Log,
+            _ => return Err("MAILER must be smtp, file or log".to_string()),
+        };
+        Ok(Mailer { from, transport })
+    }
+
+    // SMTP_TLS is either tls, starttls or none, the port default to the one of the mode
+    fn smtp_transport() -> Result<AsyncSmtpTransport<Tokio1Executor>, String> {
+        let host = env::var("SMTP_HOST")
+            .ok()
+            .filter(|host| !host.is_empty())
+            .ok_or_else(|| "SMTP_HOST is required with MAILER=smtp".to_string())?;
+        let mut builder = match env::var("SMTP_TLS")
+            .unwrap_or_else(|_| "starttls".to_string())
+            .as_str()
+        {
+            "tls" => {
+                AsyncSmtpTransport::<Tokio1Executor>::relay(&host).map_err(|err| err.to_string())?
+            }
+            "starttls" => AsyncSmtpTransport::<Tokio1Executor>::starttls_relay(&host)
+                .map_err(|err| err.to_string())?,
+            "none" => AsyncSmtpTransport::<Tokio1Executor>::builder_dangerous(&host),
+            _ => return Err("SMTP_TLS must be tls, starttls or none".to_string()),
+        };
+        if let Ok(port) = env::var("SMTP_PORT") {
+            builder = builder.port(
+                port.parse::<u16>()
+                    .map_err(|_| "SMTP_PORT must be a number".to_string())?,
+            );
+        }
+        if let (Ok(user), Ok(password)) = (env::var("SMTP_USER"), env::var("SMTP_PASSWORD")) {
+            builder = builder.credentials(Credentials::new(user, password));
+        }
+        Ok(builder.build())
+    }
+
+    pub async fn send(&self, to: &str, subject: &str, body: String) -> Result<(), String> {
+        let message = Message::builder()
+            .from(self.from.clone())
+            .to(to.parse::<Mailbox>().map_err(|err| err.to_string())?)
+            .subject(subject)
+            .header(ContentType::TEXT_PLAIN)
+            .body(body)
+            .map_err(|err| err.to_string())?;
+        match &self.transport {
+            MailTransport::Smtp(transport) => transport
+                .send(message)
+                .await
+                .map(|_| ())
+                .map_err(|err| err.to_string()),
+            MailTransport::File(transport) => transport
+                .send(message)
+                .await
+                .map(|_| ())
+                .map_err(|err| err.to_string()),
+            MailTransport::Log => {
+                tracing::info!(to = ?to, subject = ?subject, "Mail not sent (MAILER=log)");
+                Ok(())
+            }
+        }
+    }
+}
@@ -1,5 +1,18 @@
 pub mod db;
+pub mod email_verification;
+pub mod login_attempt;
+pub mod mailer;
 pub mod oidc;
 pub mod oidc_token;
+pub mod otp;
+pub mod password_hash;
+pub mod password_policy;
+pub mod password_reset;
+pub mod recovery_code;
+pub mod registration;
+pub mod revocation;
 pub mod token;
+pub mod trusted_device;
+pub mod trusted_proxy;
 pub mod user;
+pub mod webauthn;
@@ -0,0 +1,194 @@
+use std::{collections::HashMap, env};
+
+use base64::{engine::general_purpose, Engine as _};
+use openssl::{
+    rand::rand_bytes,
+    sha::sha256,
+    symm::{decrypt_aead, encrypt_aead, Cipher},
+};
+use serde::Deserialize;
+use totp_rs::Algorithm;
+use uuid::Uuid;
+
+const NONCE_LENGTH: usize = 12;
+const TAG_LENGTH: usize = 16;
+
+// Parameters of the secrets generated from now on, the existing secrets keep
+// the parameters they were created with.
+// The skew (number of time step accepted before and after the current one)
+// apply to every secret.
+// The one time token returned by the login is only valid for
+// one_time_token_lifetime seconds and one_time_token_max_attempts invalid codes.
+#[derive(Clone)]
+pub struct OtpConfig {
+    pub algorithm: Algorithm,
+    pub digits: usize,
+    pub period: u64,
+    pub skew: u8,
+    pub one_time_token_lifetime: i64,
+    pub one_time_token_max_attempts: i32,
+    pub keys: OtpKeys,
+}
+
+impl OtpConfig {
+    pub fn new() -> Result<OtpConfig, String> {
+        let algorithm = match env::var("OTP_ALGORITHM") {
+            Ok(value) => OtpConfig::parse_algorithm(&value)
+                .ok_or_else(|| "OTP_ALGORITHM must be SHA1, SHA256 or SHA512".to_string())?,
+            Err(_) => Algorithm::SHA1,
+        };
+        let digits = match env::var("OTP_DIGITS") {
+            Ok(value) => match value.parse::<usize>() {
+                Ok(value) if (6..=8).contains(&value) => value,
+                _ => return Err("OTP_DIGITS must be between 6 and 8".to_string()),
+            },
+            Err(_) => 6,
+        };
+        let period = match env::var("OTP_PERIOD") {
+            Ok(value) => match value.parse::<u64>() {
+                Ok(value) if value > 0 => value,
+                _ => return Err("OTP_PERIOD must be a positive number".to_string()),
+            },
+            Err(_) => 30,
+        };
+        let skew = match env::var("OTP_SKEW") {
+            Ok(value) => match value.parse::<u8>() {
+                Ok(value) if value <= 10 => value,
+                _ => return Err("OTP_SKEW must be between 0 and 10".to_string()),
+            },
+            Err(_) => 1,
+        };
+        let one_time_token_lifetime = match env::var("ONE_TIME_TOKEN_LIFETIME") {
+            Ok(value) => match value.parse::<i64>() {
+                Ok(value) if value > 0 => value,
+                _ => return Err("ONE_TIME_TOKEN_LIFETIME must be a positive number".to_string()),
+            },
+            Err(_) => 300,
+        };
+        let one_time_token_max_attempts = match env::var("ONE_TIME_TOKEN_MAX_ATTEMPTS") {
+            Ok(value) => match value.parse::<i32>() {
+                Ok(value) if value > 0 => value,
+                _ => {
+                    return Err("ONE_TIME_TOKEN_MAX_ATTEMPTS must be a positive number".to_string())
+                }
+            },
+            Err(_) => 5,
+        };
+        Ok(OtpConfig {
+            algorithm,
+            digits,
+            period,
+            skew,
+            one_time_token_lifetime,
+            one_time_token_max_attempts,
+            keys: OtpKeys::new()?,
+        })
+    }
+
+    pub fn parse_algorithm(value: &str) -> Option<Algorithm> {
+        match value.to_uppercase().as_str() {
+            "SHA1" => Some(Algorithm::SHA1),
+            "SHA256" => Some(Algorithm::SHA256),
+            "SHA512" => Some(Algorithm::SHA512),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct OtpKeyConfig {
+    kid: String,
+    secret: String,
+}
+
+// Keys used to encrypt the otp secrets (AES-256-GCM), the id of the key is
+// stored alongside the ciphertext.
+#[derive(Clone)]
+pub struct OtpKeys {
+    current_kid: String,
+    keys: HashMap<String, [u8; 32]>,
+}
+
+impl OtpKeys {
+    // OTP_ENCRYPTION_KEY is hashed into the key identified by OTP_ENCRYPTION_KEY_ID.
+    // Keys still needed to read the secrets encrypted before a rotation are listed in
+    // OTP_ENCRYPTION_PREVIOUS_KEYS as a json array of {"kid", "secret"}.
+    pub fn new() -> Result<OtpKeys, String> {
+        let current_kid =
+            env::var("OTP_ENCRYPTION_KEY_ID").unwrap_or_else(|_| "otp_key".to_string());
+        let secret = env::var("OTP_ENCRYPTION_KEY")
+            .unwrap_or_else(|_| "lambda_otp_encryption_key".to_string());
+        let mut keys = HashMap::new();
+        keys.insert(current_kid.clone(), sha256(secret.as_bytes()));
+        let previous: Vec<OtpKeyConfig> = match env::var("OTP_ENCRYPTION_PREVIOUS_KEYS") {
+            Ok(value) => match serde_json::from_str(&value) {
+                Ok(configs) => configs,
+                Err(err) => return Err(format!("Invalid OTP_ENCRYPTION_PREVIOUS_KEYS: {}", err)),
+            },
+            Err(_) => vec![],
+        };
+        for config in previous {
+            if keys.contains_key(&config.kid) {
+                return Err(format!(
+                    "OTP_ENCRYPTION_PREVIOUS_KEYS: duplicated kid {}",
+                    config.kid
+                ));
+            }
+            keys.insert(config.kid, sha256(config.secret.as_bytes()));
+        }
+        Ok(OtpKeys { current_kid, keys })
+    }
+
+    pub fn current_kid(&self) -> &str {
+        &self.current_kid
+    }
+
+    // Return the base64 of the nonce, ciphertext and tag, the user id is
+    // authenticated so a secret can not be moved to another user
+    pub fn encrypt(&self, user_id: Uuid, plaintext: &str) -> Result<String, String> {
+        let key = &self.keys[&self.current_kid];
+        let mut nonce = [0u8; NONCE_LENGTH];
+        rand_bytes(&mut nonce).map_err(|err| err.to_string())?;
+        let mut tag = [0u8; TAG_LENGTH];
+        let ciphertext = encrypt_aead(
+            Cipher::aes_256_gcm(),
+            key,
+            Some(&nonce),
+            user_id.as_bytes(),
+            plaintext.as_bytes(),
+            &mut tag,
+        )
+        .map_err(|err| err.to_string())?;
+        Ok(general_purpose::STANDARD.encode([&nonce[..], &ciphertext, &tag].concat()))
+    }
+
+    // A value without key id was saved before the encryption and is returned as is
+    pub fn decrypt(&self, kid: Option<&str>, user_id: Uuid, value: &str) -> Result<String, String> {
+        let kid = match kid {
+            Some(kid) => kid,
+            None => return Ok(value.to_string()),
+        };
+        let key = match self.keys.get(kid) {
+            Some(key) => key,
+            None => return Err(format!("Unknown otp encryption key {}", kid)),
+        };
+        let data = general_purpose::STANDARD
+            .decode(value)
+            .map_err(|err| err.to_string())?;
+        if data.len() < NONCE_LENGTH + TAG_LENGTH {
+            return Err("Encrypted otp value is too short".to_string());
+        }
+        let (nonce, rest) = data.split_at(NONCE_LENGTH);
+        let (ciphertext, tag) = rest.split_at(rest.len() - TAG_LENGTH);
+        let plaintext = decrypt_aead(
+            Cipher::aes_256_gcm(),
+            key,
+            Some(nonce),
+            user_id.as_bytes(),
+            ciphertext,
+            tag,
+        )
+        .map_err(|err| err.to_string())?;
+        String::from_utf8(plaintext).map_err(|err| err.to_string())
+    }
+}
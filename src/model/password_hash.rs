@@ -0,0 +1,120 @@
+use std::env;
+
+use argon2::{
+    password_hash::{rand_core::OsRng, PasswordHash, PasswordHasher, PasswordVerifier, SaltString},
+    Algorithm, Argon2, Params, Version,
+};
+
+const ARGON2ID_PREFIX: &str = "$argon2id$";
+
+// Argon2id parameters of the new hashes, ARGON2_MEMORY (KiB), ARGON2_ITERATIONS
+// and ARGON2_PARALLELISM. A hash made with other parameters or with bcrypt is
+// still verified and upgraded on the next login.
+#[derive(Clone)]
+pub struct PasswordHashConfig {
+    params: Params,
+}
+
+impl PasswordHashConfig {
+    pub fn new() -> Result<PasswordHashConfig, String> {
+        let memory = PasswordHashConfig::parse_env("ARGON2_MEMORY", 19456)?;
+        let iterations = PasswordHashConfig::parse_env("ARGON2_ITERATIONS", 2)?;
+        let parallelism = PasswordHashConfig::parse_env("ARGON2_PARALLELISM", 1)?;
+        let params = Params::new(memory, iterations, parallelism, None)
+            .map_err(|err| format!("Invalid argon2 parameters: {}", err))?;
+        Ok(PasswordHashConfig { params })
+    }
+
+    fn parse_env(name: &str, default: u32) -> Result<u32, String> {
+        match env::var(name) {
+            Ok(value) => value
+                .parse::<u32>()
+                .map_err(|_| format!("{} must be a number", name)),
+            Err(_) => Ok(default),
+        }
+    }
+
+    pub fn hash(&self, password: &str) -> Result<String, String> {
+        let salt = SaltString::generate(&mut OsRng);
+        Argon2::new(Algorithm::Argon2id, Version::V0x13, self.params.clone())
+            .hash_password(password.as_bytes(), &salt)
+            .map(|hash| hash.to_string())
+            .map_err(|err| err.to_string())
+    }
+
+    // The algorithm is read from the prefix of the hash, $argon2id$ for argon2 (the
+    // parameters are part of the hash) and $2a$, $2b$ or $2y$ for bcrypt
+    pub fn verify(password: &str, hash: &str) -> Result<bool, String> {
+        if hash.starts_with(ARGON2ID_PREFIX) {
+            let parsed = PasswordHash::new(hash).map_err(|err| err.to_string())?;
+            return Ok(Argon2::default()
+                .verify_password(password.as_bytes(), &parsed)
+                .is_ok());
+        }
+        bcrypt::verify(password, hash).map_err(|err| err.to_string())
+    }
+
+    pub fn needs_rehash(&self, hash: &str) -> bool {
+        if !hash.starts_with(ARGON2ID_PREFIX) {
+            return true;
+        }
+        match PasswordHash::new(hash).and_then(|parsed| Params::try_from(&parsed)) {
+            Ok(params) => {
+                params.m_cost() != self.params.m_cost()
+                    || params.t_cost() != self.params.t_cost()
+                    || params.p_cost() != self.params.p_cost()
+            }
+            Err(_) => true,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::PasswordHashConfig;
+    use argon2::Params;
+
+    // Small parameters to keep the tests fast
+    fn config(memory: u32, iterations: u32) -> PasswordHashConfig {
+        PasswordHashConfig {
+            params: Params::new(memory, iterations, 1, None).unwrap(),
+        }
+    }
+
+    #[test]
+    fn same_parameters_are_kept() {
+        let config = config(1024, 1);
+        let hash = config.hash("Tr0ubadour").unwrap();
+        assert!(!config.needs_rehash(&hash));
+        assert_eq!(PasswordHashConfig::verify("Tr0ubadour", &hash), Ok(true));
+        assert_eq!(PasswordHashConfig::verify("tr0ubadour", &hash), Ok(false));
+    }
+
+    #[test]
+    fn other_parameters_are_upgraded() {
+        let hash = config(1024, 1).hash("Tr0ubadour").unwrap();
+        assert!(config(2048, 1).needs_rehash(&hash));
+        assert!(config(1024, 2).needs_rehash(&hash));
+        let config = PasswordHashConfig {
+            params: Params::new(1024, 1, 2, None).unwrap(),
+        };
+        assert!(config.needs_rehash(&hash));
+    }
+
+    #[test]
+    fn bcrypt_is_verified_and_upgraded() {
+        let hash = bcrypt::hash("Tr0ubadour", 4).unwrap();
+        assert_eq!(PasswordHashConfig::verify("Tr0ubadour", &hash), Ok(true));
+        assert_eq!(PasswordHashConfig::verify("tr0ubadour", &hash), Ok(false));
+        assert!(config(1024, 1).needs_rehash(&hash));
+    }
+
+    #[test]
+    fn malformed_argon2_hash_is_upgraded() {
+        assert!(config(1024, 1).needs_rehash("$argon2id$v=19$m=1024"));
+        assert_ne!(
+            PasswordHashConfig::verify("Tr0ubadour", "$argon2id$v=19$m=1024"),
+            Ok(true)
+        );
+    }
+}
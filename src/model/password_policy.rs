@@ -0,0 +1,398 @@
+use std::{env, path::PathBuf};
+
+use actix_web::web;
+use serde::{Deserialize, Serialize};
+use utoipa::ToSchema;
+
+use crate::helper::string_rule;
+
+#[derive(ToSchema, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum PasswordRule {
+    MinLength,
+    MaxLength,
+    Lowercase,
+    Uppercase,
+    Digit,
+    Symbol,
+    // The estimated entropy is under PASSWORD_MIN_ENTROPY
+    Entropy,
+    ForbiddenWord,
+    ContainsEmail,
+    // Found in the breach corpus of PASSWORD_BREACH_CORPUS_DIR
+    Breached,
+    // The breach corpus can't be read and PASSWORD_BREACH_FAIL_OPEN is false
+    BreachUnchecked,
+}
+
+#[derive(ToSchema, Clone, Serialize, Deserialize)]
+pub struct PasswordPolicyError {
+    pub message: String,
+    pub failed_rules: Vec<PasswordRule>,
+}
+
+// Rules applied to every new password (register, reset and change), the
+// existing passwords are not checked again
+#[derive(Clone)]
+pub struct PasswordPolicy {
+    pub min_length: usize,
+    pub max_length: usize,
+    pub required_classes: Vec<PasswordRule>,
+    pub min_entropy: f64,
+    pub forbidden_words: Vec<String>,
+    pub forbid_email: bool,
+    pub breach_corpus_dir: Option<PathBuf>,
+    pub breach_min_count: u64,
+    pub breach_fail_open: bool,
+}
+
+impl PasswordPolicy {
+    pub fn new() -> Result<PasswordPolicy, String> {
+        let min_length = PasswordPolicy::parse_env("PASSWORD_MIN_LENGTH", 8)?;
+        let max_length = PasswordPolicy::parse_env("PASSWORD_MAX_LENGTH", 128)?;
+        if min_length == 0 || max_length < min_length {
+            return Err(
+                "PASSWORD_MIN_LENGTH must be positive and under PASSWORD_MAX_LENGTH".to_string(),
+            );
+        }
+        let required_classes = env::var("PASSWORD_REQUIRED_CLASSES")
+            .unwrap_or_else(|_| "lowercase,uppercase,digit".to_string())
+            .split(',')
+            .map(|class| class.trim())
+            .filter(|class| !class.is_empty())
+            .map(|class| match class {
+                "lowercase" => Ok(PasswordRule::Lowercase),
+                "uppercase" => Ok(PasswordRule::Uppercase),
+                "digit" => Ok(PasswordRule::Digit),
+                "symbol" => Ok(PasswordRule::Symbol),
+                _ => Err(format!(
+                    "PASSWORD_REQUIRED_CLASSES: unknown class {}, expected lowercase, uppercase, digit or symbol",
+                    class
+                )),
+            })
+            .collect::<Result<Vec<_>, String>>()?;
+        let min_entropy = match env::var("PASSWORD_MIN_ENTROPY") {
+            Ok(value) => match value.parse::<f64>() {
+                Ok(value) if value >= 0.0 => value,
+                _ => return Err("PASSWORD_MIN_ENTROPY must be a positive number".to_string()),
+            },
+            Err(_) => 0.0,
+        };
+        let mut forbidden_words: Vec<String> = env::var("PASSWORD_FORBIDDEN_WORDS")
+            .unwrap_or_else(|_| "password,motdepasse,azerty,qwerty,123456".to_string())
+            .split(',')
+            .map(|word| word.trim().to_lowercase())
+            .filter(|word| !word.is_empty())
+            .collect();
+        if let Ok(path) = env::var("PASSWORD_FORBIDDEN_WORDS_FILE") {
+            let content = std::fs::read_to_string(&path).map_err(|err| {
+                format!("Can't read PASSWORD_FORBIDDEN_WORDS_FILE {}: {}", path, err)
+            })?;
+            forbidden_words.extend(
+                content
+                    .lines()
+                    .map(|word| word.trim().to_lowercase())
+                    .filter(|word| !word.is_empty()),
+            );
+        }
+        let forbid_email = match env::var("PASSWORD_FORBID_EMAIL") {
+            Ok(value) => value
+                .parse::<bool>()
+                .map_err(|_| "PASSWORD_FORBID_EMAIL must be a boolean".to_string())?,
+            Err(_) => true,
+        };
+        let breach_corpus_dir = match env::var("PASSWORD_BREACH_CORPUS_DIR") {
+            Ok(dir) => {
+                let dir = PathBuf::from(dir);
+                if !dir.is_dir() {
+                    return Err(format!(
+                        "PASSWORD_BREACH_CORPUS_DIR {} is not a directory",
+                        dir.display()
+                    ));
+                }
+                Some(dir)
+            }
+            Err(_) => None,
+        };
+        let breach_min_count = match env::var("PASSWORD_BREACH_MIN_COUNT") {
+            Ok(value) => match value.parse::<u64>() {
+                Ok(value) if value > 0 => value,
+                _ => return Err("PASSWORD_BREACH_MIN_COUNT must be a positive number".to_string()),
+            },
+            Err(_) => 1,
+        };
+        let breach_fail_open = match env::var("PASSWORD_BREACH_FAIL_OPEN") {
+            Ok(value) => value
+                .parse::<bool>()
+                .map_err(|_| "PASSWORD_BREACH_FAIL_OPEN must be a boolean".to_string())?,
+            Err(_) => false,
+        };
+        Ok(PasswordPolicy {
+            min_length,
+            max_length,
+            required_classes,
+            min_entropy,
+            forbidden_words,
+            forbid_email,
+            breach_corpus_dir,
+            breach_min_count,
+            breach_fail_open,
+        })
+    }
+
+    fn parse_env(name: &str, default: usize) -> Result<usize, String> {
+        match env::var(name) {
+            Ok(value) => value
+                .parse::<usize>()
+                .map_err(|_| format!("{} must be a number", name)),
+            Err(_) => Ok(default),
+        }
+    }
+
+    // Length times the log2 of the size of the character classes used
+    pub fn estimate_entropy(password: &str) -> f64 {
+        let mut pool = 0;
+        if password.chars().any(|c| c.is_ascii_lowercase()) {
+            pool += 26;
+        }
+        if password.chars().any(|c| c.is_ascii_uppercase()) {
+            pool += 26;
+        }
+        if password.chars().any(|c| c.is_ascii_digit()) {
+            pool += 10;
+        }
+        if password
+            .chars()
+            .any(|c| c.is_ascii() && !c.is_ascii_alphanumeric())
+        {
+            pool += 33;
+        }
+        if !password.is_ascii() {
+            pool += 100;
+        }
+        if pool == 0 {
+            return 0.0;
+        }
+        password.chars().count() as f64 * (pool as f64).log2()
+    }
+
+    // Return every rule the password doesn't respect
+    pub async fn validate(&self, password: &str, email: &str) -> Result<(), Vec<PasswordRule>> {
+        let mut failed = vec![];
+        let length = password.chars().count();
+        if length < self.min_length {
+            failed.push(PasswordRule::MinLength);
+        }
+        if length > self.max_length {
+            failed.push(PasswordRule::MaxLength);
+        }
+        for class in &self.required_classes {
+            let present = match class {
+                PasswordRule::Lowercase => password.chars().any(|c| c.is_lowercase()),
+                PasswordRule::Uppercase => password.chars().any(|c| c.is_uppercase()),
+                PasswordRule::Digit => password.chars().any(|c| c.is_numeric()),
+                _ => password.chars().any(|c| !c.is_alphanumeric()),
+            };
+            if !present {
+                failed.push(*class);
+            }
+        }
+        if PasswordPolicy::estimate_entropy(password) < self.min_entropy {
+            failed.push(PasswordRule::Entropy);
+        }
+        let lowercase = password.to_lowercase();
+        if self
+            .forbidden_words
+            .iter()
+            .any(|word| lowercase.contains(word.as_str()))
+        {
+            failed.push(PasswordRule::ForbiddenWord);
+        }
+        if self.forbid_email {
+            let email = email.to_lowercase();
+            let local_part = email.split('@').next().unwrap_or_default();
+            if (!email.is_empty() && lowercase.contains(email.as_str()))
+                || (local_part.chars().count() >= 3 && lowercase.contains(local_part))
+            {
+                failed.push(PasswordRule::ContainsEmail);
+            }
+        }
+        if let Some(dir) = self.breach_corpus_dir.clone() {
+            // The corpus is read from the disk on the blocking thread pool
+            let password = password.to_string();
+            let min_count = self.breach_min_count;
+            let breached =
+                web::block(move || string_rule::is_breached_password(&dir, &password, min_count))
+                    .await
+                    .map_err(|err| err.to_string())
+                    .and_then(|breached| breached);
+            match breached {
+                Ok(true) => failed.push(PasswordRule::Breached),
+                Ok(false) => (),
+                Err(err) => {
+                    tracing::error!(error = ?err, "Error while checking breach corpus");
+                    if !self.breach_fail_open {
+                        failed.push(PasswordRule::BreachUnchecked);
+                    }
+                }
+            }
+        }
+        if failed.is_empty() {
+            Ok(())
+        } else {
+            Err(failed)
+        }
+    }
+
+    pub fn error(failed_rules: Vec<PasswordRule>) -> PasswordPolicyError {
+        let message = if failed_rules.contains(&PasswordRule::Breached) {
+            "Password not valid, it appears in a known data breach"
+        } else if failed_rules.contains(&PasswordRule::BreachUnchecked) {
+            "Password can't be checked against the known data breaches, retry later"
+        } else {
+            "Password not valid"
+        };
+        PasswordPolicyError {
+            message: message.to_string(),
+            failed_rules,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{PasswordPolicy, PasswordRule};
+
+    fn policy() -> PasswordPolicy {
+        PasswordPolicy {
+            min_length: 8,
+            max_length: 16,
+            required_classes: vec![
+                PasswordRule::Lowercase,
+                PasswordRule::Uppercase,
+                PasswordRule::Digit,
+            ],
+            min_entropy: 0.0,
+            forbidden_words: vec!["azerty".to_string()],
+            forbid_email: true,
+            breach_corpus_dir: None,
+            breach_min_count: 1,
+            breach_fail_open: false,
+        }
+    }
+
+    #[actix_web::test]
+    async fn valid_password() {
+        assert_eq!(
+            policy().validate("Tr0ubadour", "jean@test.fr").await,
+            Ok(())
+        );
+    }
+
+    #[actix_web::test]
+    async fn every_failed_rule_is_returned() {
+        assert_eq!(
+            policy().validate("abc", "jean@test.fr").await,
+            Err(vec![
+                PasswordRule::MinLength,
+                PasswordRule::Uppercase,
+                PasswordRule::Digit
+            ])
+        );
+        assert_eq!(
+            policy()
+                .validate("Tr0ubadour-Tr0ubadour", "jean@test.fr")
+                .await,
+            Err(vec![PasswordRule::MaxLength])
+        );
+    }
+
+    #[actix_web::test]
+    async fn length_is_counted_in_characters() {
+        let policy = PasswordPolicy {
+            required_classes: vec![],
+            ..policy()
+        };
+        assert_eq!(
+            policy.validate("ééééééé", "jean@test.fr").await,
+            Err(vec![PasswordRule::MinLength])
+        );
+        assert_eq!(policy.validate("éééééééé", "jean@test.fr").await, Ok(()));
+    }
+
+    #[actix_web::test]
+    async fn symbol_class() {
+        let policy = PasswordPolicy {
+            required_classes: vec![PasswordRule::Symbol],
+            ..policy()
+        };
+        assert_eq!(
+            policy.validate("Tr0ubadour", "jean@test.fr").await,
+            Err(vec![PasswordRule::Symbol])
+        );
+        assert_eq!(policy.validate("Tr0ubadour!", "jean@test.fr").await, Ok(()));
+    }
+
+    #[actix_web::test]
+    async fn forbidden_word_ignores_the_case() {
+        assert_eq!(
+            policy().validate("AzErTy123X", "jean@test.fr").await,
+            Err(vec![PasswordRule::ForbiddenWord])
+        );
+    }
+
+    #[actix_web::test]
+    async fn email_and_local_part_are_forbidden() {
+        assert_eq!(
+            policy().validate("Jean@test.fr1", "jean@test.fr").await,
+            Err(vec![PasswordRule::ContainsEmail])
+        );
+        assert_eq!(
+            policy().validate("xJEAN2024x", "jean@test.fr").await,
+            Err(vec![PasswordRule::ContainsEmail])
+        );
+        // A local part under 3 characters is too common to be checked
+        assert_eq!(policy().validate("Jo2024abcd", "jo@test.fr").await, Ok(()));
+        let policy = PasswordPolicy {
+            forbid_email: false,
+            ..policy()
+        };
+        assert_eq!(policy.validate("xJEAN2024x", "jean@test.fr").await, Ok(()));
+    }
+
+    #[actix_web::test]
+    async fn entropy() {
+        let policy = PasswordPolicy {
+            min_entropy: 60.0,
+            ..policy()
+        };
+        // 10 characters from 62 symbols is about 59.5 bits
+        assert_eq!(
+            policy.validate("Tr0ubadour", "jean@test.fr").await,
+            Err(vec![PasswordRule::Entropy])
+        );
+        assert_eq!(policy.validate("Tr0ubadour!", "jean@test.fr").await, Ok(()));
+        assert_eq!(PasswordPolicy::estimate_entropy(""), 0.0);
+    }
+
+    #[actix_web::test]
+    async fn unreadable_breach_corpus() {
+        let dir = std::env::temp_dir().join(format!("breach-policy-{}", uuid::Uuid::new_v4()));
+        // SHA-1 prefix of Tr0ubadour, a directory can't be read as a prefix file
+        std::fs::create_dir_all(dir.join("1264B")).unwrap();
+        let policy = PasswordPolicy {
+            breach_corpus_dir: Some(dir.clone()),
+            ..policy()
+        };
+        assert_eq!(
+            policy.validate("Tr0ubadour", "jean@test.fr").await,
+            Err(vec![PasswordRule::BreachUnchecked])
+        );
+        let policy = PasswordPolicy {
+            breach_fail_open: true,
+            ..policy
+        };
+        assert_eq!(policy.validate("Tr0ubadour", "jean@test.fr").await, Ok(()));
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
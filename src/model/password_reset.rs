@@ -0,0 +1,121 @@
+use std::env;
+
+use openssl::sha::sha256;
+use uuid::Uuid;
+
+use crate::helper;
+
+// Lifetime in seconds of a reset token (PASSWORD_RESET_LIFETIME) and the page of
+// the front receiving the token (PASSWORD_RESET_URL)
+#[derive(Clone)]
+pub struct PasswordResetConfig {
+    pub lifetime: i64,
+    pub url: String,
+}
+
+impl PasswordResetConfig {
+    pub fn new() -> Result<PasswordResetConfig, String> {
+        let lifetime = match env::var("PASSWORD_RESET_LIFETIME") {
+            Ok(value) => match value.parse::<i64>() {
+                Ok(value) if value > 0 => value,
+                _ => return Err("PASSWORD_RESET_LIFETIME must be a positive number".to_string()),
+            },
+            Err(_) => 3600,
+        };
+        Ok(PasswordResetConfig {
+            lifetime,
+            url: env::var("PASSWORD_RESET_URL")
+                .unwrap_or_else(|_| "http://localhost:5173/reset-password".to_string()),
+        })
+    }
+
+    pub fn reset_link(&self, token: &str) -> String {
+        format!("{}?token={}", self.url, token)
+    }
+}
+
+// Single use token sent by mail to reset a forgotten password, only a sha256
+// of the token is stored and a user only has one token at a time
+pub struct PasswordReset;
+
+impl PasswordReset {
+    pub async fn create_table(pool: deadpool_postgres::Pool) -> Result<u64, tokio_postgres::Error> {
+        let client = pool.get().await.unwrap();
+
+        let create_table = "
+      CREATE TABLE IF NOT EXISTS password_reset_tokens (
+        token_hash VARCHAR PRIMARY KEY,
+        user_id UUID NOT NULL UNIQUE,
+        created_at TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+        expires_at TIMESTAMPTZ NOT NULL
+      );";
+        client.execute(create_table, &[]).await
+    }
+
+    fn hash(token: &str) -> String {
+        sha256(token.as_bytes())
+            .iter()
+            .map(|byte| format!("{:02x}", byte))
+            .collect()
+    }
+
+    // Replace the previous token of the user and return the new one, None if the
+    // previous token is less than a minute old so the mails can't be flooded and a
+    // link can't be invalidated again and again
+    pub async fn create(
+        pool: deadpool_postgres::Pool,
+        user_id: Uuid,
+        lifetime: i64,
+    ) -> Result<Option<String>, tokio_postgres::Error> {
+        let client = pool.get().await.unwrap();
+
+        let token = helper::string::generate_random_string(48);
+        let create = "
+        INSERT INTO password_reset_tokens (token_hash, user_id, expires_at)
+        VALUES ($1, $2, NOW() + $3::BIGINT * INTERVAL '1 second')
+        ON CONFLICT (user_id) DO UPDATE
+        SET token_hash = EXCLUDED.token_hash, created_at = NOW(), expires_at = EXCLUDED.expires_at
+        WHERE password_reset_tokens.created_at < NOW() - INTERVAL '1 minute'";
+        let created = client
+            .execute(create, &[&PasswordReset::hash(&token), &user_id, &lifetime])
+            .await?;
+        Ok((created == 1).then_some(token))
+    }
+
+    // Return the user of the token if it has not expired, without consuming it
+    pub async fn find(
+        pool: deadpool_postgres::Pool,
+        token: &str,
+    ) -> Result<Option<Uuid>, tokio_postgres::Error> {
+        let client = pool.get().await.unwrap();
+
+        let find = "
+        SELECT user_id
+        FROM password_reset_tokens
+        WHERE token_hash = $1 AND expires_at > NOW()";
+        let row = client
+            .query_opt(find, &[&PasswordReset::hash(token)])
+            .await?;
+        Ok(row.map(|row| row.get(0)))
+    }
+
+    // Remove the token and return its user if it has not expired
+    pub async fn consume(
+        pool: deadpool_postgres::Pool,
+        token: &str,
+    ) -> Result<Option<Uuid>, tokio_postgres::Error> {
+        let client = pool.get().await.unwrap();
+
+        let consume = "
+        DELETE FROM password_reset_tokens
+        WHERE token_hash = $1
+        RETURNING user_id, expires_at > NOW()";
+        let row = client
+            .query_opt(consume, &[&PasswordReset::hash(token)])
+            .await?;
+        Ok(row.and_then(|row| {
+            let valid: bool = row.get(1);
+            valid.then(|| row.get(0))
+        }))
+    }
+}
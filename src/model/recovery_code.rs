@@ -0,0 +1,115 @@
+use openssl::sha::sha256;
+use rand::Rng;
+
+// Without 0/o, 1/l/i to be easily copied by hand
+const RECOVERY_CODE_ALPHABET: &[u8] = b"abcdefghjkmnpqrstuvwxyz23456789";
+const RECOVERY_CODE_COUNT: usize = 10;
+
+// Single use code replacing a totp code when the user lost its authenticator,
+// only a sha256 of the code is stored
+pub struct RecoveryCode;
+
+impl RecoveryCode {
+    pub async fn create_table(pool: deadpool_postgres::Pool) -> Result<u64, tokio_postgres::Error> {
+        let client = pool.get().await.unwrap();
+
+        let create_table = "
+      CREATE TABLE IF NOT EXISTS otp_recovery_codes (
+        user_id UUID NOT NULL,
+        code_hash VARCHAR NOT NULL,
+        created_at TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+        used_at TIMESTAMPTZ,
+        PRIMARY KEY (user_id, code_hash)
+      );";
+        client.execute(create_table, &[]).await
+    }
+
+    // Codes in the xxxx-xxxx-xxxx-xxxx format
+    pub fn generate_codes() -> Vec<String> {
+        let mut rng = rand::thread_rng();
+        (0..RECOVERY_CODE_COUNT)
+            .map(|_| {
+                (0..4)
+                    .map(|_| {
+                        (0..4)
+                            .map(|_| {
+                                let idx = rng.gen_range(0..RECOVERY_CODE_ALPHABET.len());
+                                RECOVERY_CODE_ALPHABET[idx] as char
+                            })
+                            .collect::<String>()
+                    })
+                    .collect::<Vec<String>>()
+                    .join("-")
+            })
+            .collect()
+    }
+
+    // Case, dash and space are ignored
+    fn hash(code: &str) -> String {
+        let normalized: String = code
+            .chars()
+            .filter(|c| *c != '-' && !c.is_whitespace())
+            .flat_map(|c| c.to_lowercase())
+            .collect();
+        sha256(normalized.as_bytes())
+            .iter()
+            .map(|byte| format!("{:02x}", byte))
+            .collect()
+    }
+
+    // Replace every code of the user, the previous one can no longer be used
+    pub async fn replace_all(
+        pool: deadpool_postgres::Pool,
+        user_id: uuid::Uuid,
+        codes: &[String],
+    ) -> Result<u64, tokio_postgres::Error> {
+        let mut client = pool.get().await.unwrap();
+        let transaction = client.transaction().await?;
+
+        transaction
+            .execute(
+                "DELETE FROM otp_recovery_codes WHERE user_id = $1",
+                &[&user_id],
+            )
+            .await?;
+        let insert = "
+        INSERT INTO otp_recovery_codes (user_id, code_hash)
+        VALUES ($1, $2)";
+        let mut inserted = 0;
+        for code in codes {
+            inserted += transaction
+                .execute(insert, &[&user_id, &RecoveryCode::hash(code)])
+                .await?;
+        }
+        transaction.commit().await?;
+        Ok(inserted)
+    }
+
+    // Mark the code as used, return false if it does not exist or was already used
+    pub async fn consume(
+        pool: deadpool_postgres::Pool,
+        user_id: uuid::Uuid,
+        code: &str,
+    ) -> Result<bool, tokio_postgres::Error> {
+        let client = pool.get().await.unwrap();
+
+        let consume = "
+        UPDATE otp_recovery_codes
+        SET used_at = NOW()
+        WHERE user_id = $1 AND code_hash = $2 AND used_at IS NULL";
+        let consumed = client
+            .execute(consume, &[&user_id, &RecoveryCode::hash(code)])
+            .await?;
+        Ok(consumed == 1)
+    }
+
+    pub async fn delete_all_by_user(
+        pool: deadpool_postgres::Pool,
+        user_id: uuid::Uuid,
+    ) -> Result<u64, tokio_postgres::Error> {
+        let client = pool.get().await.unwrap();
+
+        let delete = "DELETE FROM otp_recovery_codes WHERE user_id = $1";
+        client.execute(delete, &[&user_id]).await
+    }
+}
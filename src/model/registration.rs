@@ -0,0 +1,231 @@
+use std::env;
+
+use openssl::{hash::MessageDigest, memcmp, pkey::PKey, sign::Signer};
+use serde::Serialize;
+use utoipa::ToSchema;
+use uuid::Uuid;
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum RegistrationMode {
+    Open,
+    // An invitation code minted by an admin is required
+    Invite,
+    Closed,
+}
+
+// Who can register (REGISTRATION_MODE open, invite or closed), the email domains
+// allowed (REGISTRATION_ALLOWED_DOMAINS, any if empty), the lifetime in days of an
+// invitation (INVITATION_LIFETIME) and the key signing the invitation codes
+// (INVITATION_SIGN). The oidc users created on their first login follow it too.
+#[derive(Clone)]
+pub struct RegistrationConfig {
+    pub mode: RegistrationMode,
+    pub allowed_domains: Vec<String>,
+    pub invitation_lifetime: i64,
+    sign_key: Vec<u8>,
+}
+
+impl RegistrationConfig {
+    pub fn new() -> Result<RegistrationConfig, String> {
+        let mode = match env::var("REGISTRATION_MODE")
+            .unwrap_or_else(|_| "open".to_string())
+            .as_str()
+        {
+            "open" => RegistrationMode::Open,
+            "invite" => RegistrationMode::Invite,
+            "closed" => RegistrationMode::Closed,
+            _ => return Err("REGISTRATION_MODE must be open, invite or closed".to_string()),
+        };
+        let allowed_domains = env::var("REGISTRATION_ALLOWED_DOMAINS")
+            .unwrap_or_default()
+            .split(',')
+            .map(|domain| domain.trim().trim_start_matches('@').to_lowercase())
+            .filter(|domain| !domain.is_empty())
+            .collect();
+        let invitation_lifetime = match env::var("INVITATION_LIFETIME") {
+            Ok(value) => match value.parse::<i64>() {
+                Ok(value) if value > 0 => value,
+                _ => return Err("INVITATION_LIFETIME must be a positive number".to_string()),
+            },
+            Err(_) => 7,
+        };
+        Ok(RegistrationConfig {
+            mode,
+            allowed_domains,
+            invitation_lifetime,
+            sign_key: env::var("INVITATION_SIGN")
+                .unwrap_or_else(|_| "lambda_invitation_sign".to_string())
+                .into_bytes(),
+        })
+    }
+
+    pub fn can_register(&self) -> bool {
+        self.mode != RegistrationMode::Closed
+    }
+
+    pub fn invitation_required(&self) -> bool {
+        self.mode == RegistrationMode::Invite
+    }
+
+    pub fn domain_allowed(&self, email: &str) -> bool {
+        if self.allowed_domains.is_empty() {
+            return true;
+        }
+        match email.rsplit_once('@') {
+            Some((_, domain)) => self
+                .allowed_domains
+                .iter()
+                .any(|allowed| allowed.eq_ignore_ascii_case(domain)),
+            None => false,
+        }
+    }
+
+    fn sign(&self, invitation_id: Uuid) -> Result<String, String> {
+        let key = PKey::hmac(&self.sign_key).map_err(|err| err.to_string())?;
+        let mut signer =
+            Signer::new(MessageDigest::sha256(), &key).map_err(|err| err.to_string())?;
+        signer
+            .update(format!("invitation:{}", invitation_id).as_bytes())
+            .map_err(|err| err.to_string())?;
+        let signature = signer.sign_to_vec().map_err(|err| err.to_string())?;
+        Ok(signature
+            .iter()
+            .map(|byte| format!("{:02x}", byte))
+            .collect())
+    }
+
+    // The invitation code is the id of the invitation followed by its signature
+    pub fn invitation_code(&self, invitation_id: Uuid) -> Result<String, String> {
+        Ok(format!("{}.{}", invitation_id, self.sign(invitation_id)?))
+    }
+
+    // Return the id of the invitation if the code has been signed with our key
+    pub fn verify_invitation_code(&self, code: &str) -> Option<Uuid> {
+        let (invitation_id, signature) = code.trim().split_once('.')?;
+        let invitation_id = Uuid::parse_str(invitation_id).ok()?;
+        let expected = self.sign(invitation_id).ok()?;
+        if expected.len() == signature.len()
+            && memcmp::eq(expected.as_bytes(), signature.as_bytes())
+        {
+            Some(invitation_id)
+        } else {
+            None
+        }
+    }
+}
+
+// An invitation can be used once, if email is set only this address can use it.
+// Revoking it only require to delete the row
+#[derive(ToSchema, Clone, Serialize)]
+pub struct Invitation {
+    pub id: Uuid,
+    pub email: Option<String>,
+    pub created_by: Uuid,
+    pub created_at: chrono::DateTime<chrono::Utc>,
+    pub expires_at: chrono::DateTime<chrono::Utc>,
+    pub used_at: Option<chrono::DateTime<chrono::Utc>>,
+    pub used_by: Option<Uuid>,
+}
+
+impl Invitation {
+    pub async fn create_table(pool: deadpool_postgres::Pool) -> Result<u64, tokio_postgres::Error> {
+        let client = pool.get().await.unwrap();
+
+        let create_table = "
+      CREATE TABLE IF NOT EXISTS invitations (
+        id UUID PRIMARY KEY DEFAULT uuid_generate_v4(),
+        email VARCHAR(255),
+        created_by UUID NOT NULL,
+        created_at TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+        expires_at TIMESTAMPTZ NOT NULL,
+        used_at TIMESTAMPTZ,
+        used_by UUID
+      );";
+        client.execute(create_table, &[]).await
+    }
+
+    fn from_row(row: &tokio_postgres::Row) -> Invitation {
+        Invitation {
+            id: row.get(0),
+            email: row.get(1),
+            created_by: row.get(2),
+            created_at: row.get(3),
+            expires_at: row.get(4),
+            used_at: row.get(5),
+            used_by: row.get(6),
+        }
+    }
+
+    pub async fn create(
+        pool: deadpool_postgres::Pool,
+        email: Option<String>,
+        created_by: Uuid,
+        lifetime: i64,
+    ) -> Result<Invitation, tokio_postgres::Error> {
+        let client = pool.get().await.unwrap();
+
+        let create = "
+        INSERT INTO invitations (email, created_by, expires_at)
+        VALUES ($1, $2, NOW() + $3::BIGINT * INTERVAL '1 day')
+        RETURNING id, email, created_by, created_at, expires_at, used_at, used_by";
+        let row = client
+            .query_one(create, &[&email, &created_by, &lifetime])
+            .await?;
+        Ok(Invitation::from_row(&row))
+    }
+
+    pub async fn get_all(
+        pool: deadpool_postgres::Pool,
+    ) -> Result<Vec<Invitation>, tokio_postgres::Error> {
+        let client = pool.get().await.unwrap();
+
+        let get_all = "
+        SELECT id, email, created_by, created_at, expires_at, used_at, used_by
+        FROM invitations
+        ORDER BY created_at DESC";
+        let rows = client.query(get_all, &[]).await?;
+        Ok(rows.iter().map(Invitation::from_row).collect())
+    }
+
+    // Mark the invitation as used by the user, return false if it is already used,
+    // expired, revoked or bound to another email
+    pub async fn consume(
+        pool: deadpool_postgres::Pool,
+        id: Uuid,
+        email: &str,
+        user_id: Uuid,
+    ) -> Result<bool, tokio_postgres::Error> {
+        let client = pool.get().await.unwrap();
+
+        let consume = "
+        UPDATE invitations
+        SET used_at = NOW(), used_by = $3
+        WHERE id = $1 AND used_at IS NULL AND expires_at > NOW()
+        AND (email IS NULL OR LOWER(email) = LOWER($2))";
+        Ok(client.execute(consume, &[&id, &email, &user_id]).await? == 1)
+    }
+
+    // Make the invitation usable again when the user creation failed
+    pub async fn release(
+        pool: deadpool_postgres::Pool,
+        id: Uuid,
+    ) -> Result<u64, tokio_postgres::Error> {
+        let client = pool.get().await.unwrap();
+
+        let release = "
+        UPDATE invitations
+        SET used_at = NULL, used_by = NULL
+        WHERE id = $1";
+        client.execute(release, &[&id]).await
+    }
+
+    pub async fn delete(
+        pool: deadpool_postgres::Pool,
+        id: Uuid,
+    ) -> Result<u64, tokio_postgres::Error> {
+        let client = pool.get().await.unwrap();
+
+        let delete = "DELETE FROM invitations WHERE id = $1";
+        client.execute(delete, &[&id]).await
+    }
+}
@@ -0,0 +1,187 @@
+use std::{
+    collections::HashMap,
+    env, fmt,
+    sync::{Arc, RwLock},
+    time::Duration,
+};
+
+use uuid::Uuid;
+
+use super::token::{RefreshToken, TokenClaims, TokenLifetime};
+
+#[derive(Clone, Copy, PartialEq, Eq, Hash)]
+pub enum RevocationKind {
+    Session,
+    User,
+}
+
+impl fmt::Display for RevocationKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            RevocationKind::Session => write!(f, "session"),
+            RevocationKind::User => write!(f, "user"),
+        }
+    }
+}
+
+// Revocation date by revoked session or user
+type Revocations = HashMap<(RevocationKind, Uuid), chrono::DateTime<chrono::Utc>>;
+
+// Access token are not stored, a revoked session (sid claim) or user (sub claim) is kept
+// until every access token issued before the revocation has expired.
+// The list is kept in memory and reloaded from the database every
+// REVOCATION_SYNC_INTERVAL seconds (5) so a revocation reach every replica.
+#[derive(Clone)]
+pub struct TokenDenylist {
+    revoked: Arc<RwLock<Revocations>>,
+    sync_interval: u64,
+}
+
+impl TokenDenylist {
+    pub fn new() -> Result<TokenDenylist, String> {
+        let sync_interval = match env::var("REVOCATION_SYNC_INTERVAL") {
+            Ok(value) => match value.parse::<u64>() {
+                Ok(value) if value > 0 => value,
+                _ => return Err("REVOCATION_SYNC_INTERVAL must be a positive number".to_string()),
+            },
+            Err(_) => 5,
+        };
+        Ok(TokenDenylist {
+            revoked: Arc::new(RwLock::new(HashMap::new())),
+            sync_interval,
+        })
+    }
+
+    pub async fn create_table(pool: deadpool_postgres::Pool) -> Result<u64, tokio_postgres::Error> {
+        let client = pool.get().await.unwrap();
+
+        let create_table = "
+      CREATE TABLE IF NOT EXISTS revoked_tokens (
+        kind VARCHAR NOT NULL,
+        id UUID NOT NULL,
+        revoked_at TIMESTAMPTZ NOT NULL,
+        expires_at TIMESTAMPTZ NOT NULL,
+        PRIMARY KEY (kind, id)
+      );";
+        client.execute(create_table, &[]).await
+    }
+
+    // Revoke every access token of the session or user issued until now
+    pub async fn revoke(
+        &self,
+        pool: deadpool_postgres::Pool,
+        kind: RevocationKind,
+        id: Uuid,
+        lifetime: &TokenLifetime,
+    ) -> Result<u64, tokio_postgres::Error> {
+        let client = pool.get().await.unwrap();
+
+        let revoked_at = chrono::Utc::now();
+        let expires_at = revoked_at + chrono::Duration::seconds(lifetime.access);
+        let revoke = "
+        INSERT INTO revoked_tokens (kind, id, revoked_at, expires_at)
+        VALUES ($1, $2, $3, $4)
+        ON CONFLICT (kind, id) DO UPDATE
+        SET revoked_at = EXCLUDED.revoked_at,
+            expires_at = GREATEST(revoked_tokens.expires_at, EXCLUDED.expires_at)";
+        let revoked = client
+            .execute(revoke, &[&kind.to_string(), &id, &revoked_at, &expires_at])
+            .await?;
+        self.revoked.write().unwrap().insert((kind, id), revoked_at);
+        Ok(revoked)
+    }
+
+    // End every session of the user, the refresh tokens are deleted and the access tokens revoked
+    pub async fn revoke_all_sessions(
+        &self,
+        pool: deadpool_postgres::Pool,
+        user_id: Uuid,
+        lifetime: &TokenLifetime,
+    ) -> Result<u64, tokio_postgres::Error> {
+        let deleted = RefreshToken::delete_all_by_user(pool.clone(), user_id).await?;
+        self.revoke(pool, RevocationKind::User, user_id, lifetime)
+            .await?;
+        Ok(deleted)
+    }
+
+    // End every session of the user except the current one
+    pub async fn revoke_other_sessions(
+        &self,
+        pool: deadpool_postgres::Pool,
+        user_id: Uuid,
+        current_session: Uuid,
+        lifetime: &TokenLifetime,
+    ) -> Result<u64, tokio_postgres::Error> {
+        let mut revoked = 0;
+        for session in RefreshToken::get_sessions(pool.clone(), user_id).await? {
+            if session.id == current_session {
+                continue;
+            }
+            revoked += RefreshToken::delete_session(pool.clone(), user_id, session.id).await?;
+            self.revoke(pool.clone(), RevocationKind::Session, session.id, lifetime)
+                .await?;
+        }
+        Ok(revoked)
+    }
+
+    // Remove the expired revocation and reload the list from the database
+    pub async fn sync(
+        &self,
+        pool: deadpool_postgres::Pool,
+    ) -> Result<usize, tokio_postgres::Error> {
+        let client = pool.get().await.unwrap();
+
+        let delete_expired = "
+        DELETE FROM revoked_tokens
+        WHERE expires_at < NOW()";
+        client.execute(delete_expired, &[]).await?;
+
+        let get_all = "
+        SELECT kind, id, revoked_at
+        FROM revoked_tokens";
+        let rows = client.query(get_all, &[]).await?;
+        let revoked: Revocations = rows
+            .iter()
+            .filter_map(|row| {
+                let kind = match row.get::<_, String>(0).as_str() {
+                    "session" => RevocationKind::Session,
+                    "user" => RevocationKind::User,
+                    _ => return None,
+                };
+                Some(((kind, row.get(1)), row.get(2)))
+            })
+            .collect();
+        let nbr = revoked.len();
+        *self.revoked.write().unwrap() = revoked;
+        Ok(nbr)
+    }
+
+    pub fn start_sync(&self, pool: deadpool_postgres::Pool) {
+        let denylist = self.clone();
+        actix_web::rt::spawn(async move {
+            loop {
+                actix_web::rt::time::sleep(Duration::from_secs(denylist.sync_interval)).await;
+                if let Err(err) = denylist.sync(pool.clone()).await {
+                    tracing::error!(error = ?err, "Error while syncing the token denylist");
+                }
+            }
+        });
+    }
+
+    pub fn is_revoked(&self, claims: &TokenClaims) -> bool {
+        let revoked = self.revoked.read().unwrap();
+        // A token issued before iat_ms existed is only known to the second, it is
+        // considered revoked if issued in the second of the revocation
+        let issued_before = |key: (RevocationKind, Uuid)| match revoked.get(&key) {
+            Some(revoked_at) => match claims.iat_ms {
+                Some(iat_ms) => iat_ms <= revoked_at.timestamp_millis(),
+                None => claims.iat as i64 <= revoked_at.timestamp(),
+            },
+            None => false,
+        };
+        issued_before((RevocationKind::User, claims.sub))
+            || claims
+                .sid
+                .is_some_and(|sid| issued_before((RevocationKind::Session, sid)))
+    }
+}
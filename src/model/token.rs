@@ -1,84 +1,515 @@
 use std::env;
 
-use jsonwebtoken::{encode, Algorithm, DecodingKey, EncodingKey, Header, Validation};
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use jsonwebtoken::{
+    encode,
+    jwk::{
+        AlgorithmParameters, CommonParameters, EllipticCurve, EllipticCurveKeyParameters,
+        EllipticCurveKeyType, Jwk, JwkSet, OctetKeyPairParameters, OctetKeyPairType, PublicKeyUse,
+        RSAKeyParameters, RSAKeyType,
+    },
+    Algorithm, DecodingKey, EncodingKey, Header, Validation,
+};
+use openssl::{
+    bn::{BigNum, BigNumContext},
+    hash::MessageDigest,
+    nid::Nid,
+    pkey::{Id, PKey},
+    sign::Signer,
+};
 use serde::{Deserialize, Serialize};
 use utoipa::ToSchema;
 
+use super::user::User;
+
+// Issuer of the buildin token
+pub const ISSUER: &str = "Rust_api";
+
 #[derive(ToSchema, Clone, Serialize, Deserialize)]
 pub struct TokenClaims {
     pub sub: uuid::Uuid, // subject
     pub email: String,   // email
     pub exp: usize,      // expiration
     pub iat: usize,      // issued at
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub iat_ms: Option<i64>, // issued at in milliseconds, compared to the revocations
     pub iss: String,     // issuer
     pub refresh: bool,   // is refresh token
+    #[serde(default)]
+    pub jti: uuid::Uuid, // token id
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sid: Option<uuid::Uuid>, // session id, only on access token
 }
 
-impl TokenClaims {
-    pub fn new_token_claims(user_id: uuid::Uuid, email: String, refresh: bool) -> TokenClaims {
-        let mut exp = chrono::Utc::now() + chrono::Duration::hours(1);
+// A signing key, either a shared secret (HS512) or an asymmetric key loaded from a PEM file
+#[derive(Clone)]
+pub struct TokenKey {
+    pub kid: String,
+    pub algorithm: Algorithm,
+    encoding_key: EncodingKey,
+    decoding_key: DecodingKey,
+    pub jwk: Option<Jwk>,
+    pub retire_at: Option<chrono::DateTime<chrono::Utc>>,
+}
+
+impl TokenKey {
+    pub fn from_secret(kid: String, secret: String) -> TokenKey {
+        TokenKey {
+            kid,
+            algorithm: Algorithm::HS512,
+            encoding_key: EncodingKey::from_secret(secret.as_bytes()),
+            decoding_key: DecodingKey::from_secret(secret.as_bytes()),
+            jwk: None,
+            retire_at: None,
+        }
+    }
+
+    // Load a private key from a PEM file, the algorithm is deduced from the key type
+    // (RSA => RS256, EC P-256 => ES256, Ed25519 => EdDSA) and the kid default to the
+    // RFC 7638 thumbprint of the public key
+    pub fn from_pem_file(path: String, kid: Option<String>) -> Result<TokenKey, String> {
+        let pem = match std::fs::read(&path) {
+            Ok(pem) => pem,
+            Err(err) => return Err(format!("Error while reading key {}: {}", path, err)),
+        };
+        let private_key = match PKey::private_key_from_pem(&pem) {
+            Ok(key) => key,
+            Err(err) => return Err(format!("Error while parsing key {}: {}", path, err)),
+        };
+        let pkcs8 = match private_key.private_key_to_pem_pkcs8() {
+            Ok(pkcs8) => pkcs8,
+            Err(err) => return Err(format!("Error while converting key {}: {}", path, err)),
+        };
+        let (algorithm, encoding_key, params) = match private_key.id() {
+            Id::RSA => {
+                let rsa = private_key.rsa().map_err(|err| err.to_string())?;
+                (
+                    Algorithm::RS256,
+                    EncodingKey::from_rsa_pem(&pkcs8),
+                    AlgorithmParameters::RSA(RSAKeyParameters {
+                        key_type: RSAKeyType::RSA,
+                        n: URL_SAFE_NO_PAD.encode(rsa.n().to_vec()),
+                        e: URL_SAFE_NO_PAD.encode(rsa.e().to_vec()),
+                    }),
+                )
+            }
+            Id::EC => {
+                let ec = private_key.ec_key().map_err(|err| err.to_string())?;
+                if ec.group().curve_name() != Some(Nid::X9_62_PRIME256V1) {
+                    return Err(format!("Key {} is not a P-256 key", path));
+                }
+                let mut ctx = BigNumContext::new().map_err(|err| err.to_string())?;
+                let mut x = BigNum::new().map_err(|err| err.to_string())?;
+                let mut y = BigNum::new().map_err(|err| err.to_string())?;
+                ec.public_key()
+                    .affine_coordinates(ec.group(), &mut x, &mut y, &mut ctx)
+                    .map_err(|err| err.to_string())?;
+                (
+                    Algorithm::ES256,
+                    EncodingKey::from_ec_pem(&pkcs8),
+                    AlgorithmParameters::EllipticCurve(EllipticCurveKeyParameters {
+                        key_type: EllipticCurveKeyType::EC,
+                        curve: EllipticCurve::P256,
+                        x: URL_SAFE_NO_PAD
+                            .encode(x.to_vec_padded(32).map_err(|err| err.to_string())?),
+                        y: URL_SAFE_NO_PAD
+                            .encode(y.to_vec_padded(32).map_err(|err| err.to_string())?),
+                    }),
+                )
+            }
+            Id::ED25519 => {
+                let raw = private_key
+                    .raw_public_key()
+                    .map_err(|err| err.to_string())?;
+                (
+                    Algorithm::EdDSA,
+                    EncodingKey::from_ed_pem(&pkcs8),
+                    AlgorithmParameters::OctetKeyPair(OctetKeyPairParameters {
+                        key_type: OctetKeyPairType::OctetKeyPair,
+                        curve: EllipticCurve::Ed25519,
+                        x: URL_SAFE_NO_PAD.encode(raw),
+                    }),
+                )
+            }
+            _ => return Err(format!("Key {} is not a RSA, P-256 or Ed25519 key", path)),
+        };
+        let encoding_key = match encoding_key {
+            Ok(key) => key,
+            Err(err) => return Err(format!("Error while loading key {}: {}", path, err)),
+        };
+        let kid = kid.unwrap_or_else(|| TokenKey::thumbprint(&params));
+        let jwk = Jwk {
+            common: CommonParameters {
+                public_key_use: Some(PublicKeyUse::Signature),
+                algorithm: Some(algorithm),
+                key_id: Some(kid.clone()),
+                ..Default::default()
+            },
+            algorithm: params,
+        };
+        let decoding_key = match DecodingKey::from_jwk(&jwk) {
+            Ok(key) => key,
+            Err(err) => return Err(format!("Error while loading public key {}: {}", path, err)),
+        };
+        Ok(TokenKey {
+            kid,
+            algorithm,
+            encoding_key,
+            decoding_key,
+            jwk: Some(jwk),
+            retire_at: None,
+        })
+    }
+
+    // RFC 7638 thumbprint, members have to be in lexicographic order
+    fn thumbprint(params: &AlgorithmParameters) -> String {
+        let canonical = match params {
+            AlgorithmParameters::RSA(rsa) => {
+                format!(r#"{{"e":"{}","kty":"RSA","n":"{}"}}"#, rsa.e, rsa.n)
+            }
+            AlgorithmParameters::EllipticCurve(ec) => format!(
+                r#"{{"crv":"P-256","kty":"EC","x":"{}","y":"{}"}}"#,
+                ec.x, ec.y
+            ),
+            AlgorithmParameters::OctetKeyPair(okp) => {
+                format!(r#"{{"crv":"Ed25519","kty":"OKP","x":"{}"}}"#, okp.x)
+            }
+            AlgorithmParameters::OctetKey(_) => return String::new(),
+        };
+        URL_SAFE_NO_PAD.encode(openssl::sha::sha256(canonical.as_bytes()))
+    }
+
+    pub fn is_retired(&self) -> bool {
+        match self.retire_at {
+            Some(retire_at) => retire_at <= chrono::Utc::now(),
+            None => false,
+        }
+    }
+}
+
+// Entry of {PREFIX}_PREVIOUS_KEYS, either a shared secret or the path of a PEM file
+#[derive(Deserialize)]
+struct TokenKeyConfig {
+    kid: Option<String>,
+    secret: Option<String>,
+    private_key: Option<String>,
+    retire_at: Option<chrono::DateTime<chrono::Utc>>,
+}
+
+// One key used to sign new tokens and every key still accepted to validate them
+#[derive(Clone)]
+pub struct TokenKeyRing {
+    pub signing: TokenKey,
+    pub previous: Vec<TokenKey>,
+}
+
+impl TokenKeyRing {
+    // Select the verification key by kid, retired keys are ignored
+    pub fn find(&self, kid: &str) -> Option<&TokenKey> {
+        std::iter::once(&self.signing)
+            .chain(self.previous.iter())
+            .find(|key| key.kid == kid && !key.is_retired())
+    }
+
+    pub fn active_keys(&self) -> Vec<&TokenKey> {
+        std::iter::once(&self.signing)
+            .chain(self.previous.iter())
+            .filter(|key| !key.is_retired())
+            .collect()
+    }
+}
+
+#[derive(Clone)]
+pub struct TokenKeys {
+    pub access: TokenKeyRing,
+    pub refresh: TokenKeyRing,
+    refresh_hash_key: Vec<u8>,
+}
+
+impl TokenKeys {
+    // Each token kind use {PREFIX}_PRIVATE_KEY (path to a PEM file) if set, otherwise
+    // fallback to the {PREFIX}_SIGN shared secret, {PREFIX}_KEY_ID override the kid.
+    // Keys still accepted during a rotation are listed in {PREFIX}_PREVIOUS_KEYS as a json
+    // array of {"kid", "secret" or "private_key", "retire_at"}.
+    // Refresh token are stored as an HMAC keyed with REFRESH_TOKEN_HASH_KEY
+    pub fn new() -> Result<TokenKeys, String> {
+        Ok(TokenKeys {
+            access: TokenKeys::load_ring("ACCESS_TOKEN", "access_token", "lambda_token_sign")?,
+            refresh: TokenKeys::load_ring(
+                "REFRESH_TOKEN",
+                "refresh_token",
+                "lambda_refresh_token_sign",
+            )?,
+            refresh_hash_key: env::var("REFRESH_TOKEN_HASH_KEY")
+                .unwrap_or_else(|_| "lambda_refresh_token_hash".to_string())
+                .into_bytes(),
+        })
+    }
+
+    fn load_ring(
+        prefix: &str,
+        default_kid: &str,
+        default_secret: &str,
+    ) -> Result<TokenKeyRing, String> {
+        let kid = env::var(format!("{}_KEY_ID", prefix)).ok();
+        let signing = match env::var(format!("{}_PRIVATE_KEY", prefix)) {
+            Ok(path) => TokenKey::from_pem_file(path, kid)?,
+            Err(_) => TokenKey::from_secret(
+                kid.unwrap_or_else(|| default_kid.to_string()),
+                env::var(format!("{}_SIGN", prefix)).unwrap_or_else(|_| default_secret.to_string()),
+            ),
+        };
+        let previous_configs: Vec<TokenKeyConfig> =
+            match env::var(format!("{}_PREVIOUS_KEYS", prefix)) {
+                Ok(value) => match serde_json::from_str(&value) {
+                    Ok(configs) => configs,
+                    Err(err) => return Err(format!("Invalid {}_PREVIOUS_KEYS: {}", prefix, err)),
+                },
+                Err(_) => vec![],
+            };
+        let mut previous = vec![];
+        for config in previous_configs {
+            let mut key = match (config.private_key, config.secret, config.kid.clone()) {
+                (Some(path), _, kid) => TokenKey::from_pem_file(path, kid)?,
+                (None, Some(secret), Some(kid)) => TokenKey::from_secret(kid, secret),
+                (None, Some(_), None) => {
+                    return Err(format!("{}_PREVIOUS_KEYS: a secret need a kid", prefix))
+                }
+                (None, None, _) => {
+                    return Err(format!(
+                        "{}_PREVIOUS_KEYS: a key need a secret or a private_key",
+                        prefix
+                    ))
+                }
+            };
+            if key.kid == signing.kid || previous.iter().any(|k: &TokenKey| k.kid == key.kid) {
+                return Err(format!(
+                    "{}_PREVIOUS_KEYS: duplicated kid {}",
+                    prefix, key.kid
+                ));
+            }
+            key.retire_at = config.retire_at;
+            previous.push(key);
+        }
+        Ok(TokenKeyRing { signing, previous })
+    }
+
+    pub fn hash_refresh_token(&self, token: &str) -> Result<String, String> {
+        let key = PKey::hmac(&self.refresh_hash_key).map_err(|err| err.to_string())?;
+        let mut signer =
+            Signer::new(MessageDigest::sha256(), &key).map_err(|err| err.to_string())?;
+        signer
+            .update(token.as_bytes())
+            .map_err(|err| err.to_string())?;
+        let hash = signer.sign_to_vec().map_err(|err| err.to_string())?;
+        Ok(hash.iter().map(|byte| format!("{:02x}", byte)).collect())
+    }
+
+    pub fn get(&self, refresh: bool) -> &TokenKeyRing {
         if refresh {
-            exp = chrono::Utc::now() + chrono::Duration::days(7);
+            &self.refresh
+        } else {
+            &self.access
         }
+    }
+
+    // A buildin token is a JWT issued by this api or signed with one of its key id (even
+    // retired), anything else including opaque token is left to the oidc provider
+    pub fn is_buildin_token(&self, token: &str) -> bool {
+        let header = match jsonwebtoken::decode_header(token) {
+            Ok(header) => header,
+            Err(_) => return false,
+        };
+        let known_kid = header.kid.is_some_and(|kid| {
+            std::iter::once(&self.access.signing)
+                .chain(self.access.previous.iter())
+                .chain(std::iter::once(&self.refresh.signing))
+                .chain(self.refresh.previous.iter())
+                .any(|key| key.kid == kid)
+        });
+        known_kid || TokenKeys::unverified_issuer(token).as_deref() == Some(ISSUER)
+    }
+
+    // Issuer read from the payload without checking the signature, only used to route the token
+    fn unverified_issuer(token: &str) -> Option<String> {
+        let payload = URL_SAFE_NO_PAD.decode(token.split('.').nth(1)?).ok()?;
+        let claims: serde_json::Value = serde_json::from_slice(&payload).ok()?;
+        claims["iss"].as_str().map(|iss| iss.to_string())
+    }
+
+    // Only the access token public keys are published, shared secrets are never exposed
+    pub fn jwks(&self) -> JwkSet {
+        JwkSet {
+            keys: self
+                .access
+                .active_keys()
+                .into_iter()
+                .filter_map(|key| key.jwk.clone())
+                .collect(),
+        }
+    }
+}
+
+// Lifetimes in seconds, the idle lifetime is the one of a refresh token and the absolute
+// lifetime cap the whole session whatever the number of refresh
+#[derive(Clone)]
+pub struct TokenLifetime {
+    pub access: i64,
+    pub session_idle: i64,
+    pub session_absolute: Option<i64>,
+    pub max_sessions: i64,
+}
+
+impl TokenLifetime {
+    // ACCESS_TOKEN_LIFETIME (1 hour), SESSION_IDLE_LIFETIME (7 days), SESSION_ABSOLUTE_LIFETIME
+    // (no limit if not set) and MAX_SESSIONS (4) per user
+    pub fn new() -> Result<TokenLifetime, String> {
+        Ok(TokenLifetime {
+            access: TokenLifetime::load_var("ACCESS_TOKEN_LIFETIME")?.unwrap_or(3600),
+            session_idle: TokenLifetime::load_var("SESSION_IDLE_LIFETIME")?.unwrap_or(604800),
+            session_absolute: TokenLifetime::load_var("SESSION_ABSOLUTE_LIFETIME")?,
+            max_sessions: TokenLifetime::load_var("MAX_SESSIONS")?.unwrap_or(4),
+        })
+    }
+
+    fn load_var(name: &str) -> Result<Option<i64>, String> {
+        match env::var(name) {
+            Ok(value) => match value.parse::<i64>() {
+                Ok(value) if value > 0 => Ok(Some(value)),
+                _ => Err(format!("{} must be a positive number", name)),
+            },
+            Err(_) => Ok(None),
+        }
+    }
+
+    // Values set on the user take precedence over the deployment ones
+    pub fn for_user(&self, user: &User) -> TokenLifetime {
+        TokenLifetime {
+            access: user.access_token_lifetime.map_or(self.access, i64::from),
+            session_idle: user
+                .session_idle_lifetime
+                .map_or(self.session_idle, i64::from),
+            session_absolute: user
+                .session_absolute_lifetime
+                .map(i64::from)
+                .or(self.session_absolute),
+            max_sessions: user.max_sessions.map_or(self.max_sessions, i64::from),
+        }
+    }
+
+    fn session_end(
+        &self,
+        session_created_at: chrono::DateTime<chrono::Utc>,
+    ) -> Option<chrono::DateTime<chrono::Utc>> {
+        self.session_absolute
+            .map(|absolute| session_created_at + chrono::Duration::seconds(absolute))
+    }
+
+    // No token can outlive the session
+    pub fn expiration(
+        &self,
+        refresh: bool,
+        session_created_at: chrono::DateTime<chrono::Utc>,
+    ) -> chrono::DateTime<chrono::Utc> {
+        let lifetime = if refresh {
+            self.session_idle
+        } else {
+            self.access
+        };
+        let exp = chrono::Utc::now() + chrono::Duration::seconds(lifetime);
+        match self.session_end(session_created_at) {
+            Some(session_end) if session_end < exp => session_end,
+            _ => exp,
+        }
+    }
+
+    pub fn is_session_expired(&self, session_created_at: chrono::DateTime<chrono::Utc>) -> bool {
+        match self.session_end(session_created_at) {
+            Some(session_end) => session_end <= chrono::Utc::now(),
+            None => false,
+        }
+    }
+}
+
+impl TokenClaims {
+    pub fn new_token_claims(
+        user_id: uuid::Uuid,
+        email: String,
+        refresh: bool,
+        exp: chrono::DateTime<chrono::Utc>,
+    ) -> TokenClaims {
+        let now = chrono::Utc::now();
         TokenClaims {
             sub: user_id,
             email,
             exp: exp.timestamp() as usize,
-            iat: chrono::Utc::now().timestamp() as usize,
-            iss: "Rust_api".to_string(),
+            iat: now.timestamp() as usize,
+            iat_ms: Some(now.timestamp_millis()),
+            iss: ISSUER.to_string(),
             refresh,
+            jti: uuid::Uuid::new_v4(),
+            sid: None,
         }
     }
-    pub fn access_token(&mut self) {
-        self.exp = (chrono::Utc::now() + chrono::Duration::hours(1)).timestamp() as usize;
-        self.iat = chrono::Utc::now().timestamp() as usize;
+    pub fn access_token(&mut self, lifetime: &TokenLifetime, session: &RefreshToken) {
+        self.exp = lifetime
+            .expiration(false, session.session_created_at)
+            .timestamp() as usize;
+        self.sid = Some(session.family_id);
+        let now = chrono::Utc::now();
+        self.iat = now.timestamp() as usize;
+        self.iat_ms = Some(now.timestamp_millis());
         self.refresh = false;
+        self.jti = uuid::Uuid::new_v4();
     }
-    fn new_header(refresh: bool) -> Header {
-        let kid_key = if refresh {
-            "refresh_token"
-        } else {
-            "access_token"
-        };
+    fn new_header(key: &TokenKey) -> Header {
         Header {
-            alg: Algorithm::HS512,
-            kid: Some(kid_key.to_string()),
+            alg: key.algorithm,
+            kid: Some(key.kid.clone()),
             ..Default::default()
         }
     }
-    fn get_key(refresh: bool) -> String {
-        if refresh {
-            match env::var("REFRESH_TOKEN_SIGN") {
-                Ok(val) => val,
-                Err(_) => "lambda_refresh_token_sign".to_string(),
-            }
-        } else {
-            match env::var("ACCESS_TOKEN_SIGN") {
-                Ok(val) => val,
-                Err(_) => "lambda_token_sign".to_string(),
-            }
-        }
-    }
-    pub fn new_tokens(user_id: uuid::Uuid, email: String, refresh: bool) -> Result<String, String> {
-        let mut claims = TokenClaims::new_token_claims(user_id, email, refresh);
-        claims.sign_token()
+    pub fn new_tokens(
+        user_id: uuid::Uuid,
+        email: String,
+        refresh: bool,
+        keys: &TokenKeys,
+        lifetime: &TokenLifetime,
+        session_created_at: chrono::DateTime<chrono::Utc>,
+    ) -> Result<String, String> {
+        let mut claims = TokenClaims::new_token_claims(
+            user_id,
+            email,
+            refresh,
+            lifetime.expiration(refresh, session_created_at),
+        );
+        claims.sign_token(keys)
     }
-    pub fn sign_token(&mut self) -> Result<String, String> {
-        let header = TokenClaims::new_header(self.refresh);
-        let key_string = TokenClaims::get_key(self.refresh);
-        let key = key_string.as_bytes();
-        match encode(&header, &self, &EncodingKey::from_secret(key)) {
+    pub fn sign_token(&mut self, keys: &TokenKeys) -> Result<String, String> {
+        let key = &keys.get(self.refresh).signing;
+        let header = TokenClaims::new_header(key);
+        match encode(&header, &self, &key.encoding_key) {
             Ok(token) => Ok(token),
             Err(_) => Err("Error while creating token".to_string()),
         }
     }
-    pub fn validate_token(token: String, refresh: bool) -> Result<TokenClaims, String> {
-        let key_string = TokenClaims::get_key(refresh);
-        let key = key_string.as_bytes();
+    pub fn validate_token(
+        token: String,
+        refresh: bool,
+        keys: &TokenKeys,
+    ) -> Result<TokenClaims, String> {
+        let header = match jsonwebtoken::decode_header(&token) {
+            Ok(header) => header,
+            Err(err) => return Err(format!("Error while validating token {}", err)),
+        };
+        let key = match header.kid.and_then(|kid| keys.get(refresh).find(&kid)) {
+            Some(key) => key,
+            None => return Err("Unknown or retired token key id".to_string()),
+        };
         match jsonwebtoken::decode::<TokenClaims>(
             &token,
-            &DecodingKey::from_secret(key),
-            &Validation::new(Algorithm::HS512),
+            &key.decoding_key,
+            &Validation::new(key.algorithm),
         ) {
             Ok(token_data) => {
                 if token_data.claims.refresh != refresh {
@@ -96,80 +527,448 @@ impl TokenClaims {
 pub struct RefreshToken {
     pub created_at: chrono::DateTime<chrono::Utc>,
     pub user_id: uuid::Uuid,
-    pub token: String,
+    pub token_hash: String,
+    pub token_prefix: String,
+    pub family_id: uuid::Uuid,
+    pub consumed_at: Option<chrono::DateTime<chrono::Utc>>,
+    pub session_created_at: chrono::DateTime<chrono::Utc>,
+    pub user_agent: Option<String>,
+    pub ip: Option<String>,
+    pub name: Option<String>,
+}
+
+// A session is a refresh token family, the id is the family id
+#[derive(ToSchema, Clone, Serialize, Deserialize)]
+pub struct Session {
+    pub id: uuid::Uuid,
+    pub name: Option<String>,
+    pub created_at: chrono::DateTime<chrono::Utc>,
+    pub last_used_at: chrono::DateTime<chrono::Utc>,
+    pub user_agent: Option<String>,
+    pub ip: Option<String>,
 }
 
 impl RefreshToken {
-    pub async fn create_table(pool: deadpool_postgres::Pool) -> Result<u64, tokio_postgres::Error> {
-        let client = pool.get().await.unwrap();
+    pub async fn create_table(
+        pool: deadpool_postgres::Pool,
+        keys: &TokenKeys,
+    ) -> Result<u64, tokio_postgres::Error> {
+        let mut client = pool.get().await.unwrap();
 
         let create_table = "
       CREATE TABLE IF NOT EXISTS refresh_tokens (
         created_at TIMESTAMPTZ NOT NULL DEFAULT NOW(),
         user_id UUID NOT NULL,
-        token VARCHAR NOT NULL,
-        PRIMARY KEY (user_id, token)
+        token_hash VARCHAR NOT NULL PRIMARY KEY,
+        token_prefix VARCHAR NOT NULL,
+        family_id UUID NOT NULL DEFAULT uuid_generate_v4(),
+        consumed_at TIMESTAMPTZ,
+        session_created_at TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+        user_agent VARCHAR,
+        ip VARCHAR,
+        name VARCHAR
       );";
-        client.execute(create_table, &[]).await
+        client.execute(create_table, &[]).await?;
+
+        // Migrate table created before the refresh token rotation
+        let add_family = "
+      ALTER TABLE refresh_tokens
+        ADD COLUMN IF NOT EXISTS family_id UUID NOT NULL DEFAULT uuid_generate_v4(),
+        ADD COLUMN IF NOT EXISTS consumed_at TIMESTAMPTZ;";
+        client.execute(add_family, &[]).await?;
+
+        // Migrate table created before the session management
+        let add_session = "
+      ALTER TABLE refresh_tokens
+        ADD COLUMN IF NOT EXISTS session_created_at TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+        ADD COLUMN IF NOT EXISTS user_agent VARCHAR,
+        ADD COLUMN IF NOT EXISTS ip VARCHAR,
+        ADD COLUMN IF NOT EXISTS name VARCHAR;";
+        client.execute(add_session, &[]).await?;
+
+        // Migrate table storing the refresh token in clear text, in a single
+        // transaction so a failure leaves the table untouched
+        let transaction = client.transaction().await?;
+        transaction
+            .batch_execute("LOCK TABLE refresh_tokens IN ACCESS EXCLUSIVE MODE")
+            .await?;
+        let has_clear_token = "
+      SELECT 1
+      FROM information_schema.columns
+      WHERE table_name = 'refresh_tokens' AND column_name = 'token'";
+        if transaction.query_opt(has_clear_token, &[]).await?.is_none() {
+            return Ok(0);
+        }
+        let add_hash = "
+      ALTER TABLE refresh_tokens
+        ADD COLUMN IF NOT EXISTS token_hash VARCHAR,
+        ADD COLUMN IF NOT EXISTS token_prefix VARCHAR;";
+        transaction.execute(add_hash, &[]).await?;
+        let clear_tokens = transaction
+            .query(
+                "SELECT token FROM refresh_tokens WHERE token_hash IS NULL",
+                &[],
+            )
+            .await?;
+        let mut migrated = 0;
+        for row in clear_tokens {
+            let token: String = row.get(0);
+            let token_hash = match keys.hash_refresh_token(&token) {
+                Ok(hash) => hash,
+                Err(err) => {
+                    println!("Error while hashing refresh token: {}", err);
+                    continue;
+                }
+            };
+            migrated += transaction
+                .execute(
+                    "UPDATE refresh_tokens SET token_hash = $1, token_prefix = $2 WHERE token = $3",
+                    &[&token_hash, &RefreshToken::prefix(&token), &token],
+                )
+                .await?;
+        }
+        let drop_clear_token = "
+      DELETE FROM refresh_tokens WHERE token_hash IS NULL;
+      ALTER TABLE refresh_tokens DROP CONSTRAINT IF EXISTS refresh_tokens_pkey;
+      ALTER TABLE refresh_tokens DROP COLUMN token;
+      ALTER TABLE refresh_tokens ALTER COLUMN token_hash SET NOT NULL;
+      ALTER TABLE refresh_tokens ALTER COLUMN token_prefix SET NOT NULL;
+      ALTER TABLE refresh_tokens ADD PRIMARY KEY (token_hash);";
+        transaction.batch_execute(drop_clear_token).await?;
+        transaction.commit().await?;
+        println!("{} refresh tokens hashed", migrated);
+        Ok(migrated)
+    }
+
+    // Start of the signature, the start of a jwt is always the same header
+    fn prefix(token: &str) -> String {
+        token
+            .rsplit('.')
+            .next()
+            .unwrap_or_default()
+            .chars()
+            .take(8)
+            .collect()
+    }
+
+    // Start a new session
+    pub fn new(
+        user_id: uuid::Uuid,
+        token: &str,
+        keys: &TokenKeys,
+        user_agent: Option<String>,
+        ip: Option<String>,
+    ) -> Result<RefreshToken, String> {
+        let now = chrono::Utc::now();
+        Ok(RefreshToken {
+            created_at: now,
+            user_id,
+            token_hash: keys.hash_refresh_token(token)?,
+            token_prefix: RefreshToken::prefix(token),
+            family_id: uuid::Uuid::new_v4(),
+            consumed_at: None,
+            session_created_at: now,
+            user_agent,
+            ip,
+            name: None,
+        })
+    }
+
+    // Next token of the same session
+    pub fn next(
+        &self,
+        token: &str,
+        keys: &TokenKeys,
+        user_agent: Option<String>,
+        ip: Option<String>,
+    ) -> Result<RefreshToken, String> {
+        Ok(RefreshToken {
+            created_at: chrono::Utc::now(),
+            user_id: self.user_id,
+            token_hash: keys.hash_refresh_token(token)?,
+            token_prefix: RefreshToken::prefix(token),
+            family_id: self.family_id,
+            consumed_at: None,
+            session_created_at: self.session_created_at,
+            user_agent,
+            ip,
+            name: self.name.clone(),
+        })
     }
 
     pub async fn create(self, pool: deadpool_postgres::Pool) -> Result<u64, tokio_postgres::Error> {
         let client = pool.get().await.unwrap();
 
         let create = "
-        INSERT INTO refresh_tokens (created_at, user_id, token)
-        VALUES ($1, $2, $3)";
+        INSERT INTO refresh_tokens (created_at, user_id, token_hash, token_prefix, family_id, consumed_at, session_created_at, user_agent, ip, name)
+        VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10)";
         client
-            .execute(create, &[&self.created_at, &self.user_id, &self.token])
+            .execute(
+                create,
+                &[
+                    &self.created_at,
+                    &self.user_id,
+                    &self.token_hash,
+                    &self.token_prefix,
+                    &self.family_id,
+                    &self.consumed_at,
+                    &self.session_created_at,
+                    &self.user_agent,
+                    &self.ip,
+                    &self.name,
+                ],
+            )
             .await
     }
 
     pub async fn get_one_by_token(
         pool: deadpool_postgres::Pool,
-        token: String,
+        token_hash: String,
     ) -> Result<RefreshToken, tokio_postgres::Error> {
         let client = pool.get().await.unwrap();
 
         let get_one = "
-        SELECT created_at, user_id, token
+        SELECT created_at, user_id, token_hash, token_prefix, family_id, consumed_at, session_created_at, user_agent, ip, name
         FROM refresh_tokens
-        WHERE token = $1";
-        let row = client.query_one(get_one, &[&token]).await?;
+        WHERE token_hash = $1";
+        let row = client.query_one(get_one, &[&token_hash]).await?;
         Ok(RefreshToken {
             created_at: row.get(0),
             user_id: row.get(1),
-            token: row.get(2),
+            token_hash: row.get(2),
+            token_prefix: row.get(3),
+            family_id: row.get(4),
+            consumed_at: row.get(5),
+            session_created_at: row.get(6),
+            user_agent: row.get(7),
+            ip: row.get(8),
+            name: row.get(9),
         })
     }
 
-    pub async fn keep_only_four_token(
+    // Active sessions of the user, the last use is the last refresh
+    pub async fn get_sessions(
+        pool: deadpool_postgres::Pool,
+        user_id: uuid::Uuid,
+    ) -> Result<Vec<Session>, tokio_postgres::Error> {
+        let client = pool.get().await.unwrap();
+
+        let get_sessions = "
+        SELECT family_id, name, session_created_at, created_at, user_agent, ip
+        FROM refresh_tokens
+        WHERE user_id = $1 AND consumed_at IS NULL
+        ORDER BY created_at DESC";
+        let rows = client.query(get_sessions, &[&user_id]).await?;
+        Ok(rows
+            .iter()
+            .map(|row| Session {
+                id: row.get(0),
+                name: row.get(1),
+                created_at: row.get(2),
+                last_used_at: row.get(3),
+                user_agent: row.get(4),
+                ip: row.get(5),
+            })
+            .collect())
+    }
+
+    pub async fn rename_session(
+        pool: deadpool_postgres::Pool,
+        user_id: uuid::Uuid,
+        family_id: uuid::Uuid,
+        name: Option<String>,
+    ) -> Result<u64, tokio_postgres::Error> {
+        let client = pool.get().await.unwrap();
+
+        let rename = "
+        UPDATE refresh_tokens
+        SET name = $1
+        WHERE user_id = $2 AND family_id = $3";
+        client.execute(rename, &[&name, &user_id, &family_id]).await
+    }
+
+    pub async fn delete_session(
+        pool: deadpool_postgres::Pool,
+        user_id: uuid::Uuid,
+        family_id: uuid::Uuid,
+    ) -> Result<u64, tokio_postgres::Error> {
+        let client = pool.get().await.unwrap();
+
+        let delete = "
+        DELETE FROM refresh_tokens
+        WHERE user_id = $1 AND family_id = $2";
+        client.execute(delete, &[&user_id, &family_id]).await
+    }
+
+    pub async fn delete_all_by_user(
         pool: deadpool_postgres::Pool,
         user_id: uuid::Uuid,
     ) -> Result<u64, tokio_postgres::Error> {
         let client = pool.get().await.unwrap();
 
+        let delete = "
+        DELETE FROM refresh_tokens
+        WHERE user_id = $1";
+        client.execute(delete, &[&user_id]).await
+    }
+
+    // Mark the token as used, return 0 if it was already consumed
+    pub async fn consume(
+        self,
+        pool: deadpool_postgres::Pool,
+    ) -> Result<u64, tokio_postgres::Error> {
+        let client = pool.get().await.unwrap();
+
+        let consume = "
+        UPDATE refresh_tokens
+        SET consumed_at = NOW()
+        WHERE token_hash = $1 AND consumed_at IS NULL";
+        client.execute(consume, &[&self.token_hash]).await
+    }
+
+    // Called before a session is created, only the most recent sessions are kept to make
+    // room for it. Consumed token are kept for a refresh token lifetime in order to detect
+    // their reuse
+    pub async fn keep_only_last_sessions(
+        pool: deadpool_postgres::Pool,
+        user_id: uuid::Uuid,
+        lifetime: &TokenLifetime,
+    ) -> Result<u64, tokio_postgres::Error> {
+        let client = pool.get().await.unwrap();
+
         let delete = "
         DELETE FROM refresh_tokens
         WHERE user_id = $1
-        AND created_at < (
-            SELECT created_at
+        AND family_id IN (
+            SELECT family_id
             FROM refresh_tokens
-            WHERE user_id = $1
+            WHERE user_id = $1 AND consumed_at IS NULL
             ORDER BY created_at DESC
-            LIMIT 1 OFFSET 3
+            OFFSET $2::BIGINT - 1
         )";
-        client.execute(delete, &[&user_id]).await
+        client
+            .execute(delete, &[&user_id, &lifetime.max_sessions])
+            .await?;
+
+        let delete_expired = "
+        DELETE FROM refresh_tokens
+        WHERE user_id = $1
+        AND created_at < NOW() - $2::BIGINT * INTERVAL '1 second'";
+        client
+            .execute(delete_expired, &[&user_id, &lifetime.session_idle])
+            .await
     }
 
-    pub async fn delete_token(
+    pub async fn delete_family(
         pool: deadpool_postgres::Pool,
-        token: String,
+        family_id: uuid::Uuid,
     ) -> Result<u64, tokio_postgres::Error> {
         let client = pool.get().await.unwrap();
 
         let delete = "
         DELETE FROM refresh_tokens
-        WHERE token = $1";
-        client.execute(delete, &[&token]).await
+        WHERE family_id = $1";
+        client.execute(delete, &[&family_id]).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{TokenClaims, TokenKey, TokenKeyRing, TokenKeys};
+
+    fn key(kid: &str, retire_in: Option<i64>) -> TokenKey {
+        let mut key = TokenKey::from_secret(kid.to_string(), format!("{}_secret", kid));
+        key.retire_at =
+            retire_in.map(|seconds| chrono::Utc::now() + chrono::Duration::seconds(seconds));
+        key
+    }
+
+    fn keys(access: TokenKeyRing) -> TokenKeys {
+        TokenKeys {
+            access,
+            refresh: TokenKeyRing {
+                signing: key("refresh", None),
+                previous: vec![],
+            },
+            refresh_hash_key: b"hash".to_vec(),
+        }
+    }
+
+    fn access_token(keys: &TokenKeys) -> String {
+        TokenClaims::new_token_claims(
+            uuid::Uuid::new_v4(),
+            "test@test.fr".to_string(),
+            false,
+            chrono::Utc::now() + chrono::Duration::minutes(5),
+        )
+        .sign_token(keys)
+        .unwrap()
+    }
+
+    #[test]
+    fn find_by_kid() {
+        let ring = TokenKeyRing {
+            signing: key("new", None),
+            previous: vec![key("old", Some(3600)), key("older", None)],
+        };
+        assert_eq!(ring.find("new").map(|key| key.kid.as_str()), Some("new"));
+        assert_eq!(ring.find("old").map(|key| key.kid.as_str()), Some("old"));
+        assert_eq!(
+            ring.find("older").map(|key| key.kid.as_str()),
+            Some("older")
+        );
+        assert!(ring.find("unknown").is_none());
+    }
+
+    #[test]
+    fn retired_key_is_ignored() {
+        let ring = TokenKeyRing {
+            signing: key("new", None),
+            previous: vec![key("retired", Some(-1)), key("old", Some(3600))],
+        };
+        assert!(ring.find("retired").is_none());
+        let active: Vec<&str> = ring
+            .active_keys()
+            .iter()
+            .map(|key| key.kid.as_str())
+            .collect();
+        assert_eq!(active, vec!["new", "old"]);
+    }
+
+    #[test]
+    fn token_of_a_previous_key_until_retirement() {
+        let token = access_token(&keys(TokenKeyRing {
+            signing: key("old", None),
+            previous: vec![],
+        }));
+        let rotated = keys(TokenKeyRing {
+            signing: key("new", None),
+            previous: vec![key("old", Some(3600))],
+        });
+        assert!(TokenClaims::validate_token(token.clone(), false, &rotated).is_ok());
+        assert!(rotated.is_buildin_token(&token));
+
+        let retired = keys(TokenKeyRing {
+            signing: key("new", None),
+            previous: vec![key("old", Some(-1))],
+        });
+        assert!(TokenClaims::validate_token(token.clone(), false, &retired).is_err());
+        // Still a buildin token, it's rejected instead of being sent to the oidc provider
+        assert!(retired.is_buildin_token(&token));
+    }
+
+    #[test]
+    fn token_is_checked_with_the_key_of_its_kid() {
+        let token = access_token(&keys(TokenKeyRing {
+            signing: key("new", None),
+            previous: vec![],
+        }));
+        // Same kid but another secret
+        let other = keys(TokenKeyRing {
+            signing: TokenKey::from_secret("new".to_string(), "other".to_string()),
+            previous: vec![],
+        });
+        assert!(TokenClaims::validate_token(token.clone(), false, &other).is_err());
+        assert!(TokenClaims::validate_token(token, true, &other).is_err());
     }
 }
@@ -0,0 +1,181 @@
+use std::env;
+
+use openssl::{hash::MessageDigest, memcmp, pkey::PKey, sign::Signer};
+use serde::Serialize;
+use utoipa::ToSchema;
+use uuid::Uuid;
+
+// Lifetime in days of a trusted device (TRUSTED_DEVICE_LIFETIME) and the key
+// signing the device token (TRUSTED_DEVICE_SIGN)
+#[derive(Clone)]
+pub struct TrustedDeviceConfig {
+    pub lifetime: i64,
+    sign_key: Vec<u8>,
+}
+
+impl TrustedDeviceConfig {
+    pub fn new() -> Result<TrustedDeviceConfig, String> {
+        let lifetime = match env::var("TRUSTED_DEVICE_LIFETIME") {
+            Ok(value) => match value.parse::<i64>() {
+                Ok(value) if value > 0 => value,
+                _ => return Err("TRUSTED_DEVICE_LIFETIME must be a positive number".to_string()),
+            },
+            Err(_) => 30,
+        };
+        Ok(TrustedDeviceConfig {
+            lifetime,
+            sign_key: env::var("TRUSTED_DEVICE_SIGN")
+                .unwrap_or_else(|_| "lambda_trusted_device_sign".to_string())
+                .into_bytes(),
+        })
+    }
+
+    fn sign(&self, user_id: Uuid, device_id: Uuid) -> Result<String, String> {
+        let key = PKey::hmac(&self.sign_key).map_err(|err| err.to_string())?;
+        let mut signer =
+            Signer::new(MessageDigest::sha256(), &key).map_err(|err| err.to_string())?;
+        signer
+            .update(format!("{}:{}", user_id, device_id).as_bytes())
+            .map_err(|err| err.to_string())?;
+        let signature = signer.sign_to_vec().map_err(|err| err.to_string())?;
+        Ok(signature
+            .iter()
+            .map(|byte| format!("{:02x}", byte))
+            .collect())
+    }
+
+    // The device token is the id of the device followed by its signature
+    pub fn device_token(&self, user_id: Uuid, device_id: Uuid) -> Result<String, String> {
+        Ok(format!("{}.{}", device_id, self.sign(user_id, device_id)?))
+    }
+
+    // Return the id of the device if the token has been signed for this user
+    pub fn verify_device_token(&self, user_id: Uuid, token: &str) -> Option<Uuid> {
+        let (device_id, signature) = token.split_once('.')?;
+        let device_id = Uuid::parse_str(device_id).ok()?;
+        let expected = self.sign(user_id, device_id).ok()?;
+        if expected.len() == signature.len()
+            && memcmp::eq(expected.as_bytes(), signature.as_bytes())
+        {
+            Some(device_id)
+        } else {
+            None
+        }
+    }
+}
+
+// A browser allowed to skip the otp step until expires_at, revoking it only
+// require to delete the row
+#[derive(ToSchema, Clone, Serialize)]
+pub struct TrustedDevice {
+    pub id: Uuid,
+    pub user_agent: Option<String>,
+    pub ip: Option<String>,
+    pub created_at: chrono::DateTime<chrono::Utc>,
+    pub expires_at: chrono::DateTime<chrono::Utc>,
+    pub last_used_at: Option<chrono::DateTime<chrono::Utc>>,
+}
+
+impl TrustedDevice {
+    pub async fn create_table(pool: deadpool_postgres::Pool) -> Result<u64, tokio_postgres::Error> {
+        let client = pool.get().await.unwrap();
+
+        let create_table = "
+      CREATE TABLE IF NOT EXISTS trusted_devices (
+        id UUID PRIMARY KEY DEFAULT uuid_generate_v4(),
+        user_id UUID NOT NULL,
+        user_agent VARCHAR,
+        ip VARCHAR,
+        created_at TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+        expires_at TIMESTAMPTZ NOT NULL,
+        last_used_at TIMESTAMPTZ
+      );";
+        client.execute(create_table, &[]).await
+    }
+
+    pub async fn create(
+        pool: deadpool_postgres::Pool,
+        user_id: Uuid,
+        lifetime: i64,
+        user_agent: Option<String>,
+        ip: Option<String>,
+    ) -> Result<Uuid, tokio_postgres::Error> {
+        let client = pool.get().await.unwrap();
+
+        let delete_expired = "
+        DELETE FROM trusted_devices
+        WHERE user_id = $1 AND expires_at < NOW()";
+        client.execute(delete_expired, &[&user_id]).await?;
+        let create = "
+        INSERT INTO trusted_devices (user_id, user_agent, ip, expires_at)
+        VALUES ($1, $2, $3, NOW() + $4::BIGINT * INTERVAL '1 day')
+        RETURNING id";
+        let row = client
+            .query_one(create, &[&user_id, &user_agent, &ip, &lifetime])
+            .await?;
+        Ok(row.get(0))
+    }
+
+    // Mark the device as used, return false if it has been revoked or is expired
+    pub async fn use_device(
+        pool: deadpool_postgres::Pool,
+        user_id: Uuid,
+        id: Uuid,
+    ) -> Result<bool, tokio_postgres::Error> {
+        let client = pool.get().await.unwrap();
+
+        let use_device = "
+        UPDATE trusted_devices
+        SET last_used_at = NOW()
+        WHERE id = $1 AND user_id = $2 AND expires_at > NOW()";
+        Ok(client.execute(use_device, &[&id, &user_id]).await? == 1)
+    }
+
+    pub async fn get_all_by_user(
+        pool: deadpool_postgres::Pool,
+        user_id: Uuid,
+    ) -> Result<Vec<TrustedDevice>, tokio_postgres::Error> {
+        let client = pool.get().await.unwrap();
+
+        let get_all = "
+        SELECT id, user_agent, ip, created_at, expires_at, last_used_at
+        FROM trusted_devices
+        WHERE user_id = $1 AND expires_at > NOW()
+        ORDER BY created_at DESC";
+        let rows = client.query(get_all, &[&user_id]).await?;
+        Ok(rows
+            .iter()
+            .map(|row| TrustedDevice {
+                id: row.get(0),
+                user_agent: row.get(1),
+                ip: row.get(2),
+                created_at: row.get(3),
+                expires_at: row.get(4),
+                last_used_at: row.get(5),
+            })
+            .collect())
+    }
+
+    pub async fn delete(
+        pool: deadpool_postgres::Pool,
+        user_id: Uuid,
+        id: Uuid,
+    ) -> Result<u64, tokio_postgres::Error> {
+        let client = pool.get().await.unwrap();
+
+        let delete = "
+        DELETE FROM trusted_devices
+        WHERE id = $1 AND user_id = $2";
+        client.execute(delete, &[&id, &user_id]).await
+    }
+
+    pub async fn delete_all_by_user(
+        pool: deadpool_postgres::Pool,
+        user_id: Uuid,
+    ) -> Result<u64, tokio_postgres::Error> {
+        let client = pool.get().await.unwrap();
+
+        let delete = "DELETE FROM trusted_devices WHERE user_id = $1";
+        client.execute(delete, &[&user_id]).await
+    }
+}
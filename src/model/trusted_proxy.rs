@@ -0,0 +1,91 @@
+use std::{env, net::IpAddr};
+
+use actix_web::http::header::HeaderMap;
+
+// Proxies allowed to set the X-Forwarded-For header (TRUSTED_PROXIES, a comma separated
+// list of ip or cidr). Without it the ip of the client is the peer address of the
+// connection, the header can be forged by anyone.
+#[derive(Clone)]
+pub struct TrustedProxies {
+    networks: Vec<(IpAddr, u8)>,
+}
+
+impl TrustedProxies {
+    pub fn new() -> Result<TrustedProxies, String> {
+        let networks = env::var("TRUSTED_PROXIES")
+            .unwrap_or_default()
+            .split(',')
+            .map(|network| network.trim())
+            .filter(|network| !network.is_empty())
+            .map(TrustedProxies::parse_network)
+            .collect::<Result<Vec<_>, String>>()?;
+        Ok(TrustedProxies { networks })
+    }
+
+    fn parse_network(network: &str) -> Result<(IpAddr, u8), String> {
+        let invalid = || {
+            format!(
+                "TRUSTED_PROXIES contains an invalid ip or cidr: {}",
+                network
+            )
+        };
+        let (ip, prefix) = match network.split_once('/') {
+            Some((ip, prefix)) => (ip, Some(prefix)),
+            None => (network, None),
+        };
+        let ip = ip.parse::<IpAddr>().map_err(|_| invalid())?;
+        let max_prefix = if ip.is_ipv4() { 32 } else { 128 };
+        let prefix = match prefix {
+            Some(prefix) => match prefix.parse::<u8>() {
+                Ok(prefix) if prefix <= max_prefix => prefix,
+                _ => return Err(invalid()),
+            },
+            None => max_prefix,
+        };
+        Ok((ip, prefix))
+    }
+
+    pub fn is_trusted(&self, ip: &IpAddr) -> bool {
+        self.networks
+            .iter()
+            .any(|(network, prefix)| match (network, ip) {
+                (IpAddr::V4(network), IpAddr::V4(ip)) => {
+                    let mask = u32::MAX.checked_shl(32 - *prefix as u32).unwrap_or(0);
+                    u32::from(*network) & mask == u32::from(*ip) & mask
+                }
+                (IpAddr::V6(network), IpAddr::V6(ip)) => {
+                    let mask = u128::MAX.checked_shl(128 - *prefix as u32).unwrap_or(0);
+                    u128::from(*network) & mask == u128::from(*ip) & mask
+                }
+                _ => false,
+            })
+    }
+
+    // The X-Forwarded-For header is read from the right, each trusted proxy append the
+    // address it received the request from, the first untrusted one is the client
+    pub fn client_ip(&self, peer: IpAddr, headers: &HeaderMap) -> IpAddr {
+        if !self.is_trusted(&peer) {
+            return peer;
+        }
+        let mut client = peer;
+        let headers: Vec<_> = headers.get_all("x-forwarded-for").collect();
+        for forwarded_for in headers.into_iter().rev() {
+            let forwarded_for = match forwarded_for.to_str() {
+                Ok(forwarded_for) => forwarded_for,
+                Err(_) => return client,
+            };
+            for hop in forwarded_for.rsplit(',') {
+                match hop.trim().parse::<IpAddr>() {
+                    Ok(ip) => {
+                        client = ip;
+                        if !self.is_trusted(&ip) {
+                            return client;
+                        }
+                    }
+                    Err(_) => return client,
+                }
+            }
+        }
+        client
+    }
+}
@@ -1,10 +1,15 @@
-use std::{env::var, time::SystemTimeError};
+use std::{
+    env::var,
+    time::{SystemTime, UNIX_EPOCH},
+};
 
 use super::super::route::auth::info::AuthType;
 use super::oidc::Oidc;
-use actix_web::{error::ErrorUnauthorized, web, FromRequest, HttpResponse};
-use bcrypt::{hash, verify, DEFAULT_COST};
+use actix_web::{
+    error::ErrorUnauthorized, http::header::AUTHORIZATION, web, FromRequest, HttpResponse,
+};
 use deadpool_postgres::Pool;
+use openssl::memcmp;
 use serde::{Deserialize, Serialize};
 use tokio_postgres::Error;
 use totp_rs::TotpUrlError;
@@ -14,7 +19,15 @@ use uuid::Uuid;
 
 use crate::{
     helper::{self, header},
-    model::token::TokenClaims,
+    model::{
+        login_attempt::LoginAttempt,
+        otp::{OtpConfig, OtpKeys},
+        password_hash::PasswordHashConfig,
+        recovery_code::RecoveryCode,
+        revocation::TokenDenylist,
+        token::{TokenClaims, TokenKeys},
+        trusted_device::TrustedDevice,
+    },
 };
 
 #[derive(ToSchema, Clone, Serialize, Deserialize)]
@@ -22,6 +35,11 @@ pub struct PublicUser {
     pub id: Uuid,
     pub nom: String,
     pub prenom: String,
+    // Only returned to authenticated users
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub email: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub created_at: Option<chrono::DateTime<chrono::Utc>>,
 }
 
 #[derive(ToSchema, Clone, Serialize, Deserialize)]
@@ -30,6 +48,17 @@ pub struct UserUpdate {
     pub prenom: Option<String>,
 }
 
+#[derive(ToSchema, Clone, Serialize, Deserialize)]
+pub struct UserPasswordUpdate {
+    pub current_password: String,
+    pub new_password: String,
+    // End every session except the one of the current token
+    pub revoke_other_sessions: Option<bool>,
+}
+
+// Columns read by User::from_row, in the order of the fields
+const USER_COLUMNS: &str = "id, email, password, nom, prenom, otp_secret, otp_url, otp_enabled, one_time_token, is_oauth, created_at, updated_at, access_token_lifetime, session_idle_lifetime, session_absolute_lifetime, max_sessions, is_admin, otp_algorithm, otp_digits, otp_period, otp_last_step, one_time_token_expires_at, one_time_token_attempts, otp_key_id, email_verified";
+
 #[derive(ToSchema, Clone, Serialize, Deserialize)]
 pub struct User {
     pub id: Uuid,
@@ -47,7 +76,36 @@ pub struct User {
     pub updated_at: chrono::DateTime<chrono::Utc>,
     #[serde(skip)]
     pub one_time_token: Option<String>,
+    #[serde(skip)]
+    pub one_time_token_expires_at: Option<chrono::DateTime<chrono::Utc>>,
+    // Number of invalid code sent with the one time token
+    #[serde(skip)]
+    pub one_time_token_attempts: i32,
     pub is_oauth: bool,
+    // Override of the token lifetimes (in seconds) and session limit, used for service users
+    #[serde(skip)]
+    pub access_token_lifetime: Option<i32>,
+    #[serde(skip)]
+    pub session_idle_lifetime: Option<i32>,
+    #[serde(skip)]
+    pub session_absolute_lifetime: Option<i32>,
+    #[serde(skip)]
+    pub max_sessions: Option<i32>,
+    pub is_admin: bool,
+    // Parameters of the otp secret, null for the secret created with the original SHA1, 6 digits and 30s
+    #[serde(skip)]
+    pub otp_algorithm: Option<String>,
+    #[serde(skip)]
+    pub otp_digits: Option<i32>,
+    #[serde(skip)]
+    pub otp_period: Option<i32>,
+    // Last time step accepted, a code can only be used once
+    #[serde(skip)]
+    pub otp_last_step: Option<i64>,
+    // Key used to encrypt the otp secret and url, null if saved in clear text
+    #[serde(skip)]
+    pub otp_key_id: Option<String>,
+    pub email_verified: bool,
 }
 
 impl User {
@@ -61,28 +119,77 @@ impl User {
                 password VARCHAR(255) NOT NULL,
                 nom VARCHAR(255) NOT NULL,
                 prenom VARCHAR(255) NOT NULL,
-                otp_secret VARCHAR(255),
-                otp_url VARCHAR(255),
+                otp_secret TEXT,
+                otp_url TEXT,
                 otp_enabled BOOLEAN DEFAULT FALSE,
                 one_time_token VARCHAR(255),
                 is_oauth BOOLEAN DEFAULT FALSE,
                 created_at TIMESTAMPTZ NOT NULL DEFAULT NOW(),
-                updated_at TIMESTAMPTZ NOT NULL DEFAULT NOW()
+                updated_at TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+                access_token_lifetime INTEGER,
+                session_idle_lifetime INTEGER,
+                session_absolute_lifetime INTEGER,
+                max_sessions INTEGER,
+                is_admin BOOLEAN NOT NULL DEFAULT FALSE,
+                otp_algorithm VARCHAR(10),
+                otp_digits INTEGER,
+                otp_period INTEGER,
+                otp_last_step BIGINT,
+                one_time_token_expires_at TIMESTAMPTZ,
+                one_time_token_attempts INTEGER NOT NULL DEFAULT 0,
+                otp_key_id VARCHAR(255),
+                email_verified BOOLEAN NOT NULL DEFAULT FALSE
             );";
-        client.execute(create_table, &[]).await
-    }
-    pub async fn get_one(
-        pool: deadpool_postgres::Pool,
-        id: Uuid,
-    ) -> Result<User, tokio_postgres::Error> {
-        let client = pool.get().await.unwrap();
+        client.execute(create_table, &[]).await?;
 
-        let get_one = "
-            SELECT id, email, password, nom, prenom, otp_secret, otp_url, otp_enabled, one_time_token, is_oauth, created_at, updated_at
-            FROM users
-            WHERE id = $1";
-        let row = client.query_one(get_one, &[&id]).await?;
-        Ok(User {
+        // Migrate table created before the per user session override
+        let add_session_override = "
+            ALTER TABLE users
+                ADD COLUMN IF NOT EXISTS access_token_lifetime INTEGER,
+                ADD COLUMN IF NOT EXISTS session_idle_lifetime INTEGER,
+                ADD COLUMN IF NOT EXISTS session_absolute_lifetime INTEGER,
+                ADD COLUMN IF NOT EXISTS max_sessions INTEGER;";
+        client.execute(add_session_override, &[]).await?;
+
+        // Migrate table created before the admin role
+        let add_admin = "
+            ALTER TABLE users
+                ADD COLUMN IF NOT EXISTS is_admin BOOLEAN NOT NULL DEFAULT FALSE;";
+        client.execute(add_admin, &[]).await?;
+
+        // Migrate table created before the otp parameters and replay protection
+        let add_otp_parameters = "
+            ALTER TABLE users
+                ADD COLUMN IF NOT EXISTS otp_algorithm VARCHAR(10),
+                ADD COLUMN IF NOT EXISTS otp_digits INTEGER,
+                ADD COLUMN IF NOT EXISTS otp_period INTEGER,
+                ADD COLUMN IF NOT EXISTS otp_last_step BIGINT;";
+        client.execute(add_otp_parameters, &[]).await?;
+
+        // Migrate table created before the one time token expiration
+        let add_one_time_token_expiration = "
+            ALTER TABLE users
+                ADD COLUMN IF NOT EXISTS one_time_token_expires_at TIMESTAMPTZ,
+                ADD COLUMN IF NOT EXISTS one_time_token_attempts INTEGER NOT NULL DEFAULT 0;";
+        client.execute(add_one_time_token_expiration, &[]).await?;
+
+        // Migrate table created before the otp secret encryption
+        let add_otp_encryption = "
+            ALTER TABLE users
+                ALTER COLUMN otp_secret TYPE TEXT,
+                ALTER COLUMN otp_url TYPE TEXT,
+                ADD COLUMN IF NOT EXISTS otp_key_id VARCHAR(255);";
+        client.execute(add_otp_encryption, &[]).await?;
+
+        // Migrate table created before the email verification, the existing users are
+        // considered verified and the new ones are inserted with an explicit value
+        let add_email_verified = "
+            ALTER TABLE users
+                ADD COLUMN IF NOT EXISTS email_verified BOOLEAN NOT NULL DEFAULT TRUE;";
+        client.execute(add_email_verified, &[]).await
+    }
+    fn from_row(row: &tokio_postgres::Row) -> User {
+        User {
             id: row.get(0),
             email: row.get(1),
             password: row.get(2),
@@ -95,7 +202,37 @@ impl User {
             is_oauth: row.get(9),
             created_at: row.get(10),
             updated_at: row.get(11),
-        })
+            access_token_lifetime: row.get(12),
+            session_idle_lifetime: row.get(13),
+            session_absolute_lifetime: row.get(14),
+            max_sessions: row.get(15),
+            is_admin: row.get(16),
+            otp_algorithm: row.get(17),
+            otp_digits: row.get(18),
+            otp_period: row.get(19),
+            otp_last_step: row.get(20),
+            one_time_token_expires_at: row.get(21),
+            one_time_token_attempts: row.get(22),
+            otp_key_id: row.get(23),
+            email_verified: row.get(24),
+        }
+    }
+
+    pub async fn get_one(
+        pool: deadpool_postgres::Pool,
+        id: Uuid,
+    ) -> Result<User, tokio_postgres::Error> {
+        let client = pool.get().await.unwrap();
+
+        let get_one = format!(
+            "
+            SELECT {}
+            FROM users
+            WHERE id = $1",
+            USER_COLUMNS
+        );
+        let row = client.query_one(&get_one, &[&id]).await?;
+        Ok(User::from_row(&row))
     }
 
     // check if user exists
@@ -119,28 +256,15 @@ impl User {
     ) -> Result<Option<User>, tokio_postgres::Error> {
         let client = pool.get().await.unwrap();
 
-        let get_one = "
-            SELECT id, email, password, nom, prenom, otp_secret, otp_url, otp_enabled,one_time_token, is_oauth, created_at, updated_at
+        let get_one = format!(
+            "
+            SELECT {}
             FROM users
-            WHERE email = $1";
-        let row = client.query_opt(get_one, &[&email]).await?;
-        match row {
-            Some(row_content) => Ok(Some(User {
-                id: row_content.get(0),
-                email: row_content.get(1),
-                password: row_content.get(2),
-                nom: row_content.get(3),
-                prenom: row_content.get(4),
-                otp_secret: row_content.get(5),
-                otp_url: row_content.get(6),
-                otp_enabled: row_content.get(7),
-                one_time_token: row_content.get(8),
-                is_oauth: row_content.get(9),
-                created_at: row_content.get(10),
-                updated_at: row_content.get(11),
-            })),
-            None => Ok(None),
-        }
+            WHERE email = $1",
+            USER_COLUMNS
+        );
+        let row = client.query_opt(&get_one, &[&email]).await?;
+        Ok(row.as_ref().map(User::from_row))
     }
 
     pub async fn get_one_by_one_time_token(
@@ -149,38 +273,28 @@ impl User {
     ) -> Result<User, tokio_postgres::Error> {
         let client = pool.get().await.unwrap();
 
-        let get_one = "
-            SELECT id, email, password, nom, prenom, otp_secret, otp_url, otp_enabled,one_time_token, is_oauth, created_at, updated_at
+        let get_one = format!(
+            "
+            SELECT {}
             FROM users
-            WHERE one_time_token = $1";
-        let row = client.query_one(get_one, &[&token]).await?;
-
-        Ok(User {
-            id: row.get(0),
-            email: row.get(1),
-            password: row.get(2),
-            nom: row.get(3),
-            prenom: row.get(4),
-            otp_secret: row.get(5),
-            otp_url: row.get(6),
-            otp_enabled: row.get(7),
-            one_time_token: row.get(8),
-            is_oauth: row.get(9),
-            created_at: row.get(10),
-            updated_at: row.get(11),
-        })
+            WHERE one_time_token = $1 AND one_time_token_expires_at > NOW()",
+            USER_COLUMNS
+        );
+        let row = client.query_one(&get_one, &[&token]).await?;
+        Ok(User::from_row(&row))
     }
 
     pub async fn create(self, pool: deadpool_postgres::Pool) -> Result<u64, Error> {
         let client = pool.get().await.unwrap();
 
         let create = "
-            INSERT INTO users (email, password, nom, prenom, otp_secret, otp_url, otp_enabled, is_oauth, created_at, updated_at)
-            VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10)";
+            INSERT INTO users (id, email, password, nom, prenom, otp_secret, otp_url, otp_enabled, is_oauth, created_at, updated_at, email_verified)
+            VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10, $11, $12)";
         client
             .execute(
                 create,
                 &[
+                    &self.id,
                     &self.email,
                     &self.password,
                     &self.nom,
@@ -191,17 +305,39 @@ impl User {
                     &self.is_oauth,
                     &self.created_at,
                     &self.updated_at,
+                    &self.email_verified,
                 ],
             )
             .await
     }
+    // Delete the user and everything attached to it in a single transaction
     pub async fn delete(self, pool: deadpool_postgres::Pool) -> Result<u64, Error> {
-        let client = pool.get().await.unwrap();
+        let mut client = pool.get().await.unwrap();
+        let transaction = client.transaction().await?;
 
         let delete_user = "DELETE FROM users where id = $1";
-        let delete_token = "DELETE FROM refresh_tokens where user_id = $1";
-        client.execute(delete_user, &[&self.id]).await?;
-        client.execute(delete_token, &[&self.id]).await
+        let delete_attached = [
+            "DELETE FROM refresh_tokens where user_id = $1",
+            "DELETE FROM otp_recovery_codes where user_id = $1",
+            "DELETE FROM webauthn_credentials where user_id = $1",
+            "DELETE FROM webauthn_challenges where user_id = $1",
+            "DELETE FROM trusted_devices where user_id = $1",
+            "DELETE FROM password_reset_tokens where user_id = $1",
+            "DELETE FROM email_verification_tokens where user_id = $1",
+        ];
+        let delete_login_attempt = "DELETE FROM login_attempts where key = $1";
+        let deleted = transaction.execute(delete_user, &[&self.id]).await?;
+        for delete in delete_attached {
+            transaction.execute(delete, &[&self.id]).await?;
+        }
+        transaction
+            .execute(
+                delete_login_attempt,
+                &[&LoginAttempt::account_key(&self.email)],
+            )
+            .await?;
+        transaction.commit().await?;
+        Ok(deleted)
     }
 
     pub async fn update_name_surname(self, pool: deadpool_postgres::Pool) -> Result<u64, Error> {
@@ -218,6 +354,29 @@ impl User {
             .await
     }
 
+    pub async fn update_password(&self, pool: deadpool_postgres::Pool) -> Result<u64, Error> {
+        let client = pool.get().await.unwrap();
+        let update = "
+            UPDATE users
+            SET password = $1, updated_at = $2
+            WHERE id = $3";
+        client
+            .execute(update, &[&self.password, &chrono::Utc::now(), &self.id])
+            .await
+    }
+
+    pub async fn mark_email_verified(
+        pool: deadpool_postgres::Pool,
+        id: Uuid,
+    ) -> Result<u64, Error> {
+        let client = pool.get().await.unwrap();
+        let update = "
+            UPDATE users
+            SET email_verified = TRUE, updated_at = $1
+            WHERE id = $2";
+        client.execute(update, &[&chrono::Utc::now(), &id]).await
+    }
+
     pub async fn update_otp_secret_url_token_enabled(
         &self,
         pool: deadpool_postgres::Pool,
@@ -225,8 +384,10 @@ impl User {
         let client = pool.get().await.unwrap();
         let update = "
             UPDATE users
-            SET otp_secret = $1, otp_url = $2, otp_enabled = $3, updated_at = $4, one_time_token = $5
-            WHERE id = $6";
+            SET otp_secret = $1, otp_url = $2, otp_enabled = $3, updated_at = $4, one_time_token = $5,
+                otp_algorithm = $6, otp_digits = $7, otp_period = $8, otp_last_step = $9,
+                one_time_token_expires_at = $10, one_time_token_attempts = $11, otp_key_id = $12
+            WHERE id = $13";
         client
             .execute(
                 update,
@@ -236,29 +397,157 @@ impl User {
                     &self.otp_enabled,
                     &chrono::Utc::now(),
                     &self.one_time_token,
+                    &self.otp_algorithm,
+                    &self.otp_digits,
+                    &self.otp_period,
+                    &self.otp_last_step,
+                    &self.one_time_token_expires_at,
+                    &self.one_time_token_attempts,
+                    &self.otp_key_id,
                     &self.id,
                 ],
             )
             .await
     }
+
+    // Remove the otp configuration, the recovery codes and the trusted devices of the user
+    pub async fn disable_otp(&mut self, pool: deadpool_postgres::Pool) -> Result<u64, Error> {
+        self.otp_secret = None;
+        self.otp_url = None;
+        self.otp_enabled = false;
+        self.clear_one_time_token();
+        self.otp_algorithm = None;
+        self.otp_digits = None;
+        self.otp_period = None;
+        self.otp_last_step = None;
+        self.otp_key_id = None;
+        self.update_otp_secret_url_token_enabled(pool.clone())
+            .await?;
+        TrustedDevice::delete_all_by_user(pool.clone(), self.id).await?;
+        RecoveryCode::delete_all_by_user(pool, self.id).await
+    }
+
+    // Count an invalid code, the one time token is removed after max_attempts
+    // Return true if the token can still be used
+    pub async fn fail_one_time_token(
+        &self,
+        pool: deadpool_postgres::Pool,
+        max_attempts: i32,
+    ) -> Result<bool, Error> {
+        let client = pool.get().await.unwrap();
+        let fail = "
+            UPDATE users
+            SET one_time_token_attempts = one_time_token_attempts + 1,
+                one_time_token = CASE WHEN one_time_token_attempts + 1 >= $2 THEN NULL ELSE one_time_token END
+            WHERE id = $1
+            RETURNING one_time_token IS NOT NULL";
+        let row = client.query_one(fail, &[&self.id, &max_attempts]).await?;
+        Ok(row.get(0))
+    }
+
+    // Encrypt the otp secrets saved in clear text with the current key, with rotate
+    // the secrets encrypted with a previous key are also encrypted with the current one
+    pub async fn encrypt_otp_secrets(
+        pool: deadpool_postgres::Pool,
+        otp_keys: &OtpKeys,
+        rotate: bool,
+    ) -> Result<u64, String> {
+        let client = pool.get().await.unwrap();
+
+        let get_secrets = "
+            SELECT id, otp_secret, otp_url, otp_key_id
+            FROM users
+            WHERE otp_secret IS NOT NULL AND (otp_key_id IS NULL OR ($1 AND otp_key_id <> $2))";
+        let rows = client
+            .query(get_secrets, &[&rotate, &otp_keys.current_kid()])
+            .await
+            .map_err(|err| err.to_string())?;
+        let update = "
+            UPDATE users
+            SET otp_secret = $1, otp_url = $2, otp_key_id = $3
+            WHERE id = $4 AND otp_key_id IS NOT DISTINCT FROM $5";
+        let mut updated = 0;
+        for row in rows {
+            let id: Uuid = row.get(0);
+            let key_id: Option<String> = row.get(3);
+            let reencrypt = |value: Option<String>| -> Result<Option<String>, String> {
+                match value {
+                    Some(value) => {
+                        let plaintext = otp_keys.decrypt(key_id.as_deref(), id, &value)?;
+                        otp_keys.encrypt(id, &plaintext).map(Some)
+                    }
+                    None => Ok(None),
+                }
+            };
+            let secret = reencrypt(row.get(1))?;
+            let url = reencrypt(row.get(2))?;
+            updated += client
+                .execute(
+                    update,
+                    &[&secret, &url, &otp_keys.current_kid(), &id, &key_id],
+                )
+                .await
+                .map_err(|err| err.to_string())?;
+        }
+        Ok(updated)
+    }
+
+    // Save the time step as used, return false if it or a later one was already used
+    pub async fn consume_otp_step(
+        &self,
+        pool: deadpool_postgres::Pool,
+        step: i64,
+    ) -> Result<bool, Error> {
+        let client = pool.get().await.unwrap();
+        let consume = "
+            UPDATE users
+            SET otp_last_step = $1
+            WHERE id = $2 AND (otp_last_step IS NULL OR otp_last_step < $1)";
+        let consumed = client.execute(consume, &[&step, &self.id]).await?;
+        Ok(consumed == 1)
+    }
 }
 
 impl User {
-    pub fn compare_password(&self, password: String) -> Result<bool, bcrypt::BcryptError> {
-        verify(password, &self.password)
+    // Hashing is slow on purpose, it runs on the blocking thread pool to keep the worker free
+    pub async fn compare_password(&self, password: String) -> Result<bool, String> {
+        let hash = self.password.clone();
+        web::block(move || PasswordHashConfig::verify(&password, &hash))
+            .await
+            .map_err(|err| err.to_string())?
     }
-    pub fn hash_password(&mut self, password: String) -> Option<bcrypt::BcryptError> {
-        match hash(password, DEFAULT_COST) {
-            Ok(hash) => {
+    // True if the stored hash use bcrypt or other argon2 parameters than the current ones
+    pub fn needs_rehash(&self, config: &PasswordHashConfig) -> bool {
+        config.needs_rehash(&self.password)
+    }
+    pub async fn hash_password(
+        &mut self,
+        password: String,
+        config: &PasswordHashConfig,
+    ) -> Option<String> {
+        let config = config.clone();
+        match web::block(move || config.hash(&password)).await {
+            Ok(Ok(hash)) => {
                 self.password = hash;
                 None
             }
-            Err(e) => Some(e),
+            Ok(Err(e)) => Some(e),
+            Err(e) => Some(e.to_string()),
         }
     }
-    pub fn gen_one_time_token(&mut self) {
-        let token = format!("{}_{}", helper::string::generate_random_string(6), self.id);
+    pub fn gen_one_time_token(&mut self, otp_config: &OtpConfig) {
+        let token = format!("{}_{}", helper::string::generate_random_string(32), self.id);
         self.one_time_token = Some(token);
+        self.one_time_token_expires_at = Some(
+            chrono::Utc::now() + chrono::Duration::seconds(otp_config.one_time_token_lifetime),
+        );
+        self.one_time_token_attempts = 0;
+    }
+
+    pub fn clear_one_time_token(&mut self) {
+        self.one_time_token = None;
+        self.one_time_token_expires_at = None;
+        self.one_time_token_attempts = 0;
     }
 
     pub fn to_public_user(&self) -> PublicUser {
@@ -266,53 +555,245 @@ impl User {
             id: self.id,
             nom: self.nom.clone(),
             prenom: self.prenom.clone(),
+            email: None,
+            created_at: None,
+        }
+    }
+
+    // Public user with the fields visible to authenticated users
+    pub fn to_detailed_public_user(&self) -> PublicUser {
+        PublicUser {
+            email: Some(self.email.clone()),
+            created_at: Some(self.created_at),
+            ..self.to_public_user()
         }
     }
 
-    pub fn gen_otp_secret(&mut self) {
+    // The secret and url are encrypted with the current otp key
+    pub fn gen_otp_secret(&mut self, otp_config: &OtpConfig) -> Result<(), String> {
         let secret = totp_rs::Secret::generate_secret();
-        let secret_byte = match secret.to_bytes() {
-            Ok(s) => s,
-            Err(err) => {
-                println!("{:?}", err);
-                return;
-            }
-        };
+        let secret_byte = secret.to_bytes().map_err(|err| format!("{:?}", err))?;
         let totp_object = totp_rs::TOTP::new(
-            totp_rs::Algorithm::SHA1,
-            6,
-            1,
-            30,
+            otp_config.algorithm,
+            otp_config.digits,
+            otp_config.skew,
+            otp_config.period,
             secret_byte,
             Some(var("APP_NAME").unwrap()),
             self.email.clone(),
         )
-        .unwrap();
-        self.otp_secret = Some(totp_object.get_secret_base32());
+        .map_err(|err| format!("{:?}", err))?;
+        self.otp_secret = Some(
+            otp_config
+                .keys
+                .encrypt(self.id, &totp_object.get_secret_base32())?,
+        );
+        self.otp_url = Some(otp_config.keys.encrypt(self.id, &totp_object.get_url())?);
+        self.otp_key_id = Some(otp_config.keys.current_kid().to_string());
+        self.otp_algorithm = Some(otp_config.algorithm.to_string());
+        self.otp_digits = Some(otp_config.digits as i32);
+        self.otp_period = Some(otp_config.period as i32);
+        self.otp_last_step = None;
+        Ok(())
     }
 
-    pub fn get_totp_obj(&self) -> Result<totp_rs::TOTP, TotpUrlError> {
-        let secret = totp_rs::Secret::Encoded(self.otp_secret.clone().unwrap());
+    pub fn get_totp_obj(&self, otp_config: &OtpConfig) -> Result<totp_rs::TOTP, TotpUrlError> {
+        let encoded_secret = match &self.otp_secret {
+            Some(otp_secret) => otp_config
+                .keys
+                .decrypt(self.otp_key_id.as_deref(), self.id, otp_secret)
+                .map_err(TotpUrlError::Secret)?,
+            None => return Err(TotpUrlError::Secret("No otp secret".to_string())),
+        };
+        let secret = totp_rs::Secret::Encoded(encoded_secret);
         let secret_byte = match secret.to_bytes() {
             Ok(s) => s,
             Err(err) => {
                 return Err(TotpUrlError::Secret(format!("{:?}", err)));
             }
         };
+        let algorithm = match &self.otp_algorithm {
+            Some(algorithm) => match OtpConfig::parse_algorithm(algorithm) {
+                Some(algorithm) => algorithm,
+                None => return Err(TotpUrlError::Algorithm(algorithm.clone())),
+            },
+            None => totp_rs::Algorithm::SHA1,
+        };
         totp_rs::TOTP::new(
-            totp_rs::Algorithm::SHA1,
-            6,
-            1,
-            30,
+            algorithm,
+            self.otp_digits.unwrap_or(6) as usize,
+            otp_config.skew,
+            self.otp_period.unwrap_or(30) as u64,
             secret_byte,
             Some(var("APP_NAME").unwrap()),
             self.email.clone(),
         )
     }
 
-    pub fn validate_otp(&self, otp: String) -> Result<bool, SystemTimeError> {
-        let totp = self.get_totp_obj().unwrap();
-        totp.check_current(&otp)
+    // Each time step can only be used once, a valid code can not be replayed
+    pub async fn validate_otp(
+        &mut self,
+        pool: deadpool_postgres::Pool,
+        otp_config: &OtpConfig,
+        otp: String,
+    ) -> Result<bool, String> {
+        let totp = self
+            .get_totp_obj(otp_config)
+            .map_err(|err| format!("{:?}", err))?;
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_err(|err| err.to_string())?
+            .as_secs();
+        let current_step = now / totp.step;
+        let skew = totp.skew as u64;
+        let step = match (current_step.saturating_sub(skew)..=current_step + skew).find(|step| {
+            let code = totp.generate(step * totp.step);
+            code.len() == otp.len() && memcmp::eq(code.as_bytes(), otp.as_bytes())
+        }) {
+            Some(step) => step as i64,
+            None => return Ok(false),
+        };
+        if self
+            .consume_otp_step(pool, step)
+            .await
+            .map_err(|err| err.to_string())?
+        {
+            self.otp_last_step = Some(step);
+            return Ok(true);
+        }
+        tracing::debug!(user = ?self.email, step = step ,"Otp code already used");
+        Ok(false)
+    }
+}
+
+// Identity of the caller read from the token only, without any database round trip.
+// Buildin token give the user id and the session, oidc token only the email.
+#[derive(Clone)]
+pub struct AuthClaims {
+    pub auth_type: AuthType,
+    pub user_id: Option<Uuid>,
+    pub session_id: Option<Uuid>,
+    pub email: String,
+}
+
+impl AuthClaims {
+    async fn from_token(req: actix_web::HttpRequest) -> Result<AuthClaims, actix_web::Error> {
+        let get_token_span = tracing::info_span!("Auth: Get Token in header");
+        let (token, auth_type) =
+            match get_token_span.in_scope(|| -> Result<(&str, AuthType), HttpResponse> {
+                header::extract_authorization_type_header(&req)
+            }) {
+                Ok(token) => token,
+                Err(_) => {
+                    tracing::error!("Error while getting token");
+                    return Err(ErrorUnauthorized("Error lors de la récupération du token"));
+                }
+            };
+        drop(get_token_span);
+        tracing::debug!("Token of type {:?} found", auth_type.to_string());
+        match auth_type {
+            AuthType::Oidc => {
+                let oidc_handler = match req.app_data::<web::Data<Oidc>>() {
+                    Some(handler) => handler,
+                    None => {
+                        tracing::error!("Error while getting oidc handler");
+                        return Err(ErrorUnauthorized("Error avec la configuration OIDC"));
+                    }
+                };
+                if oidc_handler.oidc_disabled {
+                    tracing::error!("OIDC is disabled");
+                    return Err(ErrorUnauthorized("OIDC est désactivé sur ce serveur"));
+                }
+                tracing::debug!("OIDC config loaded");
+                let validate_token_span = tracing::info_span!("Auth: Validate Token (oidc)");
+                async move {
+                    match oidc_handler
+                        .back
+                        .clone()
+                        .unwrap()
+                        .validate_token(token.to_string())
+                        .await
+                    {
+                        Ok((valide, value)) => {
+                            if valide {
+                                let email = value["email"].to_string().replace('\"', "");
+                                tracing::debug!(email=?email, "Token valide returning email");
+                                Ok(AuthClaims {
+                                    auth_type: AuthType::Oidc,
+                                    user_id: None,
+                                    session_id: None,
+                                    email,
+                                })
+                            } else {
+                                tracing::error!("Token invalide");
+                                Err(ErrorUnauthorized("Error lors de la récupération du token"))
+                            }
+                        }
+                        Err(err) => {
+                            tracing::error!(error = ?err, "Error while checking token with oidc");
+                            Err(ErrorUnauthorized("Error lors de la récupération du token"))
+                        }
+                    }
+                }
+                .instrument(validate_token_span)
+                .await
+            }
+            AuthType::BuildIn => {
+                let token_keys = match req.app_data::<web::Data<TokenKeys>>() {
+                    Some(keys) => keys,
+                    None => {
+                        tracing::error!("Error while getting token keys");
+                        return Err(ErrorUnauthorized("Error avec la configuration des tokens"));
+                    }
+                };
+                let validate_token_span = tracing::info_span!("Auth: Validate Token");
+                let claims =
+                    match validate_token_span.in_scope(|| -> Result<TokenClaims, String> {
+                        match TokenClaims::validate_token(token.to_string(), false, token_keys) {
+                            Ok(claim) => Ok(claim),
+                            Err(err) => {
+                                tracing::error!(error = ?err, "Error while checking token");
+                                Err("Invalid token".to_string())
+                            }
+                        }
+                    }) {
+                        Ok(claim) => claim,
+                        Err(err) => return Err(ErrorUnauthorized(err)),
+                    };
+                drop(validate_token_span);
+                let token_denylist = match req.app_data::<web::Data<TokenDenylist>>() {
+                    Some(denylist) => denylist,
+                    None => {
+                        tracing::error!("Error while getting token denylist");
+                        return Err(ErrorUnauthorized("Error avec la configuration des tokens"));
+                    }
+                };
+                if token_denylist.is_revoked(&claims) {
+                    tracing::debug!(user = ?claims.sub, "Token revoked");
+                    return Err(ErrorUnauthorized("Invalid token"));
+                }
+                Ok(AuthClaims {
+                    auth_type: AuthType::BuildIn,
+                    user_id: Some(claims.sub),
+                    session_id: claims.sid,
+                    email: claims.email,
+                })
+            }
+        }
+    }
+}
+
+impl FromRequest for AuthClaims {
+    type Error = actix_web::Error;
+    type Future = std::pin::Pin<Box<dyn std::future::Future<Output = Result<Self, Self::Error>>>>;
+
+    fn from_request(
+        req: &actix_web::HttpRequest,
+        _payload: &mut actix_web::dev::Payload,
+    ) -> Self::Future {
+        let req = req.clone();
+        tracing::info!("Start auth claims middleware");
+        Box::pin(async move { AuthClaims::from_token(req).await })
     }
 }
 
@@ -327,92 +808,22 @@ impl FromRequest for User {
         let req = req.clone();
         tracing::info!("Start auth middleware");
         Box::pin(async move {
-            let req = req.clone();
-            let get_token_span = tracing::info_span!("Auth: Get Token in header");
-            let (token, auth_type) =
-                match get_token_span.in_scope(|| -> Result<(&str, AuthType), HttpResponse> {
-                    header::extract_authorization_type_header(&req)
-                }) {
-                    Ok(token) => token,
-                    Err(_) => {
-                        tracing::error!("Error while getting token");
-                        return Err(ErrorUnauthorized("Error lors de la récupération du token"));
-                    }
-                };
-            drop(get_token_span);
-            tracing::debug!("Token of type {:?} found", auth_type.to_string());
-            let email_wrap = match auth_type {
-                AuthType::Oidc => {
-                    let oidc_handler = match req.app_data::<web::Data<Oidc>>() {
-                        Some(handler) => handler,
-                        None => {
-                            tracing::error!("Error while getting oidc handler");
-                            return Err(ErrorUnauthorized("Error avec la configuration OIDC"));
-                        }
-                    };
-                    if oidc_handler.oidc_disabled {
-                        tracing::error!("OIDC is disabled");
-                        return Err(ErrorUnauthorized("OIDC est désactivé sur ce serveur"));
-                    }
-                    tracing::debug!("OIDC config loaded");
-                    let validate_token_span = tracing::info_span!("Auth: Validate Token (oidc)");
-                    async move {
-                        match oidc_handler
-                            .back
-                            .clone()
-                            .unwrap()
-                            .validate_token(token.to_string())
-                            .await {
-                                Ok((valide, value)) => {
-                                    if valide {
-                                        let email = value["email"].to_string().replace('\"', "");
-                                        tracing::debug!(email=?email, "Token valide returning email");
-                                        Ok(email)
-                                    } else {
-                                        tracing::error!("Token invalide");
-                                        Err(ErrorUnauthorized(
-                                            "Error lors de la récupération du token",
-                                        ))
-                                    }
-                                }
-                                Err(err) => {
-                                    tracing::error!(error = ?err, "Error while checking token with oidc");
-                                    Err(ErrorUnauthorized(
-                                        "Error lors de la récupération du token",
-                                    ))
-                                }
-                            }
-                    }
-                    .instrument(validate_token_span)
-                    .await
-                }
-                AuthType::BuildIn => {
-                    let validate_token_span = tracing::info_span!("Auth: Validate Token");
-                    let claims =
-                        match validate_token_span.in_scope(|| -> Result<TokenClaims, String> {
-                            match TokenClaims::validate_token(token.to_string(), false) {
-                                Ok(claim) => Ok(claim),
-                                Err(err) => {
-                                    tracing::error!(error = ?err, "Error while checking token");
-                                    Err("Invalid token".to_string())
-                                }
-                            }
-                        }) {
-                            Ok(claim) => claim,
-                            Err(err) => return Err(ErrorUnauthorized(err)),
-                        };
-                    drop(validate_token_span);
-                    Ok(claims.sub.to_string())
-                }
-            };
-            let email = match email_wrap {
-                Ok(email) => email,
+            let auth_claims = match AuthClaims::from_token(req.clone()).await {
+                Ok(auth_claims) => auth_claims,
                 Err(err) => return Err(err),
             };
+            let auth_type = auth_claims.auth_type.clone();
             let check_user_span = tracing::info_span!("Auth: Check if user exists");
             let user = match async move {
                 let pool = req.app_data::<web::Data<Pool>>().unwrap();
-                match User::get_one_by_mail(pool.get_ref().clone(), email.clone()).await {
+                // Buildin token are resolved by id, oidc token by email
+                let user = match auth_claims.user_id {
+                    Some(user_id) => User::get_one(pool.get_ref().clone(), user_id)
+                        .await
+                        .map(Some),
+                    None => User::get_one_by_mail(pool.get_ref().clone(), auth_claims.email).await,
+                };
+                match user {
                     Ok(user) => match user {
                         Some(user) => Ok(user),
                         None => {
@@ -440,3 +851,29 @@ impl FromRequest for User {
         })
     }
 }
+
+// Optional authentication, None when the request has no Authorization header but an
+// invalid token is still rejected
+pub struct MaybeUser(pub Option<User>);
+
+impl FromRequest for MaybeUser {
+    type Error = actix_web::Error;
+    type Future = std::pin::Pin<Box<dyn std::future::Future<Output = Result<Self, Self::Error>>>>;
+
+    fn from_request(
+        req: &actix_web::HttpRequest,
+        payload: &mut actix_web::dev::Payload,
+    ) -> Self::Future {
+        if !req.headers().contains_key(AUTHORIZATION) {
+            tracing::debug!("No token provided, anonymous request");
+            return Box::pin(async { Ok(MaybeUser(None)) });
+        }
+        let user = User::from_request(req, payload);
+        Box::pin(async move {
+            match user.await {
+                Ok(user) => Ok(MaybeUser(Some(user))),
+                Err(err) => Err(err),
+            }
+        })
+    }
+}
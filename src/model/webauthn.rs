@@ -0,0 +1,313 @@
+use std::env;
+
+use openssl::{hash::MessageDigest, pkey::PKey, sign::Signer};
+use serde::Serialize;
+use utoipa::ToSchema;
+use uuid::Uuid;
+use webauthn_rs::{
+    prelude::{Base64UrlSafeData, Passkey, RequestChallengeResponse, Url},
+    Webauthn, WebauthnBuilder,
+};
+
+// Time left to the user to answer a challenge
+const CHALLENGE_LIFETIME: i64 = 300;
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum ChallengeKind {
+    // Only saved once the user gave its password, an otp code or a recovery code,
+    // and bound to the session which gave it
+    Registration,
+    // Second step of the login after the password
+    SecondFactor,
+    // Login with the email and the passkey only
+    Passwordless,
+}
+
+impl ChallengeKind {
+    fn as_str(&self) -> &'static str {
+        match self {
+            ChallengeKind::Registration => "registration",
+            ChallengeKind::SecondFactor => "second_factor",
+            ChallengeKind::Passwordless => "passwordless",
+        }
+    }
+}
+
+// Relying party identified by WEBAUTHN_RP_ID (the domain of the front) and
+// WEBAUTHN_RP_ORIGIN (the url of the front)
+pub fn new_webauthn() -> Result<Webauthn, String> {
+    let rp_id = env::var("WEBAUTHN_RP_ID").unwrap_or_else(|_| "localhost".to_string());
+    let rp_origin =
+        env::var("WEBAUTHN_RP_ORIGIN").unwrap_or_else(|_| "http://localhost:5173".to_string());
+    let rp_origin = Url::parse(&rp_origin)
+        .map_err(|err| format!("WEBAUTHN_RP_ORIGIN is not a valid url: {}", err))?;
+    let rp_name = env::var("WEBAUTHN_RP_NAME")
+        .unwrap_or_else(|_| env::var("APP_NAME").unwrap_or_else(|_| "Rust API".to_string()));
+    WebauthnBuilder::new(&rp_id, &rp_origin)
+        .map_err(|err| err.to_string())?
+        .rp_name(&rp_name)
+        .build()
+        .map_err(|err| err.to_string())
+}
+
+// The passwordless login answer the same way whether the account exists or has a
+// passkey, an unknown account get a challenge allowing a credential id derived from
+// its email with WEBAUTHN_DECOY_SIGN, so it doesn't change between two calls
+#[derive(Clone)]
+pub struct PasskeyDecoy {
+    sign_key: Vec<u8>,
+}
+
+impl PasskeyDecoy {
+    pub fn new() -> Result<PasskeyDecoy, String> {
+        Ok(PasskeyDecoy {
+            sign_key: env::var("WEBAUTHN_DECOY_SIGN")
+                .unwrap_or_else(|_| "lambda_webauthn_decoy_sign".to_string())
+                .into_bytes(),
+        })
+    }
+
+    fn credential_id(&self, email: &str) -> Result<Vec<u8>, String> {
+        let key = PKey::hmac(&self.sign_key).map_err(|err| err.to_string())?;
+        let mut signer =
+            Signer::new(MessageDigest::sha256(), &key).map_err(|err| err.to_string())?;
+        signer
+            .update(format!("passkey:{}", email.to_lowercase()).as_bytes())
+            .map_err(|err| err.to_string())?;
+        signer.sign_to_vec().map_err(|err| err.to_string())
+    }
+
+    // Options shaped like the ones of a real user, the challenge is not saved so it
+    // can't be answered
+    pub fn challenge(
+        &self,
+        webauthn: &Webauthn,
+        email: &str,
+    ) -> Result<RequestChallengeResponse, String> {
+        let (mut options, _) = webauthn
+            .start_passkey_authentication(&[])
+            .map_err(|err| err.to_string())?;
+        let credential = serde_json::json!({
+            "type": "public-key",
+            "id": Base64UrlSafeData::from(self.credential_id(email)?),
+        });
+        options
+            .public_key
+            .allow_credentials
+            .push(serde_json::from_value(credential).map_err(|err| err.to_string())?);
+        Ok(options)
+    }
+}
+
+#[derive(ToSchema, Clone, Serialize)]
+pub struct WebauthnCredential {
+    pub id: Uuid,
+    pub name: String,
+    #[serde(skip)]
+    pub passkey: Passkey,
+    pub created_at: chrono::DateTime<chrono::Utc>,
+    pub last_used_at: Option<chrono::DateTime<chrono::Utc>>,
+}
+
+impl WebauthnCredential {
+    pub async fn create_table(pool: deadpool_postgres::Pool) -> Result<u64, tokio_postgres::Error> {
+        let client = pool.get().await.unwrap();
+
+        let create_table = "
+      CREATE TABLE IF NOT EXISTS webauthn_credentials (
+        id UUID PRIMARY KEY DEFAULT uuid_generate_v4(),
+        user_id UUID NOT NULL,
+        credential_id VARCHAR NOT NULL UNIQUE,
+        name VARCHAR(255) NOT NULL,
+        passkey JSONB NOT NULL,
+        created_at TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+        last_used_at TIMESTAMPTZ
+      );";
+        client.execute(create_table, &[]).await?;
+
+        let create_challenge_table = "
+      CREATE TABLE IF NOT EXISTS webauthn_challenges (
+        id UUID PRIMARY KEY DEFAULT uuid_generate_v4(),
+        user_id UUID NOT NULL,
+        kind VARCHAR NOT NULL,
+        state JSONB NOT NULL,
+        expires_at TIMESTAMPTZ NOT NULL,
+        session_id UUID
+      );";
+        client.execute(create_challenge_table, &[]).await?;
+
+        // Migrate table created before the registration was bound to a session
+        let add_session = "
+      ALTER TABLE webauthn_challenges
+        ADD COLUMN IF NOT EXISTS session_id UUID;";
+        client.execute(add_session, &[]).await
+    }
+
+    fn from_row(row: &tokio_postgres::Row) -> Result<WebauthnCredential, String> {
+        Ok(WebauthnCredential {
+            id: row.get(0),
+            name: row.get(1),
+            passkey: serde_json::from_value(row.get(2)).map_err(|err| err.to_string())?,
+            created_at: row.get(3),
+            last_used_at: row.get(4),
+        })
+    }
+
+    pub async fn create(
+        pool: deadpool_postgres::Pool,
+        user_id: Uuid,
+        name: String,
+        passkey: Passkey,
+    ) -> Result<WebauthnCredential, String> {
+        let client = pool.get().await.unwrap();
+
+        let create = "
+        INSERT INTO webauthn_credentials (user_id, credential_id, name, passkey)
+        VALUES ($1, $2, $3, $4)
+        RETURNING id, name, passkey, created_at, last_used_at";
+        let passkey_json = serde_json::to_value(&passkey).map_err(|err| err.to_string())?;
+        let row = client
+            .query_one(
+                create,
+                &[
+                    &user_id,
+                    &passkey.cred_id().to_string(),
+                    &name,
+                    &passkey_json,
+                ],
+            )
+            .await
+            .map_err(|err| err.to_string())?;
+        WebauthnCredential::from_row(&row)
+    }
+
+    pub async fn get_all_by_user(
+        pool: deadpool_postgres::Pool,
+        user_id: Uuid,
+    ) -> Result<Vec<WebauthnCredential>, String> {
+        let client = pool.get().await.unwrap();
+
+        let get_all = "
+        SELECT id, name, passkey, created_at, last_used_at
+        FROM webauthn_credentials
+        WHERE user_id = $1
+        ORDER BY created_at";
+        let rows = client
+            .query(get_all, &[&user_id])
+            .await
+            .map_err(|err| err.to_string())?;
+        rows.iter().map(WebauthnCredential::from_row).collect()
+    }
+
+    pub async fn exists_for_user(
+        pool: deadpool_postgres::Pool,
+        user_id: Uuid,
+    ) -> Result<bool, tokio_postgres::Error> {
+        let client = pool.get().await.unwrap();
+
+        let exists = "
+        SELECT EXISTS (SELECT 1 FROM webauthn_credentials WHERE user_id = $1)";
+        let row = client.query_one(exists, &[&user_id]).await?;
+        Ok(row.get(0))
+    }
+
+    // Save the new counter of the passkey and the last use
+    pub async fn update_usage(&self, pool: deadpool_postgres::Pool) -> Result<u64, String> {
+        let client = pool.get().await.unwrap();
+
+        let update = "
+        UPDATE webauthn_credentials
+        SET passkey = $1, last_used_at = NOW()
+        WHERE id = $2";
+        let passkey_json = serde_json::to_value(&self.passkey).map_err(|err| err.to_string())?;
+        client
+            .execute(update, &[&passkey_json, &self.id])
+            .await
+            .map_err(|err| err.to_string())
+    }
+
+    pub async fn delete(
+        pool: deadpool_postgres::Pool,
+        user_id: Uuid,
+        id: Uuid,
+    ) -> Result<u64, tokio_postgres::Error> {
+        let client = pool.get().await.unwrap();
+
+        let delete = "
+        DELETE FROM webauthn_credentials
+        WHERE id = $1 AND user_id = $2";
+        client.execute(delete, &[&id, &user_id]).await
+    }
+
+    // Save the state of a registration or authentication until the answer of the authenticator,
+    // the session is the one allowed to answer it (none for a login)
+    pub async fn save_challenge<T: Serialize>(
+        pool: deadpool_postgres::Pool,
+        user_id: Uuid,
+        session_id: Option<Uuid>,
+        kind: ChallengeKind,
+        state: &T,
+    ) -> Result<Uuid, String> {
+        let client = pool.get().await.unwrap();
+
+        let delete_expired = "
+        DELETE FROM webauthn_challenges
+        WHERE expires_at < NOW()";
+        client
+            .execute(delete_expired, &[])
+            .await
+            .map_err(|err| err.to_string())?;
+        let save = "
+        INSERT INTO webauthn_challenges (user_id, kind, state, expires_at, session_id)
+        VALUES ($1, $2, $3, NOW() + $4::BIGINT * INTERVAL '1 second', $5)
+        RETURNING id";
+        let state_json = serde_json::to_value(state).map_err(|err| err.to_string())?;
+        let row = client
+            .query_one(
+                save,
+                &[
+                    &user_id,
+                    &kind.as_str(),
+                    &state_json,
+                    &CHALLENGE_LIFETIME,
+                    &session_id,
+                ],
+            )
+            .await
+            .map_err(|err| err.to_string())?;
+        Ok(row.get(0))
+    }
+
+    // Remove the challenge and return its user and state, a challenge can only be answered
+    // once and is kept if sent to the route of another kind
+    pub async fn consume_challenge<T: serde::de::DeserializeOwned>(
+        pool: deadpool_postgres::Pool,
+        id: Uuid,
+        session_id: Option<Uuid>,
+        kinds: &[ChallengeKind],
+    ) -> Result<Option<(Uuid, ChallengeKind, T)>, String> {
+        let client = pool.get().await.unwrap();
+
+        let kind_names: Vec<&str> = kinds.iter().map(|kind| kind.as_str()).collect();
+        let consume = "
+        DELETE FROM webauthn_challenges
+        WHERE id = $1 AND expires_at > NOW() AND session_id IS NOT DISTINCT FROM $2
+        AND kind = ANY($3)
+        RETURNING user_id, kind, state";
+        let row = match client
+            .query_opt(consume, &[&id, &session_id, &kind_names])
+            .await
+            .map_err(|err| err.to_string())?
+        {
+            Some(row) => row,
+            None => return Ok(None),
+        };
+        let kind_str: String = row.get(1);
+        let kind = match kinds.iter().find(|kind| kind.as_str() == kind_str) {
+            Some(kind) => *kind,
+            None => return Ok(None),
+        };
+        let state = serde_json::from_value(row.get(2)).map_err(|err| err.to_string())?;
+        Ok(Some((row.get(0), kind, state)))
+    }
+}
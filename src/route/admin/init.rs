@@ -0,0 +1,10 @@
+use actix_web::{web, Scope};
+
+use super::{invitation, reset_otp, unlock};
+
+pub fn init_admin() -> Scope {
+    web::scope("/admin")
+        .service(reset_otp::reset_otp)
+        .service(unlock::unlock)
+        .service(invitation::init::init_invitation())
+}
@@ -0,0 +1,95 @@
+use crate::{
+    helper,
+    model::{
+        registration::{Invitation, RegistrationConfig},
+        user::User,
+    },
+};
+use actix_web::{http::header::ContentType, post, web, HttpResponse, Responder};
+use deadpool_postgres::Pool;
+use serde::{Deserialize, Serialize};
+use tracing::Instrument;
+use utoipa::ToSchema;
+
+#[derive(Serialize, Deserialize, ToSchema, Clone)]
+pub struct CreateInvitation {
+    // Only this email can use the invitation, anyone with the code if absent
+    pub email: Option<String>,
+}
+
+#[derive(Serialize, ToSchema)]
+pub struct CreatedInvitation {
+    // Only returned at the creation, to send to the invited user
+    pub code: String,
+    pub invitation: Invitation,
+}
+
+/// Create an invitation
+///
+/// Admin only, the returned code allow one registration while the registration is invite only
+#[utoipa::path(
+  tag = "Admin>Invitation",
+  request_body = CreateInvitation,
+  operation_id = "admincreateinvitation",
+  path = "/api/admin/invitations",
+  responses(
+      (status = 200, description = "Success", body = CreatedInvitation),
+      (status = 400, description = "Email not valid"),
+      (status = 403, description = "Not an admin"),
+      (status = 500, description = "Internal server error"),
+  ),
+  params(
+    ("Authorization-type" = Option<AuthType>, Header, description = "Type de token (oidc ou buildin), détecté depuis le token si absent")
+  ),
+  security(
+    ("access_token" = []),
+    ("oidc" = [])
+  )
+)]
+#[post("")]
+pub async fn create_invitation(
+    user: User,
+    db_pool: web::Data<Pool>,
+    registration_config: web::Data<RegistrationConfig>,
+    create_body: web::Json<CreateInvitation>,
+) -> impl Responder {
+    if !user.is_admin {
+        tracing::debug!(user = ?user.email ,"User is not an admin");
+        return HttpResponse::Forbidden().finish();
+    }
+    let body = create_body.into_inner();
+    if let Some(email) = &body.email {
+        if !helper::string_rule::validate_email(email.clone()) {
+            tracing::debug!(user = ?user.email, email = ?email ,"Email not valid");
+            return HttpResponse::BadRequest()
+                .content_type(ContentType::plaintext())
+                .body("Email not valid");
+        }
+    }
+    let pool: Pool = db_pool.into_inner().as_ref().clone();
+    let create_invitation_span = tracing::info_span!("Create invitation");
+    let invitation = match Invitation::create(
+        pool,
+        body.email,
+        user.id,
+        registration_config.invitation_lifetime,
+    )
+    .instrument(create_invitation_span)
+    .await
+    {
+        Ok(invitation) => invitation,
+        Err(err) => {
+            tracing::error!(error = ?err,user = ?user.email ,"Error while creating invitation");
+            return HttpResponse::InternalServerError().finish();
+        }
+    };
+    let code = match registration_config.invitation_code(invitation.id) {
+        Ok(code) => code,
+        Err(err) => {
+            tracing::error!(error = ?err,user = ?user.email ,"Error while signing invitation");
+            return HttpResponse::InternalServerError().finish();
+        }
+    };
+    tracing::info!(admin = ?user.email, invitation = ?invitation.id ,"Invitation created");
+    HttpResponse::Ok().json(CreatedInvitation { code, invitation })
+}
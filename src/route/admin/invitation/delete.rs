@@ -0,0 +1,58 @@
+use crate::model::{registration::Invitation, user::User};
+use actix_web::{delete, web, HttpResponse, Responder};
+use deadpool_postgres::Pool;
+use tracing::Instrument;
+
+/// Revoke an invitation
+///
+/// Admin only, the code can no longer be used to register
+#[utoipa::path(
+  tag = "Admin>Invitation",
+  operation_id = "admindeleteinvitation",
+  path = "/api/admin/invitations/{id}",
+  responses(
+      (status = 200, description = "Success"),
+      (status = 403, description = "Not an admin"),
+      (status = 404, description = "Invitation not found"),
+      (status = 500, description = "Internal server error"),
+  ),
+  params(
+    ("id" = uuid, Path, description = "Id de l'invitation"),
+    ("Authorization-type" = Option<AuthType>, Header, description = "Type de token (oidc ou buildin), détecté depuis le token si absent")
+  ),
+  security(
+    ("access_token" = []),
+    ("oidc" = [])
+  )
+)]
+#[delete("/{id}")]
+pub async fn delete_invitation(
+    user: User,
+    invitation_id: web::Path<uuid::Uuid>,
+    db_pool: web::Data<Pool>,
+) -> impl Responder {
+    if !user.is_admin {
+        tracing::debug!(user = ?user.email ,"User is not an admin");
+        return HttpResponse::Forbidden().finish();
+    }
+    let invitation_id = invitation_id.into_inner();
+    let pool: Pool = db_pool.into_inner().as_ref().clone();
+    let delete_invitation_span = tracing::info_span!("Delete invitation");
+    match Invitation::delete(pool, invitation_id)
+        .instrument(delete_invitation_span)
+        .await
+    {
+        Ok(0) => {
+            tracing::debug!(user = ?user.email, invitation = ?invitation_id, "Invitation not found");
+            HttpResponse::NotFound().finish()
+        }
+        Ok(_) => {
+            tracing::info!(admin = ?user.email, invitation = ?invitation_id, "Invitation revoked");
+            HttpResponse::Ok().finish()
+        }
+        Err(err) => {
+            tracing::error!(error = ?err, user = ?user.email, "Error while revoking invitation");
+            HttpResponse::InternalServerError().finish()
+        }
+    }
+}
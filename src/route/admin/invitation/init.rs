@@ -0,0 +1,10 @@
+use actix_web::{web, Scope};
+
+use super::{create, delete, list};
+
+pub fn init_invitation() -> Scope {
+    web::scope("/invitations")
+        .service(list::list_invitations)
+        .service(create::create_invitation)
+        .service(delete::delete_invitation)
+}
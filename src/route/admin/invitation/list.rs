@@ -0,0 +1,44 @@
+use crate::model::{registration::Invitation, user::User};
+use actix_web::{get, web, HttpResponse, Responder};
+use deadpool_postgres::Pool;
+use tracing::Instrument;
+
+/// List the invitations
+///
+/// Admin only, the used and expired invitations are included
+#[utoipa::path(
+  tag = "Admin>Invitation",
+  operation_id = "adminlistinvitations",
+  path = "/api/admin/invitations",
+  responses(
+      (status = 200, description = "Success", body = Vec<Invitation>),
+      (status = 403, description = "Not an admin"),
+      (status = 500, description = "Internal server error"),
+  ),
+  params(
+    ("Authorization-type" = Option<AuthType>, Header, description = "Type de token (oidc ou buildin), détecté depuis le token si absent")
+  ),
+  security(
+    ("access_token" = []),
+    ("oidc" = [])
+  )
+)]
+#[get("")]
+pub async fn list_invitations(user: User, db_pool: web::Data<Pool>) -> impl Responder {
+    if !user.is_admin {
+        tracing::debug!(user = ?user.email ,"User is not an admin");
+        return HttpResponse::Forbidden().finish();
+    }
+    let pool: Pool = db_pool.into_inner().as_ref().clone();
+    let list_invitations_span = tracing::info_span!("List invitations");
+    match Invitation::get_all(pool)
+        .instrument(list_invitations_span)
+        .await
+    {
+        Ok(invitations) => HttpResponse::Ok().json(invitations),
+        Err(err) => {
+            tracing::error!(error = ?err,user = ?user.email ,"Error while listing invitations");
+            HttpResponse::InternalServerError().finish()
+        }
+    }
+}
@@ -0,0 +1,4 @@
+pub mod create;
+pub mod delete;
+pub mod init;
+pub mod list;
@@ -0,0 +1,4 @@
+pub mod init;
+pub mod invitation;
+pub mod reset_otp;
+pub mod unlock;
@@ -0,0 +1,102 @@
+use crate::model::{revocation::TokenDenylist, token::TokenLifetime, user::User};
+use actix_web::{delete, http::header::ContentType, web, HttpResponse, Responder};
+use deadpool_postgres::Pool;
+use tracing::Instrument;
+
+/// Reset the otp of a user
+///
+/// Admin only, remove the otp and the recovery codes of the user and revoke every of its session
+#[utoipa::path(
+  tag = "Admin",
+  operation_id = "adminresetotp",
+  path = "/api/admin/user/{id}/otp",
+  responses(
+      (status = 200, description = "Success", body = String),
+      (status = 400, description = "Otp not enabled"),
+      (status = 403, description = "Not an admin"),
+      (status = 404, description = "User not found"),
+      (status = 500, description = "Internal server error"),
+  ),
+  params(
+    ("id" = uuid, Path, description = "Id de l'utilisateur"),
+    ("Authorization-type" = Option<AuthType>, Header, description = "Type de token (oidc ou buildin), détecté depuis le token si absent")
+  ),
+  security(
+    ("access_token" = []),
+    ("oidc" = [])
+  )
+)]
+#[delete("/user/{id}/otp")]
+pub async fn reset_otp(
+    user: User,
+    uid_user: web::Path<uuid::Uuid>,
+    db_pool: web::Data<Pool>,
+    token_lifetime: web::Data<TokenLifetime>,
+    token_denylist: web::Data<TokenDenylist>,
+) -> impl Responder {
+    if !user.is_admin {
+        tracing::debug!(user = ?user.email ,"User is not an admin");
+        return HttpResponse::Forbidden().finish();
+    }
+    let target_user_id = uid_user.into_inner();
+    tracing::debug!(admin = ?user.email, uid = ?target_user_id ,"Resetting user otp");
+    let pool: Pool = db_pool.into_inner().as_ref().clone();
+    let find_user_span = tracing::info_span!("Find user");
+    let mut target_user = match {
+        let pool = pool.clone();
+        async move {
+            match User::get_one(pool, target_user_id).await {
+                Ok(user) => {
+                    tracing::debug!(user = ?user.email ,"User found");
+                    Ok(user)
+                }
+                Err(err) => {
+                    tracing::error!(error = ?err,uid = ?target_user_id ,"Error while getting user");
+                    Err(HttpResponse::NotFound().finish())
+                }
+            }
+        }
+        .instrument(find_user_span)
+    }
+    .await
+    {
+        Ok(selected_user) => selected_user,
+        Err(err) => return err,
+    };
+    if !target_user.otp_enabled {
+        tracing::debug!(user = ?target_user.email ,"Otp not enabled");
+        return HttpResponse::BadRequest().finish();
+    }
+
+    let reset_otp_span = tracing::info_span!("Disable otp and revoke sessions");
+    match async move {
+        if let Err(err) = target_user.disable_otp(pool.clone()).await {
+            tracing::error!(error = ?err,user = ?target_user.email ,"Error while disabling otp");
+            return Err(HttpResponse::InternalServerError().finish());
+        }
+        let lifetime = token_lifetime.for_user(&target_user);
+        match token_denylist
+            .revoke_all_sessions(pool, target_user.id, &lifetime)
+            .await
+        {
+            Ok(nbr) => {
+                tracing::info!(admin = ?user.email, user = ?target_user.email, nbr_token = nbr ,"Otp reset and sessions revoked");
+                Ok(())
+            }
+            Err(err) => {
+                tracing::error!(error = ?err,user = ?target_user.email ,"Error while revoking sessions");
+                Err(HttpResponse::InternalServerError().finish())
+            }
+        }
+    }
+    .instrument(reset_otp_span)
+    .await
+    {
+        Ok(_) => (),
+        Err(err) => return err,
+    }
+
+    HttpResponse::Ok()
+        .content_type(ContentType::plaintext())
+        .body("Otp reset")
+}
@@ -0,0 +1,115 @@
+use std::net::IpAddr;
+
+use crate::model::{login_attempt::LoginAttempt, user::User};
+use actix_web::{delete, http::header::ContentType, web, HttpResponse, Responder};
+use deadpool_postgres::Pool;
+use serde::Deserialize;
+use tracing::Instrument;
+
+#[derive(Deserialize)]
+pub struct UnlockQuery {
+    pub ip: Option<String>,
+}
+
+/// Unlock a user
+///
+/// Admin only, clear the failed login attempts of the user. The counters of the ips are
+/// kept as an ip can be shared by several users, the one given in `ip` is also cleared.
+/// The response tell what was unlocked.
+#[utoipa::path(
+  tag = "Admin",
+  operation_id = "adminunlock",
+  path = "/api/admin/user/{id}/lock",
+  responses(
+      (status = 200, description = "Success", body = String),
+      (status = 400, description = "Invalid ip"),
+      (status = 403, description = "Not an admin"),
+      (status = 404, description = "User not found"),
+      (status = 500, description = "Internal server error"),
+  ),
+  params(
+    ("id" = uuid, Path, description = "Id de l'utilisateur"),
+    ("ip" = Option<String>, Query, description = "Ip to unlock as well"),
+    ("Authorization-type" = Option<AuthType>, Header, description = "Type de token (oidc ou buildin), détecté depuis le token si absent")
+  ),
+  security(
+    ("access_token" = []),
+    ("oidc" = [])
+  )
+)]
+#[delete("/user/{id}/lock")]
+pub async fn unlock(
+    user: User,
+    uid_user: web::Path<uuid::Uuid>,
+    query: web::Query<UnlockQuery>,
+    db_pool: web::Data<Pool>,
+) -> impl Responder {
+    if !user.is_admin {
+        tracing::debug!(user = ?user.email ,"User is not an admin");
+        return HttpResponse::Forbidden().finish();
+    }
+    // Written the way the client ip is stored, an ipv6 can be given in any form
+    let ip = match query.into_inner().ip.map(|ip| ip.trim().parse::<IpAddr>()) {
+        Some(Ok(ip)) => Some(ip.to_string()),
+        Some(Err(_)) => {
+            tracing::debug!(user = ?user.email ,"Invalid ip");
+            return HttpResponse::BadRequest()
+                .content_type(ContentType::plaintext())
+                .body("Invalid ip");
+        }
+        None => None,
+    };
+    let target_user_id = uid_user.into_inner();
+    tracing::debug!(admin = ?user.email, uid = ?target_user_id ,"Unlocking user");
+    let pool: Pool = db_pool.into_inner().as_ref().clone();
+    let find_user_span = tracing::info_span!("Find user");
+    let target_user = match {
+        let pool = pool.clone();
+        async move {
+            match User::get_one(pool, target_user_id).await {
+                Ok(user) => {
+                    tracing::debug!(user = ?user.email ,"User found");
+                    Ok(user)
+                }
+                Err(err) => {
+                    tracing::error!(error = ?err,uid = ?target_user_id ,"Error while getting user");
+                    Err(HttpResponse::NotFound().finish())
+                }
+            }
+        }
+        .instrument(find_user_span)
+    }
+    .await
+    {
+        Ok(selected_user) => selected_user,
+        Err(err) => return err,
+    };
+
+    let unlock_span = tracing::info_span!("Reset failed attempts");
+    match async {
+        LoginAttempt::reset(pool.clone(), &target_user.email).await?;
+        if let Some(ip) = &ip {
+            LoginAttempt::reset_ip(pool, ip).await?;
+        }
+        Ok::<(), tokio_postgres::Error>(())
+    }
+    .instrument(unlock_span)
+    .await
+    {
+        Ok(_) => {
+            tracing::info!(admin = ?user.email, user = ?target_user.email, ip = ?ip ,"User unlocked");
+        }
+        Err(err) => {
+            tracing::error!(error = ?err,user = ?target_user.email ,"Error while resetting failed attempts");
+            return HttpResponse::InternalServerError().finish();
+        }
+    }
+
+    let unlocked = match ip {
+        Some(ip) => format!("User and ip {} unlocked", ip),
+        None => "User unlocked, the ip locks are kept".to_string(),
+    };
+    HttpResponse::Ok()
+        .content_type(ContentType::plaintext())
+        .body(unlocked)
+}
@@ -1,14 +1,26 @@
 use utoipa::OpenApi;
 
 use super::super::model::oidc;
+use super::admin::{
+    invitation::{
+        create as invitation_create, delete as invitation_delete, list as invitation_list,
+    },
+    reset_otp, unlock,
+};
 use super::auth::{
-    info, login, logout,
-    otp::{activate, generate, validate},
+    device,
+    email::{resend as email_resend, verify as email_verify},
+    info, login, logout, logout_all,
+    otp::{activate, disable, generate, recovery_codes, validate},
+    password::{forgot, reset},
     refresh, register, register_oidc,
+    session::{delete, list, rename},
+    webauthn::{credentials, login as webauthn_login, register as webauthn_register},
 };
 use super::health;
 use super::security::SecurityAddon;
-use super::user::{current_user, delete_user, get_one_user, update_user};
+use super::user::{current_user, delete_user, get_one_user, update_password, update_user};
+use super::well_known;
 use crate::model;
 
 #[derive(OpenApi)]
@@ -24,8 +36,15 @@ use crate::model;
         ),
     ),
     tags(
+        (name = "Admin", description = "Administration"),
+        (name = "Admin>Invitation", description = "Administration>Invitation"),
         (name = "Auth", description = "Authentification"),
+        (name = "Auth>Device", description = "Authentification>Device"),
+        (name = "Auth>Email", description = "Authentification>Email"),
         (name = "Auth>Otp", description = "Authentification>Otp"),
+        (name = "Auth>Password", description = "Authentification>Password"),
+        (name = "Auth>Session", description = "Authentification>Session"),
+        (name = "Auth>Webauthn", description = "Authentification>Webauthn"),
         (name = "Health", description = "Health check"),
         (name = "User", description = "User management")
     ),
@@ -36,30 +55,79 @@ use crate::model;
         register::register,
         refresh::refresh,
         logout::logout,
+        logout_all::logout_all,
         info::auth_status,
         current_user::get_current_user,
         get_one_user::get_one_user,
         delete_user::delete_user,
         update_user::update_user,
+        update_password::update_password,
         generate::generate_otp,
         activate::activate_otp,
         validate::validate_otp,
+        recovery_codes::regenerate_recovery_codes,
+        disable::disable_otp,
+        reset_otp::reset_otp,
+        unlock::unlock,
+        invitation_list::list_invitations,
+        invitation_create::create_invitation,
+        invitation_delete::delete_invitation,
+        forgot::forgot_password,
+        reset::reset_password,
+        email_verify::verify_email,
+        email_resend::resend_verification,
         register_oidc::register_oidc,
+        list::list_sessions,
+        rename::rename_session,
+        delete::delete_session,
+        device::list::list_devices,
+        device::delete::delete_device,
+        webauthn_register::start_registration,
+        webauthn_register::finish_registration,
+        webauthn_login::start_login,
+        webauthn_login::finish_login,
+        credentials::list_credentials,
+        credentials::delete_credential,
+        well_known::jwks,
     ),
     components(
         schemas(
             model::user::User,
             model::user::PublicUser,
             model::user::UserUpdate,
+            model::user::UserPasswordUpdate,
             generate::GenOtp,
             activate::ActivateOtp,
             validate::ValidateOtp,
+            recovery_codes::RecoveryCodes,
+            recovery_codes::RegenerateRecoveryCodes,
+            disable::DisableOtp,
+            forgot::ForgotPassword,
+            model::password_policy::PasswordRule,
+            model::password_policy::PasswordPolicyError,
+            reset::ResetPassword,
+            email_verify::VerifyEmail,
+            email_resend::ResendVerification,
             login::LoginUser,
             login::LoginUserReturn,
             login::LoginStatus,
+            model::login_attempt::LoginLocked,
+            model::registration::Invitation,
+            invitation_create::CreateInvitation,
+            invitation_create::CreatedInvitation,
             register::RegisterUser,
+            register_oidc::RegisterOidc,
             register::RegisterUserReturn,
             refresh::RefreshTokenReturn,
+            model::token::Session,
+            rename::SessionUpdate,
+            model::trusted_device::TrustedDevice,
+            model::webauthn::WebauthnCredential,
+            webauthn_register::WebauthnChallenge,
+            webauthn_register::WebauthnProof,
+            webauthn_register::FinishWebauthnRegistration,
+            webauthn_login::StartWebauthnLogin,
+            webauthn_login::FinishWebauthnLogin,
             info::AuthStatus,
             info::AuthProtocol,
             info::AuthType,
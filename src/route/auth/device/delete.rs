@@ -0,0 +1,58 @@
+use crate::model::{trusted_device::TrustedDevice, user::User};
+use actix_web::{delete, web, HttpResponse, Responder};
+use deadpool_postgres::Pool;
+use tracing::Instrument;
+
+/// Revoke trusted device
+///
+/// The device token can no longer be used to skip the otp step
+#[utoipa::path(
+  tag = "Auth>Device",
+  operation_id = "deletedevice",
+  path = "/api/auth/devices/{id}",
+  responses(
+      (status = 200, description = "Success"),
+      (status = 404, description = "Device not found"),
+      (status = 500, description = "Internal server error"),
+  ),
+  params(
+    ("id" = uuid, Path, description = "Id du device"),
+    ("Authorization-type" = Option<AuthType>, Header, description = "Type de token (oidc ou buildin), détecté depuis le token si absent")
+  ),
+  security(
+    ("access_token" = [])
+  )
+)]
+#[delete("/{id}")]
+pub async fn delete_device(
+    user: User,
+    device_id: web::Path<uuid::Uuid>,
+    db_pool: web::Data<Pool>,
+) -> impl Responder {
+    let device_id = device_id.into_inner();
+    tracing::debug!(user = ?user.email, device = ?device_id, "Revoking trusted device");
+    let pool: Pool = db_pool.into_inner().as_ref().clone();
+    let delete_device_span = tracing::info_span!("Delete trusted device");
+    match async move {
+        match TrustedDevice::delete(pool, user.id, device_id).await {
+            Ok(0) => {
+                tracing::debug!(user = ?user.email, device = ?device_id, "Trusted device not found");
+                Err(HttpResponse::NotFound().finish())
+            }
+            Ok(_) => {
+                tracing::debug!(user = ?user.email, device = ?device_id, "Trusted device revoked");
+                Ok(())
+            }
+            Err(err) => {
+                tracing::error!(error = ?err, user = ?user.email, "Error while revoking trusted device");
+                Err(HttpResponse::InternalServerError().finish())
+            }
+        }
+    }
+    .instrument(delete_device_span)
+    .await
+    {
+        Ok(_) => HttpResponse::Ok().finish(),
+        Err(err) => err,
+    }
+}
@@ -0,0 +1,9 @@
+use actix_web::{web, Scope};
+
+use super::{delete, list};
+
+pub fn init_device() -> Scope {
+    web::scope("/devices")
+        .service(list::list_devices)
+        .service(delete::delete_device)
+}
@@ -0,0 +1,47 @@
+use crate::model::{trusted_device::TrustedDevice, user::User};
+use actix_web::{get, web, HttpResponse, Responder};
+use deadpool_postgres::Pool;
+use tracing::Instrument;
+
+/// List trusted devices
+///
+/// List the devices of the current user allowed to skip the otp step
+#[utoipa::path(
+  tag = "Auth>Device",
+  operation_id = "listdevices",
+  path = "/api/auth/devices",
+  responses(
+      (status = 200, description = "Trusted devices", body = [TrustedDevice]),
+      (status = 500, description = "Internal server error"),
+  ),
+  params(
+    ("Authorization-type" = Option<AuthType>, Header, description = "Type de token (oidc ou buildin), détecté depuis le token si absent")
+  ),
+  security(
+    ("access_token" = [])
+  )
+)]
+#[get("")]
+pub async fn list_devices(user: User, db_pool: web::Data<Pool>) -> impl Responder {
+    tracing::debug!(user = ?user.email, "Listing trusted devices");
+    let pool: Pool = db_pool.into_inner().as_ref().clone();
+    let list_devices_span = tracing::info_span!("List trusted devices");
+    match async move {
+        match TrustedDevice::get_all_by_user(pool, user.id).await {
+            Ok(devices) => {
+                tracing::debug!(user = ?user.email, nbr_device = devices.len(), "Trusted devices found");
+                Ok(devices)
+            }
+            Err(err) => {
+                tracing::error!(error = ?err, user = ?user.email, "Error while getting trusted devices");
+                Err(HttpResponse::InternalServerError().finish())
+            }
+        }
+    }
+    .instrument(list_devices_span)
+    .await
+    {
+        Ok(devices) => HttpResponse::Ok().json(devices),
+        Err(err) => err,
+    }
+}
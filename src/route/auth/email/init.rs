@@ -0,0 +1,9 @@
+use actix_web::{web, Scope};
+
+use super::{resend, verify};
+
+pub fn init_email() -> Scope {
+    web::scope("/email")
+        .service(verify::verify_email)
+        .service(resend::resend_verification)
+}
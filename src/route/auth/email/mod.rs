@@ -0,0 +1,3 @@
+pub mod init;
+pub mod resend;
+pub mod verify;
@@ -0,0 +1,124 @@
+use crate::model::{
+    email_verification::{EmailVerification, EmailVerificationConfig},
+    mailer::Mailer,
+    user::User,
+};
+use actix_web::{http::header::ContentType, post, web, HttpResponse, Responder};
+use deadpool_postgres::Pool;
+use serde::{Deserialize, Serialize};
+use tracing::Instrument;
+use utoipa::ToSchema;
+
+#[derive(Serialize, Deserialize, ToSchema, Clone)]
+pub struct ResendVerification {
+    pub email: String,
+}
+
+// Create a new verification token and send it by mail in the background
+pub async fn send_verification_mail(
+    pool: Pool,
+    mailer: web::Data<Mailer>,
+    config: &EmailVerificationConfig,
+    user: User,
+) -> Result<(), HttpResponse> {
+    let create_token_span = tracing::info_span!("Create verification token");
+    let token = match EmailVerification::create(pool, user.id, config.lifetime)
+        .instrument(create_token_span)
+        .await
+    {
+        Ok(Some(token)) => token,
+        Ok(None) => {
+            tracing::debug!(user = ?user.email ,"Verification mail sent less than a minute ago");
+            return Ok(());
+        }
+        Err(err) => {
+            tracing::error!(error = ?err,user = ?user.email ,"Error while creating verification token");
+            return Err(HttpResponse::InternalServerError().finish());
+        }
+    };
+
+    // In hours when it is a whole number of hours, in minutes otherwise
+    let expires_in = if config.lifetime % 3600 == 0 {
+        format!("{} hours", config.lifetime / 3600)
+    } else {
+        format!("{} minutes", (config.lifetime + 59) / 60)
+    };
+    let content = format!(
+        "Hello {},\n\nUse the link below to verify your email address. It expires in {}.\n\n{}\n\nIf you did not create an account, you can ignore this mail.\n",
+        user.prenom,
+        expires_in,
+        config.verification_link(&token)
+    );
+    let send_mail_span = tracing::info_span!("Send verification mail");
+    actix_web::rt::spawn(
+        async move {
+            match mailer.send(&user.email, "Verify your email", content).await {
+                Ok(_) => tracing::debug!(user = ?user.email ,"Verification mail sent"),
+                Err(err) => {
+                    tracing::error!(error = ?err,user = ?user.email ,"Error while sending verification mail")
+                }
+            }
+        }
+        .instrument(send_mail_span),
+    );
+    Ok(())
+}
+
+/// Resend the verification mail
+///
+/// The response is the same whether the email exist, is already verified or not.
+/// A new mail is sent at most once a minute and invalidate the previous link
+#[utoipa::path(
+  tag = "Auth>Email",
+  request_body = ResendVerification,
+  operation_id = "resendverification",
+  path = "/api/auth/email/resend",
+  responses(
+      (status = 200, description = "Success", body = String),
+      (status = 500, description = "Internal server error"),
+  )
+)]
+#[post("/resend")]
+pub async fn resend_verification(
+    db_pool: web::Data<Pool>,
+    mailer: web::Data<Mailer>,
+    verification_config: web::Data<EmailVerificationConfig>,
+    resend_body: web::Json<ResendVerification>,
+) -> impl Responder {
+    let body = resend_body.into_inner();
+    let pool: Pool = db_pool.into_inner().as_ref().clone();
+    let find_user_span = tracing::info_span!("Find user");
+    let user = match {
+        let pool_swap = pool.clone();
+        let body_swap = body.clone();
+        async move {
+            match User::get_one_by_mail(pool_swap, body_swap.email.clone()).await {
+                Ok(Some(user)) if !user.is_oauth && !user.email_verified => Ok(Some(user)),
+                Ok(_) => {
+                    tracing::debug!(user = ?body_swap.email ,"User not found, oauth or already verified");
+                    Ok(None)
+                }
+                Err(err) => {
+                    tracing::error!(error = ?err,user = ?body_swap.email ,"Error while getting user");
+                    Err(HttpResponse::InternalServerError().finish())
+                }
+            }
+        }
+        .instrument(find_user_span)
+    }
+    .await
+    {
+        Ok(user) => user,
+        Err(err) => return err,
+    };
+
+    if let Some(user) = user {
+        if let Err(err) = send_verification_mail(pool, mailer, &verification_config, user).await {
+            return err;
+        }
+    }
+
+    HttpResponse::Ok()
+        .content_type(ContentType::plaintext())
+        .body("If the email exist and is not verified a verification link has been sent")
+}
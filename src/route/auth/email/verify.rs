@@ -0,0 +1,72 @@
+use crate::model::{email_verification::EmailVerification, user::User};
+use actix_web::{http::header::ContentType, post, web, HttpResponse, Responder};
+use deadpool_postgres::Pool;
+use serde::{Deserialize, Serialize};
+use tracing::Instrument;
+use utoipa::ToSchema;
+
+#[derive(Serialize, Deserialize, ToSchema, Clone)]
+pub struct VerifyEmail {
+    // Token received by mail
+    pub token: String,
+}
+
+/// Verify email
+///
+/// Mark the email of the user as verified with the token received by mail, the token can only be used once
+#[utoipa::path(
+  tag = "Auth>Email",
+  request_body = VerifyEmail,
+  operation_id = "verifyemail",
+  path = "/api/auth/email/verify",
+  responses(
+      (status = 200, description = "Success", body = String),
+      (status = 400, description = "Token invalid or expired"),
+      (status = 500, description = "Internal server error"),
+  )
+)]
+#[post("/verify")]
+pub async fn verify_email(
+    db_pool: web::Data<Pool>,
+    verify_body: web::Json<VerifyEmail>,
+) -> impl Responder {
+    let body = verify_body.into_inner();
+    let pool: Pool = db_pool.into_inner().as_ref().clone();
+
+    let verify_email_span = tracing::info_span!("Consume verification token");
+    match async move {
+        let user_id = match EmailVerification::consume(pool.clone(), &body.token).await {
+            Ok(Some(user_id)) => user_id,
+            Ok(None) => {
+                tracing::debug!("Verification token invalid or expired");
+                return Err(HttpResponse::BadRequest()
+                    .content_type(ContentType::plaintext())
+                    .body("Token invalid or expired"));
+            }
+            Err(err) => {
+                tracing::error!(error = ?err ,"Error while consuming verification token");
+                return Err(HttpResponse::InternalServerError().finish());
+            }
+        };
+        match User::mark_email_verified(pool, user_id).await {
+            Ok(_) => {
+                tracing::debug!(user = ?user_id ,"Email verified");
+                Ok(())
+            }
+            Err(err) => {
+                tracing::error!(error = ?err,user = ?user_id ,"Error while verifying email");
+                Err(HttpResponse::InternalServerError().finish())
+            }
+        }
+    }
+    .instrument(verify_email_span)
+    .await
+    {
+        Ok(_) => (),
+        Err(err) => return err,
+    }
+
+    HttpResponse::Ok()
+        .content_type(ContentType::plaintext())
+        .body("Email verified")
+}
@@ -1,4 +1,7 @@
-use crate::model::oidc::{FrontOidc, Oidc};
+use crate::model::{
+    oidc::{FrontOidc, Oidc},
+    registration::RegistrationConfig,
+};
 use actix_web::{get, web, HttpResponse, Responder};
 use serde::{Deserialize, Serialize};
 use std::fmt;
@@ -7,7 +10,10 @@ use utoipa::ToSchema;
 #[derive(Deserialize, Serialize, ToSchema, Clone)]
 pub struct AuthStatus {
     pub enabled_protocol: Vec<AuthProtocol>,
+    // A new account can be created, with the register or the first oidc login
     pub can_register: bool,
+    // The register need an invitation code
+    pub invitation_required: bool,
 }
 
 #[derive(Deserialize, Serialize, ToSchema, Clone)]
@@ -46,7 +52,10 @@ impl fmt::Display for AuthType {
   )
 )]
 #[get("")]
-pub async fn auth_status(oidc_handler: web::Data<Oidc>) -> impl Responder {
+pub async fn auth_status(
+    oidc_handler: web::Data<Oidc>,
+    registration_config: web::Data<RegistrationConfig>,
+) -> impl Responder {
     tracing::debug!("Asking for api auth status");
     let mut auth_possible = vec![AuthProtocol {
         type_auth: AuthType::BuildIn,
@@ -63,7 +72,8 @@ pub async fn auth_status(oidc_handler: web::Data<Oidc>) -> impl Responder {
         });
     }
     HttpResponse::Ok().json(AuthStatus {
-        can_register: true,
+        can_register: registration_config.can_register(),
+        invitation_required: registration_config.invitation_required(),
         enabled_protocol: auth_possible,
     })
 }
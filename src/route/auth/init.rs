@@ -1,19 +1,31 @@
 use actix_web::{web, Scope};
 
+use super::device;
+use super::email;
 use super::info;
 use super::login;
 use super::logout;
+use super::logout_all;
 use super::otp;
+use super::password;
 use super::refresh;
 use super::register;
 use super::register_oidc;
+use super::session;
+use super::webauthn;
 pub fn init_auth() -> Scope {
     web::scope("/auth")
         .service(login::login)
         .service(register::register)
         .service(refresh::refresh)
         .service(logout::logout)
+        .service(logout_all::logout_all)
         .service(otp::init::init_otp())
+        .service(password::init::init_password())
+        .service(email::init::init_email())
+        .service(session::init::init_session())
+        .service(device::init::init_device())
+        .service(webauthn::init::init_webauthn())
         .service(info::auth_status)
         .service(register_oidc::register_oidc)
 }
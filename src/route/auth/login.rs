@@ -1,17 +1,29 @@
-use actix_web::{post, web, HttpResponse, Responder};
+use actix_web::{http::header::ContentType, post, web, HttpRequest, HttpResponse, Responder};
 use deadpool_postgres::Pool;
 use serde::{Deserialize, Serialize};
 use tracing::Instrument;
 use utoipa::ToSchema;
 
-use crate::model::{
-    token::{self, RefreshToken, TokenClaims},
-    user::User,
+use crate::{
+    helper::header,
+    model::{
+        email_verification::EmailVerificationConfig,
+        login_attempt::{LoginAttempt, LoginAttemptConfig, LoginReservation},
+        otp::OtpConfig,
+        password_hash::PasswordHashConfig,
+        token::{self, RefreshToken, TokenClaims, TokenKeys, TokenLifetime},
+        trusted_device::{TrustedDevice, TrustedDeviceConfig},
+        user::User,
+        webauthn::WebauthnCredential,
+    },
 };
 
 #[derive(Serialize, Deserialize, ToSchema, Clone)]
+#[allow(clippy::enum_variant_names)]
 pub enum LoginStatus {
     OtpStep,
+    // The user has a passkey, the otp can still be used if enabled
+    WebauthnStep,
     RefreshStep,
 }
 
@@ -19,6 +31,8 @@ pub enum LoginStatus {
 pub struct LoginUser {
     pub email: String,
     pub password: String,
+    // Returned by the otp validation of a trusted device, skip the otp step
+    pub device_token: Option<String>,
 }
 
 #[derive(Serialize, Deserialize, ToSchema)]
@@ -28,24 +42,128 @@ pub struct LoginUserReturn {
     pub user: Option<User>,
     pub status: LoginStatus,
     pub token: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[serde(default)]
+    pub device_token: Option<String>,
+}
+
+// Count the attempt as failed before the credentials are checked, return the 423
+// response if the account or the ip is locked and a 500 if it can't be counted
+pub async fn reserve_login_attempt(
+    pool: Pool,
+    login_attempt_config: &LoginAttemptConfig,
+    email: &str,
+    ip: Option<&str>,
+) -> Result<LoginReservation, HttpResponse> {
+    let reserve_attempt_span = tracing::info_span!("Check if locked and count attempt");
+    match LoginAttempt::reserve(pool, login_attempt_config, email, ip)
+        .instrument(reserve_attempt_span)
+        .await
+    {
+        Ok(Ok(reservation)) => Ok(reservation),
+        Ok(Err(retry_after)) => {
+            tracing::debug!(user = ?email, retry_after = retry_after, "Too many failed attempts");
+            Err(LoginAttempt::locked_response(retry_after))
+        }
+        Err(err) => {
+            tracing::error!(error = ?err, user = ?email, "Error while counting attempt");
+            Err(HttpResponse::InternalServerError().finish())
+        }
+    }
+}
+
+// Give back the attempt once the credentials are valid, an error keep it counted as failed
+pub async fn release_login_attempt(pool: Pool, reservation: &LoginReservation) {
+    let release_attempt_span = tracing::info_span!("Release counted attempt");
+    if let Err(err) = LoginAttempt::release(pool, reservation)
+        .instrument(release_attempt_span)
+        .await
+    {
+        tracing::error!(error = ?err, "Error while releasing counted attempt");
+    }
+}
+
+// Clear the failures of the account once fully logged in
+pub async fn reset_login_failures(pool: Pool, email: &str) {
+    let reset_failures_span = tracing::info_span!("Reset failed attempts");
+    if let Err(err) = LoginAttempt::reset(pool, email)
+        .instrument(reset_failures_span)
+        .await
+    {
+        tracing::error!(error = ?err, user = ?email, "Error while resetting failed attempts");
+    }
+}
+
+// Return the 423 response if the account or the ip is locked, without counting an attempt
+pub async fn check_login_lock(
+    pool: Pool,
+    email: &str,
+    ip: Option<&str>,
+) -> Result<(), HttpResponse> {
+    let check_lock_span = tracing::info_span!("Check if locked");
+    match LoginAttempt::locked_for(pool, email, ip)
+        .instrument(check_lock_span)
+        .await
+    {
+        Ok(None) => Ok(()),
+        Ok(Some(retry_after)) => {
+            tracing::debug!(user = ?email, retry_after = retry_after, "Too many failed attempts");
+            Err(LoginAttempt::locked_response(retry_after))
+        }
+        Err(err) => {
+            tracing::error!(error = ?err, user = ?email, "Error while checking failed attempts");
+            Err(HttpResponse::InternalServerError().finish())
+        }
+    }
 }
 
 /// Login user
+///
+/// After too many failed attempts the account or the ip is locked, a 423 is returned
+/// with the seconds to wait in Retry-After.
+/// If the email verification is required an unverified user get a 403
 #[utoipa::path(
   tag = "Auth",
   request_body = LoginUser,
   operation_id = "login",
   path = "/api/auth/login",
   responses(
-      (status = 200, description = "Login user", body = LoginUserReturn)
+      (status = 200, description = "Login user", body = LoginUserReturn),
+      (status = 401, description = "Invalid credentials"),
+      (status = 403, description = "Email not verified"),
+      (status = 423, description = "Too many failed attempts", body = LoginLocked),
   )
 )]
 #[post("/login")]
-pub async fn login(login_body: web::Json<LoginUser>, db_pool: web::Data<Pool>) -> impl Responder {
+#[allow(clippy::too_many_arguments)]
+pub async fn login(
+    req: HttpRequest,
+    login_body: web::Json<LoginUser>,
+    db_pool: web::Data<Pool>,
+    token_keys: web::Data<TokenKeys>,
+    token_lifetime: web::Data<TokenLifetime>,
+    otp_config: web::Data<OtpConfig>,
+    trusted_device_config: web::Data<TrustedDeviceConfig>,
+    password_hash_config: web::Data<PasswordHashConfig>,
+    login_attempt_config: web::Data<LoginAttemptConfig>,
+    verification_config: web::Data<EmailVerificationConfig>,
+) -> impl Responder {
     let body = login_body.into_inner();
     let pool: Pool = db_pool.into_inner().as_ref().clone();
+    let (user_agent, ip) = header::extract_client_info(&req);
+    let reservation = match reserve_login_attempt(
+        pool.clone(),
+        &login_attempt_config,
+        &body.email,
+        ip.as_deref(),
+    )
+    .await
+    {
+        Ok(reservation) => reservation,
+        Err(err) => return err,
+    };
     let check_user_span = tracing::info_span!("Check if user exist");
-    let user = match {
+    let mut user = match {
         let pool_swap = pool.clone();
         let body_swap = body.clone();
         async move {
@@ -78,8 +196,8 @@ pub async fn login(login_body: web::Json<LoginUser>, db_pool: web::Data<Pool>) -
     };
     {
         let valid_password_span = tracing::info_span!("Check if password is valid");
-        if let Err(err_response)=valid_password_span.in_scope(|| -> Result<_,HttpResponse> {
-            match user.compare_password(body.password.clone()) {
+        if let Err(err_response) = async {
+            match user.compare_password(body.password.clone()).await {
                 Ok(valid) => {
                     if !valid {
                         tracing::error!(user = ?body.email.clone() ,"Invalid password");
@@ -93,16 +211,115 @@ pub async fn login(login_body: web::Json<LoginUser>, db_pool: web::Data<Pool>) -
                     Err(HttpResponse::Unauthorized().finish())
                 }
             }
-        }){
+        }
+        .instrument(valid_password_span)
+        .await
+        {
             return err_response;
         }
     }
+    release_login_attempt(pool.clone(), &reservation).await;
+
+    if user.needs_rehash(&password_hash_config) {
+        // A failed upgrade doesn't block the login, it will be retried on the next one
+        let rehash_password_span = tracing::info_span!("Upgrade password hash");
+        async {
+            if let Some(err) = user
+                .hash_password(body.password.clone(), &password_hash_config)
+                .await
+            {
+                tracing::error!(error = ?err,user = ?body.email.clone() ,"Error while hashing password");
+                return;
+            }
+            match user.update_password(pool.clone()).await {
+                Ok(_) => {
+                    tracing::debug!(user = ?body.email.clone() ,"Password hash upgraded");
+                }
+                Err(err) => {
+                    tracing::error!(error = ?err,user = ?body.email.clone() ,"Error while saving upgraded password hash");
+                }
+            }
+        }
+        .instrument(rehash_password_span)
+        .await;
+    }
+
+    if verification_config.required && !user.email_verified {
+        tracing::debug!(user = ?body.email ,"Email not verified");
+        return HttpResponse::Forbidden()
+            .content_type(ContentType::plaintext())
+            .body("Email not verified");
+    }
+
+    let check_passkey_span = tracing::info_span!("Check if user has a passkey");
+    let has_passkey = match {
+        let pool_swap = pool.clone();
+        let user_id = user.id;
+        async move {
+            match WebauthnCredential::exists_for_user(pool_swap, user_id).await {
+                Ok(has_passkey) => Ok(has_passkey),
+                Err(err) => {
+                    tracing::error!(error = ?err, user = ?user_id, "Error while checking passkeys");
+                    Err(HttpResponse::InternalServerError().finish())
+                }
+            }
+        }
+        .instrument(check_passkey_span)
+    }
+    .await
+    {
+        Ok(has_passkey) => has_passkey,
+        Err(err) => return err,
+    };
+
+    let second_factor = has_passkey || user.otp_enabled;
+    let trusted_device = match (second_factor, body.device_token.clone()) {
+        (true, Some(device_token)) => {
+            match trusted_device_config.verify_device_token(user.id, &device_token) {
+                Some(device_id) => {
+                    let check_device_span = tracing::info_span!("Check trusted device");
+                    match {
+                        let pool_swap = pool.clone();
+                        let user_id = user.id;
+                        async move {
+                            match TrustedDevice::use_device(pool_swap, user_id, device_id).await {
+                                Ok(trusted) => Ok(trusted),
+                                Err(err) => {
+                                    tracing::error!(error = ?err, user = ?user_id, "Error while checking trusted device");
+                                    Err(HttpResponse::InternalServerError().finish())
+                                }
+                            }
+                        }
+                        .instrument(check_device_span)
+                    }
+                    .await
+                    {
+                        Ok(trusted) => trusted,
+                        Err(err) => return err,
+                    }
+                }
+                None => {
+                    tracing::debug!(user = body.email, "Invalid device token");
+                    false
+                }
+            }
+        }
+        _ => false,
+    };
 
-    if user.otp_enabled {
-        tracing::debug!(user = body.email, "User has otp enabled, sending otp");
-        let mut user = user;
+    if second_factor && !trusted_device {
+        let status = if has_passkey {
+            tracing::debug!(
+                user = body.email,
+                "User has a passkey, sending webauthn step"
+            );
+            LoginStatus::WebauthnStep
+        } else {
+            tracing::debug!(user = body.email, "User has otp enabled, sending otp");
+            LoginStatus::OtpStep
+        };
         tracing::debug!(user = body.email, "One time token generated");
-        user.gen_one_time_token();
+        user.gen_one_time_token(&otp_config);
         let token = user.one_time_token.clone();
 
         {
@@ -126,9 +343,10 @@ pub async fn login(login_body: web::Json<LoginUser>, db_pool: web::Data<Pool>) -
         }
 
         return HttpResponse::Ok().json(LoginUserReturn {
-            status: LoginStatus::OtpStep,
+            status,
             user: None,
             token,
+            device_token: None,
         });
     }
 
@@ -136,8 +354,17 @@ pub async fn login(login_body: web::Json<LoginUser>, db_pool: web::Data<Pool>) -
         user = body.email,
         "User logged in, generating refresh_token"
     );
+    reset_login_failures(pool.clone(), &user.email).await;
 
-    let refresh_token = match TokenClaims::new_tokens(user.id, user.email.clone(), true) {
+    let lifetime = token_lifetime.for_user(&user);
+    let refresh_token = match TokenClaims::new_tokens(
+        user.id,
+        user.email.clone(),
+        true,
+        &token_keys,
+        &lifetime,
+        chrono::Utc::now(),
+    ) {
         Ok(token) => token,
         Err(err) => {
             tracing::error!(error = ?err,user = ?body.email.clone() ,"Error while generating token");
@@ -145,18 +372,21 @@ pub async fn login(login_body: web::Json<LoginUser>, db_pool: web::Data<Pool>) -
         }
     };
 
-    let refresh_token_db = RefreshToken {
-        created_at: chrono::Utc::now(),
-        user_id: user.id,
-        token: refresh_token.clone(),
-    };
+    let refresh_token_db =
+        match RefreshToken::new(user.id, &refresh_token, &token_keys, user_agent, ip) {
+            Ok(token) => token,
+            Err(err) => {
+                tracing::error!(error = ?err, "Error while hashing refresh token");
+                return HttpResponse::InternalServerError().finish();
+            }
+        };
 
     {
         let pool_swap = pool.clone();
         let body_swap = body.clone();
         let delete_old_token_span = tracing::info_span!("Delete old refresh token");
         if let Err(err_response) = async move{
-            match token::RefreshToken::keep_only_four_token(pool_swap, user.id).await {
+            match token::RefreshToken::keep_only_last_sessions(pool_swap, user.id, &lifetime).await {
                 Ok(_) => {
                     tracing::debug!(user = ?body_swap.email.clone() ,"Successfully deleted old refresh token");
                     Ok(())
@@ -195,5 +425,6 @@ pub async fn login(login_body: web::Json<LoginUser>, db_pool: web::Data<Pool>) -
         user: Some(user),
         status: LoginStatus::RefreshStep,
         token: Some(refresh_token),
+        device_token: None,
     })
 }
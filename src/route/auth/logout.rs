@@ -5,25 +5,38 @@ use tracing::Instrument;
 
 use crate::{
     helper::header,
-    model::token::{self, RefreshToken, TokenClaims},
+    model::{
+        revocation::{RevocationKind, TokenDenylist},
+        token::{self, RefreshToken, TokenClaims, TokenKeys, TokenLifetime},
+        user::User,
+    },
 };
 
 /// Logout
 ///
 /// This endpoint is used to disconnect the user with the refresh token
+///
+/// A refresh token that was already rotated is handled as a reuse, the session is still revoked but a 401 is returned
 #[utoipa::path(
   tag = "Auth",
   operation_id = "logout",
   path = "/api/auth/logout",
   responses(
-    (status = 200, description = "Logout", body = String)
+    (status = 200, description = "Logout", body = String),
+    (status = 401, description = "Refresh token already used, the session is revoked", body = String)
   ),
   security(
     ("refresh_token" = [])
   )
 )]
 #[get("/logout")]
-pub async fn logout(req: HttpRequest, db_pool: web::Data<Pool>) -> impl Responder {
+pub async fn logout(
+    req: HttpRequest,
+    db_pool: web::Data<Pool>,
+    token_keys: web::Data<TokenKeys>,
+    token_lifetime: web::Data<TokenLifetime>,
+    token_denylist: web::Data<TokenDenylist>,
+) -> impl Responder {
     let get_token_span = tracing::info_span!("Get Token in header");
     let (token, auth_type) =
         match get_token_span.in_scope(|| -> Result<(&str, AuthType), HttpResponse> {
@@ -41,7 +54,7 @@ pub async fn logout(req: HttpRequest, db_pool: web::Data<Pool>) -> impl Responde
                 .content_type(ContentType::plaintext())
                 .body("Invalid token type"));
         }
-        match token::TokenClaims::validate_token(token.to_string(), true) {
+        match token::TokenClaims::validate_token(token.to_string(), true, &token_keys) {
             Ok(claim) => Ok(claim),
             Err(err) => {
                 tracing::error!(error = ?err, "Error while checking token");
@@ -55,50 +68,106 @@ pub async fn logout(req: HttpRequest, db_pool: web::Data<Pool>) -> impl Responde
         Err(err) => return err,
     };
     drop(check_token_span);
+    let token_hash = match token_keys.hash_refresh_token(token) {
+        Ok(hash) => hash,
+        Err(err) => {
+            tracing::error!(error = ?err, "Error while hashing refresh token");
+            return HttpResponse::InternalServerError().finish();
+        }
+    };
     let pool: Pool = db_pool.clone().into_inner().as_ref().clone();
-    {
+    let refresh_token_db = {
         let check_refresh_token_span = tracing::info_span!("Check if refresh token exist");
         match async move {
-            match RefreshToken::get_one_by_token(pool.clone(), token.to_string()).await {
+            match RefreshToken::get_one_by_token(pool.clone(), token_hash).await {
                 Ok(found_token) => {
-                    tracing::debug!(token = ?found_token.token ,"Refresh token found");
+                    tracing::debug!(token = ?found_token.token_prefix ,"Refresh token found");
                     Ok(found_token)
                 }
                 Err(err) => {
-                    tracing::error!(error = ?err,token = ?token ,"Error while getting refresh token");
+                    tracing::error!(error = ?err ,"Error while getting refresh token");
                     Err(HttpResponse::Unauthorized().finish())
                 }
             }
         }
         .instrument(check_refresh_token_span)
         .await
+        {
+            Ok(found_token) => found_token,
+            Err(err) => return err,
+        }
+    };
+    let user_id = refresh_token_db.user_id;
+    let family_id = refresh_token_db.family_id;
+    let reused = refresh_token_db.consumed_at.is_some();
+    if reused {
+        tracing::warn!(
+            security_event = "refresh_token_reuse",
+            user = ?user_id,
+            family = ?family_id,
+            "Reuse of a consumed refresh token, revoking the whole token family"
+        );
+    }
+    let token_prefix = refresh_token_db.token_prefix;
+    let delete_refresh_span = tracing::info_span!("Delete refresh token family");
+    let pool_delete: Pool = db_pool.clone().into_inner().as_ref().clone();
+    let pool_revoke: Pool = db_pool.into_inner().as_ref().clone();
+    {
+        match async move {
+            match RefreshToken::delete_family(pool_delete.clone(), family_id).await {
+                Ok(_) => {
+                    tracing::debug!(token = ?token_prefix, family = ?family_id ,"Refresh token family deleted");
+                    Ok(())
+                }
+                Err(err) => {
+                    tracing::error!(error = ?err,token = ?token_prefix, family = ?family_id ,"Error while deleting refresh token");
+                    Err(HttpResponse::InternalServerError().finish())
+                }
+            }
+        }
+        .instrument(delete_refresh_span.clone())
+        .await
         {
             Ok(_) => {}
             Err(err) => return err,
         };
     }
-    let delete_refresh_span = tracing::info_span!("Delete refresh token");
-    let pool_delete: Pool = db_pool.into_inner().as_ref().clone();
+    let revoke_access_span = tracing::info_span!("Revoke access token of the session");
     {
         match async move {
-            match RefreshToken::delete_token(pool_delete.clone(), token.to_string()).await {
+            let lifetime = match User::get_one(pool_revoke.clone(), user_id).await {
+                Ok(user) => token_lifetime.for_user(&user),
+                Err(err) => {
+                    tracing::error!(error = ?err, user = ?user_id, "Error while getting user");
+                    return Err(HttpResponse::InternalServerError().finish());
+                }
+            };
+            match token_denylist
+                .revoke(pool_revoke, RevocationKind::Session, family_id, &lifetime)
+                .await
+            {
                 Ok(_) => {
-                    tracing::debug!(token = ?token ,"Refresh token deleted");
+                    tracing::debug!(user = ?user_id, "Session access token revoked");
                     Ok(())
                 }
                 Err(err) => {
-                    tracing::error!(error = ?err,token = ?token ,"Error while deleting refresh token");
+                    tracing::error!(error = ?err, user = ?user_id, "Error while revoking session access token");
                     Err(HttpResponse::InternalServerError().finish())
                 }
             }
         }
-        .instrument(delete_refresh_span.clone())
+        .instrument(revoke_access_span)
         .await
         {
             Ok(_) => {}
             Err(err) => return err,
         };
     }
+    if reused {
+        return HttpResponse::Unauthorized()
+            .content_type(ContentType::plaintext())
+            .body("Refresh token already used");
+    }
     HttpResponse::Ok()
         .content_type(ContentType::plaintext())
         .body("User Disconnected")
@@ -0,0 +1,200 @@
+use super::info::AuthType;
+use actix_web::{get, http::header::ContentType, web, HttpRequest, HttpResponse, Responder};
+use deadpool_postgres::Pool;
+use tracing::Instrument;
+
+use crate::{
+    helper::header,
+    model::{
+        revocation::{RevocationKind, TokenDenylist},
+        token::{self, RefreshToken, TokenClaims, TokenKeys, TokenLifetime},
+        user::User,
+    },
+};
+
+/// Logout all
+///
+/// This endpoint is used to disconnect every session of the user with the refresh token
+///
+/// A refresh token that was already rotated is handled as a reuse like on refresh, only its family is revoked and a 401 is returned
+#[utoipa::path(
+  tag = "Auth",
+  operation_id = "logoutall",
+  path = "/api/auth/logout-all",
+  responses(
+    (status = 200, description = "Logout", body = String),
+    (status = 401, description = "Refresh token already used, only its session is revoked", body = String)
+  ),
+  security(
+    ("refresh_token" = [])
+  )
+)]
+#[get("/logout-all")]
+pub async fn logout_all(
+    req: HttpRequest,
+    db_pool: web::Data<Pool>,
+    token_keys: web::Data<TokenKeys>,
+    token_lifetime: web::Data<TokenLifetime>,
+    token_denylist: web::Data<TokenDenylist>,
+) -> impl Responder {
+    let get_token_span = tracing::info_span!("Get Token in header");
+    let (token, auth_type) =
+        match get_token_span.in_scope(|| -> Result<(&str, AuthType), HttpResponse> {
+            header::extract_authorization_type_header(&req)
+        }) {
+            Ok(token) => token,
+            Err(err) => return err,
+        };
+    drop(get_token_span);
+    let check_token_span = tracing::info_span!("Check if token is valid");
+    match check_token_span.in_scope(|| -> Result<TokenClaims, HttpResponse> {
+        if auth_type == AuthType::Oidc {
+            tracing::error!(token_type = ?auth_type.to_string(),"Invalid token type");
+            return Err(HttpResponse::Unauthorized()
+                .content_type(ContentType::plaintext())
+                .body("Invalid token type"));
+        }
+        match token::TokenClaims::validate_token(token.to_string(), true, &token_keys) {
+            Ok(claim) => Ok(claim),
+            Err(err) => {
+                tracing::error!(error = ?err, "Error while checking token");
+                Err(HttpResponse::Unauthorized()
+                    .content_type(ContentType::plaintext())
+                    .body("Invalid token"))
+            }
+        }
+    }) {
+        Ok(claim) => claim,
+        Err(err) => return err,
+    };
+    drop(check_token_span);
+    let token_hash = match token_keys.hash_refresh_token(token) {
+        Ok(hash) => hash,
+        Err(err) => {
+            tracing::error!(error = ?err, "Error while hashing refresh token");
+            return HttpResponse::InternalServerError().finish();
+        }
+    };
+    let pool: Pool = db_pool.clone().into_inner().as_ref().clone();
+    let refresh_token_db = {
+        let check_refresh_token_span = tracing::info_span!("Check if refresh token exist");
+        match async move {
+            match RefreshToken::get_one_by_token(pool.clone(), token_hash).await {
+                Ok(found_token) => {
+                    tracing::debug!(token = ?found_token.token_prefix ,"Refresh token found");
+                    Ok(found_token)
+                }
+                Err(err) => {
+                    tracing::error!(error = ?err ,"Error while getting refresh token");
+                    Err(HttpResponse::Unauthorized().finish())
+                }
+            }
+        }
+        .instrument(check_refresh_token_span)
+        .await
+        {
+            Ok(found_token) => found_token,
+            Err(err) => return err,
+        }
+    };
+    let user_id = refresh_token_db.user_id;
+    if refresh_token_db.consumed_at.is_some() {
+        let pool_reuse: Pool = db_pool.clone().into_inner().as_ref().clone();
+        let revoke_family_span = tracing::info_span!("Revoke reused refresh token family");
+        return async move {
+            tracing::warn!(
+                security_event = "refresh_token_reuse",
+                user = ?user_id,
+                family = ?refresh_token_db.family_id,
+                "Reuse of a consumed refresh token, revoking the whole token family"
+            );
+            if let Err(err) =
+                RefreshToken::delete_family(pool_reuse.clone(), refresh_token_db.family_id).await
+            {
+                tracing::error!(error = ?err, "Error while revoking refresh token family");
+                return HttpResponse::InternalServerError().finish();
+            }
+            let lifetime = match User::get_one(pool_reuse.clone(), user_id).await {
+                Ok(user) => token_lifetime.for_user(&user),
+                Err(err) => {
+                    tracing::error!(error = ?err, user = ?user_id, "Error while getting user");
+                    return HttpResponse::InternalServerError().finish();
+                }
+            };
+            if let Err(err) = token_denylist
+                .revoke(
+                    pool_reuse,
+                    RevocationKind::Session,
+                    refresh_token_db.family_id,
+                    &lifetime,
+                )
+                .await
+            {
+                tracing::error!(error = ?err, "Error while revoking session access token");
+                return HttpResponse::InternalServerError().finish();
+            }
+            HttpResponse::Unauthorized()
+                .content_type(ContentType::plaintext())
+                .body("Refresh token already used")
+        }
+        .instrument(revoke_family_span)
+        .await;
+    }
+    let delete_refresh_span = tracing::info_span!("Delete every refresh token of the user");
+    let pool_delete: Pool = db_pool.clone().into_inner().as_ref().clone();
+    let pool_revoke: Pool = db_pool.into_inner().as_ref().clone();
+    {
+        match async move {
+            match RefreshToken::delete_all_by_user(pool_delete.clone(), user_id).await {
+                Ok(nbr) => {
+                    tracing::debug!(user = ?user_id, nbr_token = nbr, "Every refresh token deleted");
+                    Ok(())
+                }
+                Err(err) => {
+                    tracing::error!(error = ?err, user = ?user_id, "Error while deleting refresh tokens");
+                    Err(HttpResponse::InternalServerError().finish())
+                }
+            }
+        }
+        .instrument(delete_refresh_span.clone())
+        .await
+        {
+            Ok(_) => {}
+            Err(err) => return err,
+        };
+    }
+    let revoke_access_span = tracing::info_span!("Revoke access token of the user");
+    {
+        match async move {
+            let lifetime = match User::get_one(pool_revoke.clone(), user_id).await {
+                Ok(user) => token_lifetime.for_user(&user),
+                Err(err) => {
+                    tracing::error!(error = ?err, user = ?user_id, "Error while getting user");
+                    return Err(HttpResponse::InternalServerError().finish());
+                }
+            };
+            match token_denylist
+                .revoke(pool_revoke, RevocationKind::User, user_id, &lifetime)
+                .await
+            {
+                Ok(_) => {
+                    tracing::debug!(user = ?user_id, "User access token revoked");
+                    Ok(())
+                }
+                Err(err) => {
+                    tracing::error!(error = ?err, user = ?user_id, "Error while revoking user access token");
+                    Err(HttpResponse::InternalServerError().finish())
+                }
+            }
+        }
+        .instrument(revoke_access_span)
+        .await
+        {
+            Ok(_) => {}
+            Err(err) => return err,
+        };
+    }
+    HttpResponse::Ok()
+        .content_type(ContentType::plaintext())
+        .body("User Disconnected from every session")
+}
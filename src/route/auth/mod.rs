@@ -1,8 +1,14 @@
+pub mod device;
+pub mod email;
 pub mod info;
 pub mod init;
 pub mod login;
 pub mod logout;
+pub mod logout_all;
 pub mod otp;
+pub mod password;
 pub mod refresh;
 pub mod register;
 pub mod register_oidc;
+pub mod session;
+pub mod webauthn;
@@ -1,4 +1,5 @@
-use crate::model::user::User;
+use super::recovery_codes::RecoveryCodes;
+use crate::model::{otp::OtpConfig, recovery_code::RecoveryCode, user::User};
 use actix_web::{post, web, HttpResponse, Responder};
 use deadpool_postgres::Pool;
 use serde::{Deserialize, Serialize};
@@ -11,18 +12,20 @@ pub struct ActivateOtp {
 }
 
 /// End the totp activate process
+///
+/// Return the recovery codes of the user, they are only shown once
 #[utoipa::path(
   tag = "Auth>Otp",
   request_body = ActivateOtp,
   operation_id = "activate",
   path = "/api/auth/otp/activate",
   responses(
-      (status = 200, description = "Success"),
+      (status = 200, description = "Success", body = RecoveryCodes),
       (status = 400, description = "Bad request"),
       (status = 500, description = "Internal server error"),
   ),
   params(
-    ("Authorization-type" = AuthType, Header, description = "Type de token (oidc ou buildin)")
+    ("Authorization-type" = Option<AuthType>, Header, description = "Type de token (oidc ou buildin), détecté depuis le token si absent")
   ),
   security(
     ("access_token" = [])
@@ -32,6 +35,7 @@ pub struct ActivateOtp {
 pub async fn activate_otp(
     mut user: User,
     db_pool: web::Data<Pool>,
+    otp_config: web::Data<OtpConfig>,
     activate_otp: web::Json<ActivateOtp>,
 ) -> impl Responder {
     tracing::debug!(user = ?user.email ,"User found, starting otp final activation");
@@ -44,7 +48,13 @@ pub async fn activate_otp(
         return HttpResponse::BadRequest().finish();
     }
     let body = activate_otp.into_inner();
-    match user.validate_otp(body.otp_code) {
+    let pool: Pool = db_pool.into_inner().as_ref().clone();
+    let validate_otp_span = tracing::info_span!("Validate otp");
+    match user
+        .validate_otp(pool.clone(), &otp_config, body.otp_code)
+        .instrument(validate_otp_span)
+        .await
+    {
         Ok(status) => {
             if !status {
                 tracing::debug!(user = ?user.email ,"User otp code is invalid");
@@ -60,7 +70,7 @@ pub async fn activate_otp(
     user.otp_enabled = true;
     let update_otp_span = tracing::info_span!("Update user otp");
     match {
-        let pool_swap = db_pool.into_inner().as_ref().clone();
+        let pool_swap = pool.clone();
         let user_swap = user.clone();
         async move {
             match user_swap.update_otp_secret_url_token_enabled(pool_swap.clone()).await {
@@ -79,5 +89,29 @@ pub async fn activate_otp(
         Err(err) => return err,
     }
 
-    HttpResponse::Ok().body("")
+    let recovery_codes = RecoveryCode::generate_codes();
+    let save_recovery_codes_span = tracing::info_span!("Save recovery codes");
+    match {
+        let recovery_codes_swap = recovery_codes.clone();
+        async move {
+            match RecoveryCode::replace_all(pool, user.id, &recovery_codes_swap).await {
+                Ok(_) => {
+                    tracing::debug!(user = ?user.email ,"Recovery codes saved");
+                    Ok(())
+                }
+                Err(err) => {
+                    tracing::error!(error = ?err,user = ?user.email ,"Error while saving recovery codes");
+                    Err(HttpResponse::InternalServerError().finish())
+                }
+            }
+        }
+        .instrument(save_recovery_codes_span)
+    }
+    .await
+    {
+        Ok(_) => (),
+        Err(err) => return err,
+    }
+
+    HttpResponse::Ok().json(RecoveryCodes { recovery_codes })
 }
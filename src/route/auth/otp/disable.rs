@@ -0,0 +1,137 @@
+use crate::helper::header;
+use crate::model::{
+    login_attempt::LoginAttemptConfig, otp::OtpConfig, revocation::TokenDenylist,
+    token::TokenLifetime, user::User,
+};
+use crate::route::auth::login::{release_login_attempt, reserve_login_attempt};
+use actix_web::{delete, http::header::ContentType, web, HttpRequest, HttpResponse, Responder};
+use deadpool_postgres::Pool;
+use serde::{Deserialize, Serialize};
+use tracing::Instrument;
+use utoipa::ToSchema;
+
+#[derive(Serialize, Deserialize, ToSchema, Clone)]
+pub struct DisableOtp {
+    // Only one of the otp code or the password is needed
+    pub otp_code: Option<String>,
+    pub password: Option<String>,
+}
+
+/// Disable the otp
+///
+/// Remove the otp and the recovery codes of the user, every session is revoked.
+/// A wrong otp code or password count as a failed login, the account can be locked.
+#[utoipa::path(
+  tag = "Auth>Otp",
+  request_body = DisableOtp,
+  operation_id = "disableotp",
+  path = "/api/auth/otp",
+  responses(
+      (status = 200, description = "Success", body = String),
+      (status = 400, description = "Bad request"),
+      (status = 423, description = "Too many failed attempts", body = LoginLocked),
+      (status = 500, description = "Internal server error"),
+  ),
+  params(
+    ("Authorization-type" = Option<AuthType>, Header, description = "Type de token (oidc ou buildin), détecté depuis le token si absent")
+  ),
+  security(
+    ("access_token" = [])
+  )
+)]
+#[delete("")]
+#[allow(clippy::too_many_arguments)]
+pub async fn disable_otp(
+    req: HttpRequest,
+    mut user: User,
+    db_pool: web::Data<Pool>,
+    otp_config: web::Data<OtpConfig>,
+    token_lifetime: web::Data<TokenLifetime>,
+    token_denylist: web::Data<TokenDenylist>,
+    login_attempt_config: web::Data<LoginAttemptConfig>,
+    disable_body: web::Json<DisableOtp>,
+) -> impl Responder {
+    tracing::debug!(user = ?user.email ,"Disabling otp");
+    if !user.otp_enabled {
+        tracing::debug!(user = ?user.email ,"Otp not enabled");
+        return HttpResponse::BadRequest().finish();
+    }
+    let body = disable_body.into_inner();
+    let pool: Pool = db_pool.into_inner().as_ref().clone();
+    let (_, ip) = header::extract_client_info(&req);
+    let reservation = match reserve_login_attempt(
+        pool.clone(),
+        &login_attempt_config,
+        &user.email,
+        ip.as_deref(),
+    )
+    .await
+    {
+        Ok(reservation) => reservation,
+        Err(err) => return err,
+    };
+    let valid = match (body.otp_code, body.password) {
+        (Some(otp_code), None) => match user
+            .validate_otp(pool.clone(), &otp_config, otp_code)
+            .instrument(tracing::info_span!("Validate otp"))
+            .await
+        {
+            Ok(status) => status,
+            Err(err) => {
+                tracing::error!(error = ?err,user = ?user.email ,"Error while validating otp");
+                return HttpResponse::InternalServerError().finish();
+            }
+        },
+        (None, Some(password)) => match user.compare_password(password).await {
+            Ok(status) => status,
+            Err(err) => {
+                tracing::error!(error = ?err,user = ?user.email ,"Error while comparing password");
+                return HttpResponse::InternalServerError().finish();
+            }
+        },
+        _ => {
+            tracing::debug!(user = ?user.email ,"Either an otp code or a password is required");
+            release_login_attempt(pool.clone(), &reservation).await;
+            return HttpResponse::BadRequest().finish();
+        }
+    };
+    if !valid {
+        tracing::debug!(user = ?user.email ,"User otp code or password is invalid");
+        return HttpResponse::BadRequest().finish();
+    }
+    release_login_attempt(pool.clone(), &reservation).await;
+
+    let disable_otp_span = tracing::info_span!("Disable otp and revoke sessions");
+    match {
+        async move {
+            if let Err(err) = user.disable_otp(pool.clone()).await {
+                tracing::error!(error = ?err,user = ?user.email ,"Error while disabling otp");
+                return Err(HttpResponse::InternalServerError().finish());
+            }
+            let lifetime = token_lifetime.for_user(&user);
+            match token_denylist
+                .revoke_all_sessions(pool, user.id, &lifetime)
+                .await
+            {
+                Ok(nbr) => {
+                    tracing::debug!(user = ?user.email, nbr_token = nbr ,"Otp disabled and sessions revoked");
+                    Ok(())
+                }
+                Err(err) => {
+                    tracing::error!(error = ?err,user = ?user.email ,"Error while revoking sessions");
+                    Err(HttpResponse::InternalServerError().finish())
+                }
+            }
+        }
+        .instrument(disable_otp_span)
+    }
+    .await
+    {
+        Ok(_) => (),
+        Err(err) => return err,
+    }
+
+    HttpResponse::Ok()
+        .content_type(ContentType::plaintext())
+        .body("Otp disabled")
+}
@@ -1,4 +1,4 @@
-use crate::model::user::User;
+use crate::model::{otp::OtpConfig, user::User};
 use actix_web::{get, web, HttpResponse, Responder};
 use deadpool_postgres::Pool;
 use serde::{Deserialize, Serialize};
@@ -22,14 +22,18 @@ pub struct GenOtp {
       (status = 500, description = "Internal server error"),
   ),
   params(
-    ("Authorization-type" = AuthType, Header, description = "Type de token (oidc ou buildin)")
+    ("Authorization-type" = Option<AuthType>, Header, description = "Type de token (oidc ou buildin), détecté depuis le token si absent")
   ),
   security(
     ("access_token" = [])
   )
 )]
 #[get("/activate")]
-pub async fn generate_otp(mut user: User, db_pool: web::Data<Pool>) -> impl Responder {
+pub async fn generate_otp(
+    mut user: User,
+    db_pool: web::Data<Pool>,
+    otp_config: web::Data<OtpConfig>,
+) -> impl Responder {
     tracing::debug!(user = ?user.email ,"User found, starting otp generation");
     if user.otp_enabled {
         tracing::debug!(user = ?user.email ,"User already has otp enabled");
@@ -39,15 +43,17 @@ pub async fn generate_otp(mut user: User, db_pool: web::Data<Pool>) -> impl Resp
         tracing::debug!(user = ?user.email ,"User is oauth");
         return HttpResponse::BadRequest().finish();
     }
-    user.gen_otp_secret();
-    let otp_object = match user.get_totp_obj() {
+    if let Err(err) = user.gen_otp_secret(&otp_config) {
+        tracing::error!(error = ?err,user = ?user.email ,"Error while generating otp secret");
+        return HttpResponse::InternalServerError().finish();
+    }
+    let otp_object = match user.get_totp_obj(&otp_config) {
         Ok(otp) => otp,
         Err(err) => {
             tracing::error!(error = ?err,user = ?user.email ,"Error while getting totp user");
             return HttpResponse::InternalServerError().finish();
         }
     };
-    user.otp_url = Some(otp_object.get_url());
     let update_otp_span = tracing::info_span!("Update user otp");
     match {
         let pool_swap = db_pool.into_inner().as_ref().clone();
@@ -1,10 +1,12 @@
 use actix_web::{web, Scope};
 
-use super::{activate, generate, validate};
+use super::{activate, disable, generate, recovery_codes, validate};
 
 pub fn init_otp() -> Scope {
     web::scope("/otp")
         .service(generate::generate_otp)
         .service(activate::activate_otp)
         .service(validate::validate_otp)
+        .service(recovery_codes::regenerate_recovery_codes)
+        .service(disable::disable_otp)
 }
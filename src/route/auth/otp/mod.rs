@@ -1,4 +1,6 @@
 pub mod activate;
+pub mod disable;
 pub mod generate;
 pub mod init;
+pub mod recovery_codes;
 pub mod validate;
@@ -0,0 +1,96 @@
+use crate::model::{otp::OtpConfig, recovery_code::RecoveryCode, user::User};
+use actix_web::{post, web, HttpResponse, Responder};
+use deadpool_postgres::Pool;
+use serde::{Deserialize, Serialize};
+use tracing::Instrument;
+use utoipa::ToSchema;
+
+#[derive(Serialize, Deserialize, ToSchema, Clone)]
+pub struct RecoveryCodes {
+    pub recovery_codes: Vec<String>,
+}
+
+#[derive(Serialize, Deserialize, ToSchema, Clone)]
+pub struct RegenerateRecoveryCodes {
+    pub otp_code: String,
+}
+
+/// Regenerate the otp recovery codes
+///
+/// The previous codes can no longer be used, the new ones are only shown once
+#[utoipa::path(
+  tag = "Auth>Otp",
+  request_body = RegenerateRecoveryCodes,
+  operation_id = "regeneraterecoverycodes",
+  path = "/api/auth/otp/recovery-codes",
+  responses(
+      (status = 200, description = "Success", body = RecoveryCodes),
+      (status = 400, description = "Bad request"),
+      (status = 500, description = "Internal server error"),
+  ),
+  params(
+    ("Authorization-type" = Option<AuthType>, Header, description = "Type de token (oidc ou buildin), détecté depuis le token si absent")
+  ),
+  security(
+    ("access_token" = [])
+  )
+)]
+#[post("/recovery-codes")]
+pub async fn regenerate_recovery_codes(
+    mut user: User,
+    db_pool: web::Data<Pool>,
+    otp_config: web::Data<OtpConfig>,
+    regenerate_body: web::Json<RegenerateRecoveryCodes>,
+) -> impl Responder {
+    tracing::debug!(user = ?user.email ,"Regenerating otp recovery codes");
+    if !user.otp_enabled {
+        tracing::debug!(user = ?user.email ,"Otp not enabled");
+        return HttpResponse::BadRequest().finish();
+    }
+    let body = regenerate_body.into_inner();
+    let pool: Pool = db_pool.into_inner().as_ref().clone();
+    let validate_otp_span = tracing::info_span!("Validate otp");
+    match user
+        .validate_otp(pool.clone(), &otp_config, body.otp_code)
+        .instrument(validate_otp_span)
+        .await
+    {
+        Ok(status) => {
+            if !status {
+                tracing::debug!(user = ?user.email ,"User otp code is invalid");
+                return HttpResponse::BadRequest().finish();
+            }
+        }
+        Err(err) => {
+            tracing::error!(error = ?err,user = ?user.email ,"Error while validating otp");
+            return HttpResponse::InternalServerError().finish();
+        }
+    }
+
+    let recovery_codes = RecoveryCode::generate_codes();
+    let save_recovery_codes_span = tracing::info_span!("Save recovery codes");
+    match {
+        let pool_swap = pool.clone();
+        let recovery_codes_swap = recovery_codes.clone();
+        async move {
+            match RecoveryCode::replace_all(pool_swap, user.id, &recovery_codes_swap).await {
+                Ok(_) => {
+                    tracing::debug!(user = ?user.email ,"Recovery codes saved");
+                    Ok(())
+                }
+                Err(err) => {
+                    tracing::error!(error = ?err,user = ?user.email ,"Error while saving recovery codes");
+                    Err(HttpResponse::InternalServerError().finish())
+                }
+            }
+        }
+        .instrument(save_recovery_codes_span)
+    }
+    .await
+    {
+        Ok(_) => (),
+        Err(err) => return err,
+    }
+
+    HttpResponse::Ok().json(RecoveryCodes { recovery_codes })
+}
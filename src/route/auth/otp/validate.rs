@@ -1,7 +1,15 @@
-use crate::model::token::{self, RefreshToken, TokenClaims};
+use crate::helper::header;
+use crate::model::login_attempt::LoginAttemptConfig;
+use crate::model::otp::OtpConfig;
+use crate::model::recovery_code::RecoveryCode;
+use crate::model::token::{self, RefreshToken, TokenClaims, TokenKeys, TokenLifetime};
+use crate::model::trusted_device::{TrustedDevice, TrustedDeviceConfig};
 use crate::model::user::User;
-use crate::route::auth::login::{LoginStatus, LoginUserReturn};
-use actix_web::{post, web, HttpResponse, Responder};
+use crate::route::auth::login::{
+    release_login_attempt, reserve_login_attempt, reset_login_failures, LoginStatus,
+    LoginUserReturn,
+};
+use actix_web::{post, web, HttpRequest, HttpResponse, Responder};
 use deadpool_postgres::Pool;
 use serde::{Deserialize, Serialize};
 use tracing::Instrument;
@@ -9,11 +17,19 @@ use utoipa::ToSchema;
 
 #[derive(Serialize, Deserialize, ToSchema, Clone)]
 pub struct ValidateOtp {
-    pub otp_code: String,
+    // Either a totp code or one of the recovery code
+    pub otp_code: Option<String>,
+    pub recovery_code: Option<String>,
     pub one_time_token: String,
+    // Skip the otp step on the next logins from this device
+    pub trust_device: Option<bool>,
 }
 
 /// End the auth process
+///
+/// A recovery code can be used instead of the totp code, each recovery code can only be used once.
+/// With trust_device a device token is returned, sent with the next logins it skip the otp step.
+/// The failed attempts are counted with the ones of the login, a 423 is returned once locked
 #[utoipa::path(
   tag = "Auth>Otp",
   request_body = ValidateOtp,
@@ -22,12 +38,20 @@ pub struct ValidateOtp {
   responses(
       (status = 200, description = "Success", body = LoginUserReturn),
       (status = 400, description = "Bad request"),
+      (status = 423, description = "Too many failed attempts", body = LoginLocked),
       (status = 500, description = "Internal server error"),
   )
 )]
 #[post("/validate")]
+#[allow(clippy::too_many_arguments)]
 pub async fn validate_otp(
+    req: HttpRequest,
     db_pool: web::Data<Pool>,
+    token_keys: web::Data<TokenKeys>,
+    token_lifetime: web::Data<TokenLifetime>,
+    otp_config: web::Data<OtpConfig>,
+    trusted_device_config: web::Data<TrustedDeviceConfig>,
+    login_attempt_config: web::Data<LoginAttemptConfig>,
     activate_otp: web::Json<ValidateOtp>,
 ) -> impl Responder {
     let body = activate_otp.into_inner();
@@ -62,21 +86,85 @@ pub async fn validate_otp(
         tracing::debug!(user = ?user.email ,"User is oauth");
         return HttpResponse::BadRequest().finish();
     }
+    let (user_agent, ip) = header::extract_client_info(&req);
+    let reservation = match reserve_login_attempt(
+        pool.clone(),
+        &login_attempt_config,
+        &user.email,
+        ip.as_deref(),
+    )
+    .await
+    {
+        Ok(reservation) => reservation,
+        Err(err) => return err,
+    };
 
-    match user.validate_otp(body.otp_code.clone()) {
-        Ok(status) => {
-            if !status {
-                tracing::debug!(user = ?user.email ,"User otp code is invalid");
-                return HttpResponse::BadRequest().finish();
+    let valid = match (body.otp_code.clone(), body.recovery_code.clone()) {
+        (Some(otp_code), None) => match user
+            .validate_otp(pool.clone(), &otp_config, otp_code)
+            .instrument(tracing::info_span!("Validate otp"))
+            .await
+        {
+            Ok(status) => status,
+            Err(err) => {
+                tracing::error!(error = ?err,user = ?user.email ,"Error while validating otp");
+                return HttpResponse::InternalServerError().finish();
+            }
+        },
+        (None, Some(recovery_code)) => {
+            let consume_recovery_code_span = tracing::info_span!("Consume recovery code");
+            match {
+                let pool_swap = pool.clone();
+                let user_swap = user.clone();
+                async move {
+                    match RecoveryCode::consume(pool_swap, user_swap.id, &recovery_code).await {
+                        Ok(status) => {
+                            tracing::debug!(user = ?user_swap.email, valid = status ,"Recovery code checked");
+                            Ok(status)
+                        }
+                        Err(err) => {
+                            tracing::error!(error = ?err,user = ?user_swap.email ,"Error while consuming recovery code");
+                            Err(HttpResponse::InternalServerError().finish())
+                        }
+                    }
+                }
+                .instrument(consume_recovery_code_span)
+            }
+            .await
+            {
+                Ok(status) => status,
+                Err(err) => return err,
             }
         }
-        Err(err) => {
-            tracing::error!(error = ?err,user = ?user.email ,"Error while validating otp");
-            return HttpResponse::InternalServerError().finish();
+        _ => {
+            tracing::debug!(user = ?user.email ,"Either an otp code or a recovery code is needed");
+            release_login_attempt(pool.clone(), &reservation).await;
+            return HttpResponse::BadRequest().finish();
+        }
+    };
+    if !valid {
+        tracing::debug!(user = ?user.email ,"User otp or recovery code is invalid");
+        let fail_one_time_token_span = tracing::info_span!("Count failed attempt");
+        match user
+            .fail_one_time_token(pool.clone(), otp_config.one_time_token_max_attempts)
+            .instrument(fail_one_time_token_span)
+            .await
+        {
+            Ok(true) => (),
+            Ok(false) => {
+                tracing::debug!(user = ?user.email ,"Too many failed attempts, one time token invalidated")
+            }
+            Err(err) => {
+                tracing::error!(error = ?err,user = ?user.email ,"Error while counting failed attempt");
+                return HttpResponse::InternalServerError().finish();
+            }
         }
+        return HttpResponse::BadRequest().finish();
     }
 
-    user.one_time_token = None;
+    release_login_attempt(pool.clone(), &reservation).await;
+    reset_login_failures(pool.clone(), &user.email).await;
+    user.clear_one_time_token();
     let update_otp_span = tracing::info_span!("Update user otp");
     match {
         let pool_swap = pool.clone();
@@ -103,7 +191,15 @@ pub async fn validate_otp(
         "User otp logged in, generating refresh_token"
     );
 
-    let refresh_token = match TokenClaims::new_tokens(user.id, user.email.clone(), true) {
+    let lifetime = token_lifetime.for_user(&user);
+    let refresh_token = match TokenClaims::new_tokens(
+        user.id,
+        user.email.clone(),
+        true,
+        &token_keys,
+        &lifetime,
+        chrono::Utc::now(),
+    ) {
         Ok(token) => token,
         Err(err) => {
             tracing::error!(error = ?err,user = user.email.clone() ,"Error while generating token");
@@ -111,18 +207,63 @@ pub async fn validate_otp(
         }
     };
 
-    let refresh_token_db = RefreshToken {
-        created_at: chrono::Utc::now(),
-        user_id: user.id,
-        token: refresh_token.clone(),
+    let device_token = if body.trust_device.unwrap_or(false) {
+        let trust_device_span = tracing::info_span!("Trust device");
+        match {
+            let pool_swap = pool.clone();
+            let user_swap = user.clone();
+            let user_agent = user_agent.clone();
+            let ip = ip.clone();
+            async move {
+                match TrustedDevice::create(
+                    pool_swap,
+                    user_swap.id,
+                    trusted_device_config.lifetime,
+                    user_agent,
+                    ip,
+                )
+                .await
+                {
+                    Ok(device_id) => {
+                        tracing::debug!(user = ?user_swap.email ,"Device trusted");
+                        trusted_device_config
+                            .device_token(user_swap.id, device_id)
+                            .map_err(|err| {
+                                tracing::error!(error = ?err,user = ?user_swap.email ,"Error while signing device token");
+                                HttpResponse::InternalServerError().finish()
+                            })
+                    }
+                    Err(err) => {
+                        tracing::error!(error = ?err,user = ?user_swap.email ,"Error while trusting device");
+                        Err(HttpResponse::InternalServerError().finish())
+                    }
+                }
+            }
+            .instrument(trust_device_span)
+        }
+        .await
+        {
+            Ok(device_token) => Some(device_token),
+            Err(err) => return err,
+        }
+    } else {
+        None
     };
+    let refresh_token_db =
+        match RefreshToken::new(user.id, &refresh_token, &token_keys, user_agent, ip) {
+            Ok(token) => token,
+            Err(err) => {
+                tracing::error!(error = ?err, "Error while hashing refresh token");
+                return HttpResponse::InternalServerError().finish();
+            }
+        };
 
     {
         let pool_swap = pool.clone();
         let body_swap = body.clone();
         let delete_old_token_span = tracing::info_span!("Delete old refresh token");
         if let Err(err_response) = async move{
-            match token::RefreshToken::keep_only_four_token(pool_swap, user.id).await {
+            match token::RefreshToken::keep_only_last_sessions(pool_swap, user.id, &lifetime).await {
                 Ok(_) => {
                     tracing::debug!(user = ?body_swap.one_time_token.clone() ,"Successfully deleted old refresh token");
                     Ok(())
@@ -161,5 +302,6 @@ pub async fn validate_otp(
         user: Some(user),
         token: Some(refresh_token),
         status: LoginStatus::RefreshStep,
+        device_token,
     })
 }
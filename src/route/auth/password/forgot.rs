@@ -0,0 +1,104 @@
+use crate::model::{
+    mailer::Mailer,
+    password_reset::{PasswordReset, PasswordResetConfig},
+    user::User,
+};
+use actix_web::{http::header::ContentType, post, web, HttpResponse, Responder};
+use deadpool_postgres::Pool;
+use serde::{Deserialize, Serialize};
+use tracing::Instrument;
+use utoipa::ToSchema;
+
+#[derive(Serialize, Deserialize, ToSchema, Clone)]
+pub struct ForgotPassword {
+    pub email: String,
+}
+
+/// Forgot password
+///
+/// Send a link to reset the password, the response is the same whether the email exist or not.
+/// A new link is sent at most once a minute and invalidate the previous one
+#[utoipa::path(
+  tag = "Auth>Password",
+  request_body = ForgotPassword,
+  operation_id = "forgotpassword",
+  path = "/api/auth/password/forgot",
+  responses(
+      (status = 200, description = "Success", body = String),
+      (status = 500, description = "Internal server error"),
+  )
+)]
+#[post("/forgot")]
+pub async fn forgot_password(
+    db_pool: web::Data<Pool>,
+    mailer: web::Data<Mailer>,
+    reset_config: web::Data<PasswordResetConfig>,
+    forgot_body: web::Json<ForgotPassword>,
+) -> impl Responder {
+    let body = forgot_body.into_inner();
+    let pool: Pool = db_pool.into_inner().as_ref().clone();
+    let find_user_span = tracing::info_span!("Find user");
+    let user = match {
+        let pool_swap = pool.clone();
+        let body_swap = body.clone();
+        async move {
+            match User::get_one_by_mail(pool_swap, body_swap.email.clone()).await {
+                Ok(Some(user)) if !user.is_oauth => Ok(Some(user)),
+                Ok(_) => {
+                    tracing::debug!(user = ?body_swap.email ,"User not found or oauth");
+                    Ok(None)
+                }
+                Err(err) => {
+                    tracing::error!(error = ?err,user = ?body_swap.email ,"Error while getting user");
+                    Err(HttpResponse::InternalServerError().finish())
+                }
+            }
+        }
+        .instrument(find_user_span)
+    }
+    .await
+    {
+        Ok(user) => user,
+        Err(err) => return err,
+    };
+
+    // The token is created and sent in the background so the response time doesn't
+    // tell if the user exist
+    if let Some(user) = user {
+        let send_mail_span = tracing::info_span!("Create reset token and send mail");
+        let lifetime = reset_config.lifetime;
+        let reset_config = reset_config.into_inner();
+        actix_web::rt::spawn(
+            async move {
+                let token = match PasswordReset::create(pool, user.id, lifetime).await {
+                    Ok(Some(token)) => token,
+                    Ok(None) => {
+                        tracing::debug!(user = ?user.email ,"Reset mail sent less than a minute ago");
+                        return;
+                    }
+                    Err(err) => {
+                        tracing::error!(error = ?err,user = ?user.email ,"Error while creating reset token");
+                        return;
+                    }
+                };
+                let content = format!(
+                    "Hello {},\n\nA password reset has been requested for your account, use the link below to choose a new password. It expires in {} minutes.\n\n{}\n\nIf you did not request it, you can ignore this mail.\n",
+                    user.prenom,
+                    lifetime / 60,
+                    reset_config.reset_link(&token)
+                );
+                match mailer.send(&user.email, "Reset your password", content).await {
+                    Ok(_) => tracing::debug!(user = ?user.email ,"Reset mail sent"),
+                    Err(err) => {
+                        tracing::error!(error = ?err,user = ?user.email ,"Error while sending reset mail")
+                    }
+                }
+            }
+            .instrument(send_mail_span),
+        );
+    }
+
+    HttpResponse::Ok()
+        .content_type(ContentType::plaintext())
+        .body("If the email exist a reset link has been sent")
+}
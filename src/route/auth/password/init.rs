@@ -0,0 +1,9 @@
+use actix_web::{web, Scope};
+
+use super::{forgot, reset};
+
+pub fn init_password() -> Scope {
+    web::scope("/password")
+        .service(forgot::forgot_password)
+        .service(reset::reset_password)
+}
@@ -0,0 +1,3 @@
+pub mod forgot;
+pub mod init;
+pub mod reset;
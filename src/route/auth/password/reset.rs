@@ -0,0 +1,159 @@
+use crate::model::{
+    login_attempt::LoginAttempt, password_hash::PasswordHashConfig,
+    password_policy::PasswordPolicy, password_reset::PasswordReset, revocation::TokenDenylist,
+    token::TokenLifetime, trusted_device::TrustedDevice, user::User,
+};
+use actix_web::{http::header::ContentType, post, web, HttpResponse, Responder};
+use deadpool_postgres::Pool;
+use serde::{Deserialize, Serialize};
+use tracing::Instrument;
+use utoipa::ToSchema;
+
+#[derive(Serialize, Deserialize, ToSchema, Clone)]
+pub struct ResetPassword {
+    // Token received by mail
+    pub token: String,
+    pub password: String,
+}
+
+/// Reset password
+///
+/// Set a new password with the token received by mail, the token can only be used once. Every session and trusted device is revoked
+/// and the failed logins of the account are forgotten
+#[utoipa::path(
+  tag = "Auth>Password",
+  request_body = ResetPassword,
+  operation_id = "resetpassword",
+  path = "/api/auth/password/reset",
+  responses(
+      (status = 200, description = "Success", body = String),
+      (status = 400, description = "Error message or failed password rules", body = PasswordPolicyError),
+      (status = 500, description = "Internal server error"),
+  )
+)]
+#[post("/reset")]
+pub async fn reset_password(
+    db_pool: web::Data<Pool>,
+    token_lifetime: web::Data<TokenLifetime>,
+    token_denylist: web::Data<TokenDenylist>,
+    password_policy: web::Data<PasswordPolicy>,
+    password_hash_config: web::Data<PasswordHashConfig>,
+    reset_body: web::Json<ResetPassword>,
+) -> impl Responder {
+    let body = reset_body.into_inner();
+    let pool: Pool = db_pool.into_inner().as_ref().clone();
+
+    let find_token_span = tracing::info_span!("Find reset token");
+    let mut user = match {
+        let pool_swap = pool.clone();
+        let token = body.token.clone();
+        async move {
+            let user_id = match PasswordReset::find(pool_swap.clone(), &token).await {
+                Ok(Some(user_id)) => user_id,
+                Ok(None) => {
+                    tracing::debug!("Reset token invalid or expired");
+                    return Err(HttpResponse::BadRequest()
+                        .content_type(ContentType::plaintext())
+                        .body("Token invalid or expired"));
+                }
+                Err(err) => {
+                    tracing::error!(error = ?err ,"Error while getting reset token");
+                    return Err(HttpResponse::InternalServerError().finish());
+                }
+            };
+            match User::get_one(pool_swap, user_id).await {
+                Ok(user) => Ok(user),
+                Err(err) => {
+                    tracing::error!(error = ?err,user = ?user_id ,"Error while getting user");
+                    Err(HttpResponse::BadRequest()
+                        .content_type(ContentType::plaintext())
+                        .body("Token invalid or expired"))
+                }
+            }
+        }
+        .instrument(find_token_span)
+    }
+    .await
+    {
+        Ok(user) => user,
+        Err(err) => return err,
+    };
+
+    // The token is only consumed once the new password is accepted
+    if let Err(failed_rules) = password_policy.validate(&body.password, &user.email).await {
+        tracing::debug!(user = ?user.email, failed_rules = ?failed_rules ,"Password not valid");
+        return HttpResponse::BadRequest().json(PasswordPolicy::error(failed_rules));
+    }
+    let consume_token_span = tracing::info_span!("Consume reset token");
+    match {
+        let pool_swap = pool.clone();
+        let user_swap = user.clone();
+        async move {
+            match PasswordReset::consume(pool_swap, &body.token).await {
+                Ok(Some(user_id)) if user_id == user_swap.id => Ok(()),
+                Ok(_) => {
+                    tracing::debug!(user = ?user_swap.email ,"Reset token already used");
+                    Err(HttpResponse::BadRequest()
+                        .content_type(ContentType::plaintext())
+                        .body("Token invalid or expired"))
+                }
+                Err(err) => {
+                    tracing::error!(error = ?err,user = ?user_swap.email ,"Error while consuming reset token");
+                    Err(HttpResponse::InternalServerError().finish())
+                }
+            }
+        }
+        .instrument(consume_token_span)
+    }
+    .await
+    {
+        Ok(_) => (),
+        Err(err) => return err,
+    }
+
+    if let Some(err) = user
+        .hash_password(body.password, &password_hash_config)
+        .await
+    {
+        tracing::error!(error = ?err,user = ?user.email ,"Error while hashing password");
+        return HttpResponse::InternalServerError().finish();
+    }
+
+    let update_password_span = tracing::info_span!("Update password and revoke sessions");
+    match async move {
+        if let Err(err) = user.update_password(pool.clone()).await {
+            tracing::error!(error = ?err,user = ?user.email ,"Error while updating password");
+            return Err(HttpResponse::InternalServerError().finish());
+        }
+        let lifetime = token_lifetime.for_user(&user);
+        let nbr = match token_denylist
+            .revoke_all_sessions(pool.clone(), user.id, &lifetime)
+            .await
+        {
+            Ok(nbr) => nbr,
+            Err(err) => {
+                tracing::error!(error = ?err,user = ?user.email ,"Error while revoking sessions");
+                return Err(HttpResponse::InternalServerError().finish());
+            }
+        };
+        // The devices trusted and the failed logins were tied to the previous password
+        if let Err(err) = TrustedDevice::delete_all_by_user(pool.clone(), user.id).await {
+            tracing::error!(error = ?err,user = ?user.email ,"Error while deleting trusted devices");
+            return Err(HttpResponse::InternalServerError().finish());
+        }
+        if let Err(err) = LoginAttempt::reset(pool, &user.email).await {
+            tracing::error!(error = ?err,user = ?user.email ,"Error while resetting failed logins");
+            return Err(HttpResponse::InternalServerError().finish());
+        }
+        tracing::debug!(user = ?user.email, nbr_token = nbr ,"Password reset and sessions revoked");
+        Ok(())
+    }
+    .instrument(update_password_span)
+    .await
+    {
+        Ok(_) => HttpResponse::Ok()
+            .content_type(ContentType::plaintext())
+            .body("Password reset"),
+        Err(err) => err,
+    }
+}
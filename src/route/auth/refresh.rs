@@ -7,33 +7,46 @@ use utoipa::ToSchema;
 
 use crate::{
     helper::header,
-    model::token::{self, RefreshToken, TokenClaims},
+    model::{
+        revocation::{RevocationKind, TokenDenylist},
+        token::{self, RefreshToken, TokenClaims, TokenKeys, TokenLifetime},
+        user::User,
+    },
 };
 
 #[derive(Serialize, Deserialize, ToSchema)]
 pub struct RefreshTokenReturn {
     pub access_token: String,
+    pub refresh_token: String,
 }
 
 /// Refresh token
 ///
-/// This endpoint is used to get access token from refresh token
+/// This endpoint is used to get access token from refresh token.
+/// The refresh token is consumed and replaced by the returned one, reusing a consumed refresh token revoke the whole session.
 #[utoipa::path(
   tag = "Auth",
   operation_id = "refresh",
   path = "/api/auth/refresh",
   responses(
-    (status = 200, description = "Token body", body = RefreshTokenReturn)
+    (status = 200, description = "Token body", body = RefreshTokenReturn),
+    (status = 401, description = "Invalid, revoked or reused refresh token")
   ),
   params(
-    ("Authorization-type" = AuthType, Header, description = "Type de token (oidc ou buildin)")
+    ("Authorization-type" = Option<AuthType>, Header, description = "Type de token (oidc ou buildin), détecté depuis le token si absent")
   ),
   security(
     ("refresh_token" = [])
   )
 )]
 #[get("/refresh")]
-pub async fn refresh(req: HttpRequest, db_pool: web::Data<Pool>) -> impl Responder {
+pub async fn refresh(
+    req: HttpRequest,
+    db_pool: web::Data<Pool>,
+    token_keys: web::Data<TokenKeys>,
+    token_lifetime: web::Data<TokenLifetime>,
+    token_denylist: web::Data<TokenDenylist>,
+) -> impl Responder {
     let get_token_span = tracing::info_span!("Get Token in header");
     let (token, auth_type) =
         match get_token_span.in_scope(|| -> Result<(&str, AuthType), HttpResponse> {
@@ -51,7 +64,7 @@ pub async fn refresh(req: HttpRequest, db_pool: web::Data<Pool>) -> impl Respond
                 .content_type(ContentType::plaintext())
                 .body("Invalid token type"));
         }
-        match token::TokenClaims::validate_token(token.to_string(), true) {
+        match token::TokenClaims::validate_token(token.to_string(), true, &token_keys) {
             Ok(claim) => Ok(claim),
             Err(err) => {
                 tracing::error!(error = ?err, "Error while checking token");
@@ -65,17 +78,25 @@ pub async fn refresh(req: HttpRequest, db_pool: web::Data<Pool>) -> impl Respond
         Err(err) => return err,
     };
     drop(check_token_span);
+    let token_hash = match token_keys.hash_refresh_token(token) {
+        Ok(hash) => hash,
+        Err(err) => {
+            tracing::error!(error = ?err, "Error while hashing refresh token");
+            return HttpResponse::InternalServerError().finish();
+        }
+    };
     let pool: Pool = db_pool.into_inner().as_ref().clone();
-    {
+    let refresh_token_db = {
+        let pool_swap = pool.clone();
         let check_refresh_token_span = tracing::info_span!("Check if refresh token exist");
         match async move {
-            match RefreshToken::get_one_by_token(pool.clone(), token.to_string()).await {
+            match RefreshToken::get_one_by_token(pool_swap, token_hash).await {
                 Ok(found_token) => {
-                    tracing::debug!(token = ?found_token.token ,"Refresh token found");
+                    tracing::debug!(token = ?found_token.token_prefix ,"Refresh token found");
                     Ok(found_token)
                 }
                 Err(err) => {
-                    tracing::error!(error = ?err,token = ?token ,"Error while getting refresh token");
+                    tracing::error!(error = ?err ,"Error while getting refresh token");
                     Err(HttpResponse::Unauthorized().finish())
                 }
             }
@@ -83,15 +104,153 @@ pub async fn refresh(req: HttpRequest, db_pool: web::Data<Pool>) -> impl Respond
         .instrument(check_refresh_token_span)
         .await
         {
-            Ok(_) => {}
+            Ok(found_token) => found_token,
+            Err(err) => return err,
+        }
+    };
+
+    let lifetime = {
+        let pool_swap = pool.clone();
+        let refresh_token_swap = refresh_token_db.clone();
+        let check_session_span = tracing::info_span!("Check if session is expired");
+        match async move {
+            let user = match User::get_one(pool_swap.clone(), refresh_token_swap.user_id).await {
+                Ok(user) => user,
+                Err(err) => {
+                    tracing::error!(error = ?err, "Error while getting user");
+                    return Err(HttpResponse::Unauthorized().finish());
+                }
+            };
+            let lifetime = token_lifetime.for_user(&user);
+            if !lifetime.is_session_expired(refresh_token_swap.session_created_at) {
+                return Ok(lifetime);
+            }
+            tracing::debug!(family = ?refresh_token_swap.family_id, "Session expired");
+            if let Err(err) =
+                RefreshToken::delete_family(pool_swap, refresh_token_swap.family_id).await
+            {
+                tracing::error!(error = ?err, "Error while deleting expired session");
+                return Err(HttpResponse::InternalServerError().finish());
+            }
+            Err(HttpResponse::Unauthorized()
+                .content_type(ContentType::plaintext())
+                .body("Session expired"))
+        }
+        .instrument(check_session_span)
+        .await
+        {
+            Ok(lifetime) => lifetime,
             Err(err) => return err,
+        }
+    };
+
+    {
+        let pool_swap = pool.clone();
+        let refresh_token_swap = refresh_token_db.clone();
+        let lifetime_swap = lifetime.clone();
+        let consume_refresh_token_span = tracing::info_span!("Consume refresh token");
+        if let Err(err_response) = async move {
+            let consumed = if refresh_token_swap.consumed_at.is_some() {
+                0
+            } else {
+                match refresh_token_swap.clone().consume(pool_swap.clone()).await {
+                    Ok(consumed) => consumed,
+                    Err(err) => {
+                        tracing::error!(error = ?err, "Error while consuming refresh token");
+                        return Err(HttpResponse::InternalServerError().finish());
+                    }
+                }
+            };
+            if consumed != 0 {
+                tracing::debug!(family = ?refresh_token_swap.family_id, "Refresh token consumed");
+                return Ok(());
+            }
+            tracing::warn!(
+                security_event = "refresh_token_reuse",
+                user = ?refresh_token_swap.user_id,
+                family = ?refresh_token_swap.family_id,
+                "Reuse of a consumed refresh token, revoking the whole token family"
+            );
+            if let Err(err) =
+                RefreshToken::delete_family(pool_swap.clone(), refresh_token_swap.family_id).await
+            {
+                tracing::error!(error = ?err, "Error while revoking refresh token family");
+                return Err(HttpResponse::InternalServerError().finish());
+            }
+            if let Err(err) = token_denylist
+                .revoke(
+                    pool_swap,
+                    RevocationKind::Session,
+                    refresh_token_swap.family_id,
+                    &lifetime_swap,
+                )
+                .await
+            {
+                tracing::error!(error = ?err, "Error while revoking session access token");
+                return Err(HttpResponse::InternalServerError().finish());
+            }
+            Err(HttpResponse::Unauthorized()
+                .content_type(ContentType::plaintext())
+                .body("Refresh token already used"))
+        }
+        .instrument(consume_refresh_token_span)
+        .await
+        {
+            return err_response;
+        }
+    }
+
+    let new_refresh_token = match TokenClaims::new_tokens(
+        claims.sub,
+        claims.email.clone(),
+        true,
+        &token_keys,
+        &lifetime,
+        refresh_token_db.session_created_at,
+    ) {
+        Ok(token) => token,
+        Err(err) => {
+            tracing::error!(error = ?err, "Error while generating refresh token");
+            return HttpResponse::InternalServerError().finish();
+        }
+    };
+
+    let (user_agent, ip) = header::extract_client_info(&req);
+    let new_refresh_token_db =
+        match refresh_token_db.next(&new_refresh_token, &token_keys, user_agent, ip) {
+            Ok(token) => token,
+            Err(err) => {
+                tracing::error!(error = ?err, "Error while hashing refresh token");
+                return HttpResponse::InternalServerError().finish();
+            }
         };
+
+    {
+        let pool_swap = pool.clone();
+        let insert_new_token_span = tracing::info_span!("Insert new refresh token");
+        if let Err(err_response) = async move {
+            match new_refresh_token_db.create(pool_swap).await {
+                Ok(_) => {
+                    tracing::debug!("Refresh token saved to db");
+                    Ok(())
+                }
+                Err(err) => {
+                    tracing::error!(error = ?err, "Error while saving refresh token to db");
+                    Err(HttpResponse::InternalServerError().finish())
+                }
+            }
+        }
+        .instrument(insert_new_token_span)
+        .await
+        {
+            return err_response;
+        }
     }
 
     let sign_token_span = tracing::info_span!("Sign access token");
     let new_token = match sign_token_span.in_scope(|| -> Result<String, HttpResponse> {
-        claims.access_token();
-        match claims.sign_token() {
+        claims.access_token(&lifetime, &refresh_token_db);
+        match claims.sign_token(&token_keys) {
             Ok(token) => Ok(token),
             Err(err) => {
                 tracing::error!(error = ?err, "Error while signing token");
@@ -106,5 +265,6 @@ pub async fn refresh(req: HttpRequest, db_pool: web::Data<Pool>) -> impl Respond
 
     HttpResponse::Ok().json(RefreshTokenReturn {
         access_token: new_token,
+        refresh_token: new_refresh_token,
     })
 }
@@ -4,7 +4,18 @@ use serde::{Deserialize, Serialize};
 use tracing::Instrument;
 use utoipa::ToSchema;
 
-use crate::{helper, model::user::User};
+use crate::{
+    helper,
+    model::{
+        email_verification::EmailVerificationConfig,
+        mailer::Mailer,
+        password_hash::PasswordHashConfig,
+        password_policy::PasswordPolicy,
+        registration::{Invitation, RegistrationConfig},
+        user::User,
+    },
+    route::auth::email::resend::send_verification_mail,
+};
 
 #[derive(Serialize, Deserialize, ToSchema, Clone)]
 pub struct RegisterUser {
@@ -12,6 +23,8 @@ pub struct RegisterUser {
     pub password: String,
     pub nom: String,
     pub prenom: String,
+    // Required if the registration is invite only
+    pub invitation_code: Option<String>,
 }
 #[derive(Serialize, Deserialize, ToSchema)]
 pub struct RegisterUserReturn {
@@ -23,9 +36,12 @@ pub struct RegisterUserReturn {
 
 /// Register user
 ///
-/// Password must be between 3 and 20 characters long and contain at least one number, one lowercase and one uppercase letter.
+/// Password must follow the password policy, the failed rules are returned otherwise
+/// (`breached` if it appears in the breach corpus).
 /// Email must be a valid email.
 /// Name and surname must be at least 2 characters long.
+/// A link to verify the email is sent by mail.
+/// Depending on the configuration the registration can be closed, invite only or limited to some email domains.
 #[utoipa::path(
   tag = "Auth",
   request_body = RegisterUser,
@@ -33,7 +49,8 @@ pub struct RegisterUserReturn {
   path = "/api/auth/register",
   responses(
       (status = 200, description = "Register user", body = RegisterUserReturn),
-      (status = 400, description = "Error message"),
+      (status = 400, description = "Error message or failed password rules", body = PasswordPolicyError),
+      (status = 403, description = "Registration disabled"),
       (status = 500, description = "Internal server error"),
   )
 )]
@@ -41,8 +58,19 @@ pub struct RegisterUserReturn {
 pub async fn register(
     register_body: web::Json<RegisterUser>,
     db_pool: web::Data<Pool>,
+    password_policy: web::Data<PasswordPolicy>,
+    password_hash_config: web::Data<PasswordHashConfig>,
+    mailer: web::Data<Mailer>,
+    verification_config: web::Data<EmailVerificationConfig>,
+    registration_config: web::Data<RegistrationConfig>,
 ) -> impl Responder {
     let body = register_body.into_inner();
+    if !registration_config.can_register() {
+        tracing::debug!(user = ?body.email ,"Registration disabled");
+        return HttpResponse::Forbidden()
+            .content_type(ContentType::plaintext())
+            .body("Registration disabled");
+    }
     let pool: Pool = db_pool.into_inner().as_ref().clone();
     let check_user_span = tracing::info_span!("Check if user exist");
     {
@@ -72,43 +100,73 @@ pub async fn register(
 
     {
         let validate_input_span = tracing::info_span!("Validate input");
-        if let Err(err_response) =
-            validate_input_span.in_scope(|| -> Result<_, HttpResponse> {
-                if !helper::string_rule::validate_email(body.email.clone()) {
-                    tracing::debug!(user = ?body.email.clone() ,"Email not valid");
-                    return Err(HttpResponse::BadRequest()
-                .content_type(ContentType::plaintext())
-                .body("Email not valid(>=3,<=20, >= 1 number, >= 1 lowercase, >= 1 uppercase)"));
-                }
+        if let Err(err_response) = async {
+            if !helper::string_rule::validate_email(body.email.clone()) {
+                tracing::debug!(user = ?body.email.clone() ,"Email not valid");
+                return Err(HttpResponse::BadRequest()
+            .content_type(ContentType::plaintext())
+            .body("Email not valid(>=3,<=20, >= 1 number, >= 1 lowercase, >= 1 uppercase)"));
+            }
 
-                if !helper::string_rule::validate_password(body.password.clone()) {
-                    tracing::debug!(user = ?body.email.clone() ,"Password not valid");
-                    return Err(HttpResponse::BadRequest()
-                        .content_type(ContentType::plaintext())
-                        .body("Password not valid"));
-                }
+            if !registration_config.domain_allowed(&body.email) {
+                tracing::debug!(user = ?body.email.clone() ,"Email domain not allowed");
+                return Err(HttpResponse::BadRequest()
+                    .content_type(ContentType::plaintext())
+                    .body("Email domain not allowed"));
+            }
 
-                if !helper::string_rule::validate_name(body.nom.clone()) {
-                    tracing::debug!(user = ?body.email.clone() ,"Nom not valid");
-                    return Err(HttpResponse::BadRequest()
-                        .content_type(ContentType::plaintext())
-                        .body("Nom not valid"));
-                }
+            if let Err(failed_rules) = password_policy.validate(&body.password, &body.email).await {
+                tracing::debug!(user = ?body.email.clone(), failed_rules = ?failed_rules ,"Password not valid");
+                return Err(HttpResponse::BadRequest().json(PasswordPolicy::error(failed_rules)));
+            }
 
-                if !helper::string_rule::validate_name(body.prenom.clone()) {
-                    tracing::debug!(user = ?body.email.clone() ,"Prenom not valid");
-                    return Err(HttpResponse::BadRequest()
-                        .content_type(ContentType::plaintext())
-                        .body("Prenom not valid"));
-                }
-                Ok(())
-            })
+            if !helper::string_rule::validate_name(body.nom.clone()) {
+                tracing::debug!(user = ?body.email.clone() ,"Nom not valid");
+                return Err(HttpResponse::BadRequest()
+                    .content_type(ContentType::plaintext())
+                    .body("Nom not valid"));
+            }
+
+            if !helper::string_rule::validate_name(body.prenom.clone()) {
+                tracing::debug!(user = ?body.email.clone() ,"Prenom not valid");
+                return Err(HttpResponse::BadRequest()
+                    .content_type(ContentType::plaintext())
+                    .body("Prenom not valid"));
+            }
+            Ok(())
+        }
+        .instrument(validate_input_span)
+        .await
         {
             return err_response;
         }
     }
     tracing::debug!(user = ?body.email.clone() ,"Input valid");
 
+    let invitation_id = if registration_config.invitation_required() {
+        match body
+            .invitation_code
+            .as_deref()
+            .map(|code| registration_config.verify_invitation_code(code))
+        {
+            Some(Some(invitation_id)) => Some(invitation_id),
+            Some(None) => {
+                tracing::debug!(user = ?body.email ,"Invitation code invalid");
+                return HttpResponse::BadRequest()
+                    .content_type(ContentType::plaintext())
+                    .body("Invitation code invalid");
+            }
+            None => {
+                tracing::debug!(user = ?body.email ,"Invitation code required");
+                return HttpResponse::BadRequest()
+                    .content_type(ContentType::plaintext())
+                    .body("Invitation code required");
+            }
+        }
+    } else {
+        None
+    };
+
     let mut user = User {
         id: uuid::Uuid::new_v4(),
         email: body.email.clone(),
@@ -121,7 +179,20 @@ pub async fn register(
         otp_secret: None,
         otp_url: None,
         one_time_token: None,
+        one_time_token_expires_at: None,
+        one_time_token_attempts: 0,
         is_oauth: false,
+        access_token_lifetime: None,
+        session_idle_lifetime: None,
+        session_absolute_lifetime: None,
+        max_sessions: None,
+        is_admin: false,
+        otp_algorithm: None,
+        otp_digits: None,
+        otp_period: None,
+        otp_last_step: None,
+        otp_key_id: None,
+        email_verified: false,
     };
 
     let id = user.id;
@@ -129,25 +200,57 @@ pub async fn register(
     {
         let span =
             tracing::info_span!("Hash password (is meant to be slow  to prevent bruteforce)");
-        if let Err(res_hash) =span.in_scope(|| -> Result<_, HttpResponse> {
-            if let Some(err) = user.hash_password(body.password.clone()) {
+        if let Err(res_hash) = async {
+            if let Some(err) = user.hash_password(body.password.clone(), &password_hash_config).await {
             tracing::error!(error = ?err,user = ?body.email.clone() ,"Error while hashing password");
             return Err(HttpResponse::BadRequest()
             .content_type(ContentType::plaintext())
             .body("Error while hashing password"));
         }
         Ok(())
-        }) {
+        }
+        .instrument(span)
+        .await
+        {
             return res_hash;
         }
     }
 
+    if let Some(invitation_id) = invitation_id {
+        let consume_invitation_span = tracing::info_span!("Consume invitation");
+        match Invitation::consume(pool.clone(), invitation_id, &body.email, id)
+            .instrument(consume_invitation_span)
+            .await
+        {
+            Ok(true) => {
+                tracing::debug!(user = ?body.email, invitation = ?invitation_id ,"Invitation used")
+            }
+            Ok(false) => {
+                tracing::debug!(user = ?body.email, invitation = ?invitation_id ,"Invitation used, expired or for another email");
+                return HttpResponse::BadRequest()
+                    .content_type(ContentType::plaintext())
+                    .body("Invitation code invalid");
+            }
+            Err(err) => {
+                tracing::error!(error = ?err,user = ?body.email ,"Error while consuming invitation");
+                return HttpResponse::InternalServerError().finish();
+            }
+        }
+    }
+
+    let new_user = user.clone();
     {
         let insert_user_span = tracing::info_span!("Insert user");
         let body_swap = body.clone();
+        let pool = pool.clone();
         if let Err(err) = async move {
         if let Err(err) = user.create(pool.clone()).await {
             tracing::error!(error = ?err,user = ?body_swap.email.clone() ,"Error while creating user");
+            if let Some(invitation_id) = invitation_id {
+                if let Err(err) = Invitation::release(pool.clone(), invitation_id).await {
+                    tracing::error!(error = ?err,invitation = ?invitation_id ,"Error while releasing invitation");
+                }
+            }
             return Err(HttpResponse::InternalServerError()
                 .content_type(ContentType::plaintext())
                 .body("Error while creating user"));
@@ -162,6 +265,8 @@ pub async fn register(
     }
 
     tracing::debug!(user = ?body.email.clone(), uid = ?id ,"User created");
+    // The error is already logged, the user can ask a new mail
+    let _ = send_verification_mail(pool, mailer, &verification_config, new_user).await;
 
     HttpResponse::Ok().json(RegisterUserReturn {
         created: true,
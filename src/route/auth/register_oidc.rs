@@ -2,27 +2,47 @@
 
 use crate::{
     helper::header,
-    model::{oidc::Oidc, user::User},
+    model::{
+        oidc::Oidc,
+        registration::{Invitation, RegistrationConfig},
+        user::User,
+    },
     route::auth::info::AuthType,
 };
 use actix_web::{http::header::ContentType, post, web, HttpRequest, HttpResponse, Responder};
 use deadpool_postgres::Pool;
+use serde::{Deserialize, Serialize};
 use tracing::Instrument;
+use utoipa::ToSchema;
 
+#[derive(Serialize, Deserialize, ToSchema, Clone)]
+pub struct RegisterOidc {
+    // Required to create the user if the registration is invite only
+    pub invitation_code: Option<String>,
+}
+
+/// Register or update an oidc user
+///
+/// The user is created on its first call, following the registration mode and the
+/// allowed email domains like the register. The body is only needed to give an
+/// invitation code.
 #[utoipa::path(
     tag = "Auth",
     operation_id = "register_oidc",
     path = "/api/auth/register_oidc",
+    request_body = Option<RegisterOidc>,
     responses(
         (status = 200, description = "Register oidc user", body = User),
         (status = 500, description = "Possible internal server error", body = String),
-        (status = 401, description = "Access denied", body = String)
+        (status = 400, description = "Invitation code required or invalid", body = String),
+        (status = 401, description = "Access denied", body = String),
+        (status = 403, description = "Registration disabled or email domain not allowed", body = String)
     ),
     security(
         ("oidc" = [])
     ),
     params(
-        ("Authorization-type" = AuthType, Header, description = "Type de token (oidc ou buildin)")
+        ("Authorization-type" = Option<AuthType>, Header, description = "Type de token (oidc ou buildin), détecté depuis le token si absent")
     ),
 )]
 #[post("/register_oidc")]
@@ -30,6 +50,8 @@ pub async fn register_oidc(
     req: HttpRequest,
     db_pool: web::Data<Pool>,
     oidc_handler: web::Data<Oidc>,
+    registration_config: web::Data<RegistrationConfig>,
+    register_body: Option<web::Json<RegisterOidc>>,
 ) -> impl Responder {
     tracing::info!("Start auth validation");
     let get_token_span = tracing::info_span!("Auth: Get Token in header");
@@ -150,13 +172,71 @@ pub async fn register_oidc(
         }
         None => {
             tracing::debug!("User not found in database, proceed to create it");
+            let email = user_info["email"].to_string().replace('\"', "");
+            if !registration_config.can_register() {
+                tracing::debug!(user = ?email ,"Registration disabled");
+                return HttpResponse::Forbidden()
+                    .content_type(ContentType::plaintext())
+                    .body("Registration disabled");
+            }
+            if !registration_config.domain_allowed(&email) {
+                tracing::debug!(user = ?email ,"Email domain not allowed");
+                return HttpResponse::Forbidden()
+                    .content_type(ContentType::plaintext())
+                    .body("Email domain not allowed");
+            }
+            let id = uuid::Uuid::new_v4();
+            let invitation_id = if registration_config.invitation_required() {
+                let invitation_code =
+                    register_body.and_then(|body| body.into_inner().invitation_code);
+                let invitation_id = match invitation_code
+                    .as_deref()
+                    .map(|code| registration_config.verify_invitation_code(code))
+                {
+                    Some(Some(invitation_id)) => invitation_id,
+                    Some(None) => {
+                        tracing::debug!(user = ?email ,"Invitation code invalid");
+                        return HttpResponse::BadRequest()
+                            .content_type(ContentType::plaintext())
+                            .body("Invitation code invalid");
+                    }
+                    None => {
+                        tracing::debug!(user = ?email ,"Invitation code required");
+                        return HttpResponse::BadRequest()
+                            .content_type(ContentType::plaintext())
+                            .body("Invitation code required");
+                    }
+                };
+                let consume_invitation_span = tracing::info_span!("Consume invitation");
+                match Invitation::consume(pool.clone(), invitation_id, &email, id)
+                    .instrument(consume_invitation_span)
+                    .await
+                {
+                    Ok(true) => {
+                        tracing::debug!(user = ?email, invitation = ?invitation_id ,"Invitation used")
+                    }
+                    Ok(false) => {
+                        tracing::debug!(user = ?email, invitation = ?invitation_id ,"Invitation used, expired or for another email");
+                        return HttpResponse::BadRequest()
+                            .content_type(ContentType::plaintext())
+                            .body("Invitation code invalid");
+                    }
+                    Err(err) => {
+                        tracing::error!(error = ?err,user = ?email ,"Error while consuming invitation");
+                        return HttpResponse::InternalServerError().finish();
+                    }
+                }
+                Some(invitation_id)
+            } else {
+                None
+            };
             let span_create_user = tracing::info_span!("Create user");
 
             async move {
                 let pool = pool.clone();
                 let user = User {
-                    id: uuid::Uuid::new_v4(),
-                    email: user_info["email"].to_string().replace('\"', ""),
+                    id,
+                    email,
                     password: "".to_string(),
                     is_oauth: true,
                     nom: user_info["family_name"].to_string().replace('\"', ""),
@@ -167,15 +247,34 @@ pub async fn register_oidc(
                     otp_secret: None,
                     otp_url: None,
                     one_time_token: None,
+                    one_time_token_expires_at: None,
+                    one_time_token_attempts: 0,
+                    access_token_lifetime: None,
+                    session_idle_lifetime: None,
+                    session_absolute_lifetime: None,
+                    max_sessions: None,
+                    is_admin: false,
+                    otp_algorithm: None,
+                    otp_digits: None,
+                    otp_period: None,
+                    otp_last_step: None,
+                    otp_key_id: None,
+                    // The email is verified by the identity provider
+                    email_verified: true,
                 };
 
-                match user.clone().create(pool).await {
+                match user.clone().create(pool.clone()).await {
                     Ok(_) => {
                         tracing::debug!("User created");
                         HttpResponse::Ok().json(user)
                     }
                     Err(err) => {
                         tracing::error!("Error while creating user {:?}", err);
+                        if let Some(invitation_id) = invitation_id {
+                            if let Err(err) = Invitation::release(pool, invitation_id).await {
+                                tracing::error!(error = ?err,invitation = ?invitation_id ,"Error while releasing invitation");
+                            }
+                        }
                         return HttpResponse::InternalServerError()
                             .content_type(ContentType::plaintext())
                             .body("Error while creating user");
@@ -0,0 +1,76 @@
+use crate::model::{
+    revocation::{RevocationKind, TokenDenylist},
+    token::{RefreshToken, TokenLifetime},
+    user::User,
+};
+use actix_web::{delete, web, HttpResponse, Responder};
+use deadpool_postgres::Pool;
+use tracing::Instrument;
+
+/// Revoke session
+///
+/// Revoke one of the current user session, its refresh and access token can no longer be used
+#[utoipa::path(
+  tag = "Auth>Session",
+  operation_id = "deletesession",
+  path = "/api/auth/sessions/{id}",
+  responses(
+      (status = 200, description = "Success"),
+      (status = 404, description = "Session not found"),
+      (status = 500, description = "Internal server error"),
+  ),
+  params(
+    ("id" = uuid, Path, description = "Id de la session"),
+    ("Authorization-type" = Option<AuthType>, Header, description = "Type de token (oidc ou buildin), détecté depuis le token si absent")
+  ),
+  security(
+    ("access_token" = []),
+    ("oidc" = [])
+  )
+)]
+#[delete("/{id}")]
+pub async fn delete_session(
+    user: User,
+    session_id: web::Path<uuid::Uuid>,
+    db_pool: web::Data<Pool>,
+    token_lifetime: web::Data<TokenLifetime>,
+    token_denylist: web::Data<TokenDenylist>,
+) -> impl Responder {
+    let session_id = session_id.into_inner();
+    tracing::debug!(user = ?user.email, session = ?session_id, "Revoking session");
+    let pool: Pool = db_pool.into_inner().as_ref().clone();
+    let delete_session_span = tracing::info_span!("Delete session");
+    match async move {
+        match RefreshToken::delete_session(pool.clone(), user.id, session_id).await {
+            Ok(0) => {
+                tracing::debug!(user = ?user.email, session = ?session_id, "Session not found");
+                return Err(HttpResponse::NotFound().finish());
+            }
+            Ok(_) => {}
+            Err(err) => {
+                tracing::error!(error = ?err, user = ?user.email, "Error while revoking session");
+                return Err(HttpResponse::InternalServerError().finish());
+            }
+        }
+        let lifetime = token_lifetime.for_user(&user);
+        match token_denylist
+            .revoke(pool, RevocationKind::Session, session_id, &lifetime)
+            .await
+        {
+            Ok(_) => {
+                tracing::debug!(user = ?user.email, session = ?session_id, "Session revoked");
+                Ok(())
+            }
+            Err(err) => {
+                tracing::error!(error = ?err, user = ?user.email, "Error while revoking session access token");
+                Err(HttpResponse::InternalServerError().finish())
+            }
+        }
+    }
+    .instrument(delete_session_span)
+    .await
+    {
+        Ok(_) => HttpResponse::Ok().finish(),
+        Err(err) => err,
+    }
+}
@@ -0,0 +1,10 @@
+use actix_web::{web, Scope};
+
+use super::{delete, list, rename};
+
+pub fn init_session() -> Scope {
+    web::scope("/sessions")
+        .service(list::list_sessions)
+        .service(rename::rename_session)
+        .service(delete::delete_session)
+}
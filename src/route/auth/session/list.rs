@@ -0,0 +1,48 @@
+use crate::model::{token::RefreshToken, user::User};
+use actix_web::{get, web, HttpResponse, Responder};
+use deadpool_postgres::Pool;
+use tracing::Instrument;
+
+/// List sessions
+///
+/// List the active sessions of the current user
+#[utoipa::path(
+  tag = "Auth>Session",
+  operation_id = "listsessions",
+  path = "/api/auth/sessions",
+  responses(
+      (status = 200, description = "Sessions", body = [Session]),
+      (status = 500, description = "Internal server error"),
+  ),
+  params(
+    ("Authorization-type" = Option<AuthType>, Header, description = "Type de token (oidc ou buildin), détecté depuis le token si absent")
+  ),
+  security(
+    ("access_token" = []),
+    ("oidc" = [])
+  )
+)]
+#[get("")]
+pub async fn list_sessions(user: User, db_pool: web::Data<Pool>) -> impl Responder {
+    tracing::debug!(user = ?user.email, "Listing sessions");
+    let pool: Pool = db_pool.into_inner().as_ref().clone();
+    let list_sessions_span = tracing::info_span!("List sessions");
+    match async move {
+        match RefreshToken::get_sessions(pool, user.id).await {
+            Ok(sessions) => {
+                tracing::debug!(user = ?user.email, nbr_session = sessions.len(), "Sessions found");
+                Ok(sessions)
+            }
+            Err(err) => {
+                tracing::error!(error = ?err, user = ?user.email, "Error while getting sessions");
+                Err(HttpResponse::InternalServerError().finish())
+            }
+        }
+    }
+    .instrument(list_sessions_span)
+    .await
+    {
+        Ok(sessions) => HttpResponse::Ok().json(sessions),
+        Err(err) => err,
+    }
+}
@@ -0,0 +1,4 @@
+pub mod delete;
+pub mod init;
+pub mod list;
+pub mod rename;
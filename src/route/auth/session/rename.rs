@@ -0,0 +1,69 @@
+use crate::model::{token::RefreshToken, user::User};
+use actix_web::{put, web, HttpResponse, Responder};
+use deadpool_postgres::Pool;
+use serde::{Deserialize, Serialize};
+use tracing::Instrument;
+use utoipa::ToSchema;
+
+#[derive(Serialize, Deserialize, ToSchema, Clone)]
+pub struct SessionUpdate {
+    pub name: Option<String>,
+}
+
+/// Rename session
+///
+/// Name one of the current user session, a null name remove it
+#[utoipa::path(
+  tag = "Auth>Session",
+  operation_id = "renamesession",
+  request_body = SessionUpdate,
+  path = "/api/auth/sessions/{id}",
+  responses(
+      (status = 200, description = "Success"),
+      (status = 404, description = "Session not found"),
+      (status = 500, description = "Internal server error"),
+  ),
+  params(
+    ("id" = uuid, Path, description = "Id de la session"),
+    ("Authorization-type" = Option<AuthType>, Header, description = "Type de token (oidc ou buildin), détecté depuis le token si absent")
+  ),
+  security(
+    ("access_token" = []),
+    ("oidc" = [])
+  )
+)]
+#[put("/{id}")]
+pub async fn rename_session(
+    user: User,
+    session_id: web::Path<uuid::Uuid>,
+    db_pool: web::Data<Pool>,
+    body: web::Json<SessionUpdate>,
+) -> impl Responder {
+    let session_id = session_id.into_inner();
+    let name = body.into_inner().name;
+    tracing::debug!(user = ?user.email, session = ?session_id, "Renaming session");
+    let pool: Pool = db_pool.into_inner().as_ref().clone();
+    let rename_session_span = tracing::info_span!("Rename session");
+    match async move {
+        match RefreshToken::rename_session(pool, user.id, session_id, name).await {
+            Ok(0) => {
+                tracing::debug!(user = ?user.email, session = ?session_id, "Session not found");
+                Err(HttpResponse::NotFound().finish())
+            }
+            Ok(_) => {
+                tracing::debug!(user = ?user.email, session = ?session_id, "Session renamed");
+                Ok(())
+            }
+            Err(err) => {
+                tracing::error!(error = ?err, user = ?user.email, "Error while renaming session");
+                Err(HttpResponse::InternalServerError().finish())
+            }
+        }
+    }
+    .instrument(rename_session_span)
+    .await
+    {
+        Ok(_) => HttpResponse::Ok().finish(),
+        Err(err) => err,
+    }
+}
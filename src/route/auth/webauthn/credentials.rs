@@ -0,0 +1,143 @@
+use crate::helper::header;
+use crate::model::{
+    login_attempt::LoginAttemptConfig,
+    otp::OtpConfig,
+    revocation::TokenDenylist,
+    token::TokenLifetime,
+    user::{AuthClaims, User},
+    webauthn::WebauthnCredential,
+};
+use crate::route::auth::webauthn::register::{check_proof, WebauthnProof};
+use actix_web::{delete, get, web, HttpRequest, HttpResponse, Responder};
+use deadpool_postgres::Pool;
+use tracing::Instrument;
+
+/// List the passkeys of the user
+#[utoipa::path(
+  tag = "Auth>Webauthn",
+  operation_id = "listwebauthncredentials",
+  path = "/api/auth/webauthn/credentials",
+  responses(
+      (status = 200, description = "Passkeys", body = Vec<WebauthnCredential>),
+      (status = 500, description = "Internal server error"),
+  ),
+  params(
+    ("Authorization-type" = Option<AuthType>, Header, description = "Type de token (oidc ou buildin), détecté depuis le token si absent")
+  ),
+  security(
+    ("access_token" = [])
+  )
+)]
+#[get("/credentials")]
+pub async fn list_credentials(user: User, db_pool: web::Data<Pool>) -> impl Responder {
+    let pool: Pool = db_pool.into_inner().as_ref().clone();
+    let get_credentials_span = tracing::info_span!("Get user passkeys");
+    async move {
+        match WebauthnCredential::get_all_by_user(pool, user.id).await {
+            Ok(credentials) => HttpResponse::Ok().json(credentials),
+            Err(err) => {
+                tracing::error!(error = ?err,user = ?user.email ,"Error while getting passkeys");
+                HttpResponse::InternalServerError().finish()
+            }
+        }
+    }
+    .instrument(get_credentials_span)
+    .await
+}
+
+/// Delete a passkey
+///
+/// The password, an otp code or a recovery code is required, a wrong one count as a failed
+/// login and the account can be locked. The other sessions of the user are revoked.
+#[utoipa::path(
+  tag = "Auth>Webauthn",
+  request_body = WebauthnProof,
+  operation_id = "deletewebauthncredential",
+  path = "/api/auth/webauthn/credentials/{id}",
+  responses(
+      (status = 200, description = "Passkey deleted"),
+      (status = 400, description = "Bad request or invalid proof"),
+      (status = 404, description = "Passkey not found"),
+      (status = 423, description = "Too many failed attempts", body = LoginLocked),
+      (status = 500, description = "Internal server error"),
+  ),
+  params(
+    ("id" = uuid, Path, description = "Id of the passkey"),
+    ("Authorization-type" = Option<AuthType>, Header, description = "Type de token (oidc ou buildin), détecté depuis le token si absent")
+  ),
+  security(
+    ("access_token" = [])
+  )
+)]
+#[delete("/credentials/{id}")]
+#[allow(clippy::too_many_arguments)]
+pub async fn delete_credential(
+    req: HttpRequest,
+    mut user: User,
+    auth_claims: AuthClaims,
+    credential_id: web::Path<uuid::Uuid>,
+    db_pool: web::Data<Pool>,
+    otp_config: web::Data<OtpConfig>,
+    login_attempt_config: web::Data<LoginAttemptConfig>,
+    token_lifetime: web::Data<TokenLifetime>,
+    token_denylist: web::Data<TokenDenylist>,
+    proof_body: web::Json<WebauthnProof>,
+) -> impl Responder {
+    let credential_id = credential_id.into_inner();
+    let pool: Pool = db_pool.into_inner().as_ref().clone();
+    let (_, ip) = header::extract_client_info(&req);
+    if let Err(err) = check_proof(
+        pool.clone(),
+        &mut user,
+        proof_body.into_inner(),
+        &otp_config,
+        &login_attempt_config,
+        ip.as_deref(),
+    )
+    .await
+    {
+        return err;
+    }
+
+    let delete_credential_span = tracing::info_span!("Delete passkey and revoke other sessions");
+    async move {
+        match WebauthnCredential::delete(pool.clone(), user.id, credential_id).await {
+            Ok(0) => {
+                tracing::debug!(user = ?user.email, credential = ?credential_id ,"Passkey not found");
+                return HttpResponse::NotFound().finish();
+            }
+            Ok(_) => {
+                tracing::debug!(user = ?user.email, credential = ?credential_id ,"Passkey deleted");
+            }
+            Err(err) => {
+                tracing::error!(error = ?err,user = ?user.email ,"Error while deleting passkey");
+                return HttpResponse::InternalServerError().finish();
+            }
+        }
+        let lifetime = token_lifetime.for_user(&user);
+        let revoked = match auth_claims.session_id {
+            Some(session_id) => {
+                token_denylist
+                    .revoke_other_sessions(pool, user.id, session_id, &lifetime)
+                    .await
+            }
+            None => {
+                token_denylist
+                    .revoke_all_sessions(pool, user.id, &lifetime)
+                    .await
+            }
+        };
+        match revoked {
+            Ok(nbr) => {
+                tracing::debug!(user = ?user.email, nbr_token = nbr ,"Other sessions revoked");
+                HttpResponse::Ok().finish()
+            }
+            Err(err) => {
+                tracing::error!(error = ?err,user = ?user.email ,"Error while revoking sessions");
+                HttpResponse::InternalServerError().finish()
+            }
+        }
+    }
+    .instrument(delete_credential_span)
+    .await
+}
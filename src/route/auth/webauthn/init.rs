@@ -0,0 +1,13 @@
+use actix_web::{web, Scope};
+
+use super::{credentials, login, register};
+
+pub fn init_webauthn() -> Scope {
+    web::scope("/webauthn")
+        .service(register::start_registration)
+        .service(register::finish_registration)
+        .service(login::start_login)
+        .service(login::finish_login)
+        .service(credentials::list_credentials)
+        .service(credentials::delete_credential)
+}
@@ -0,0 +1,487 @@
+use crate::helper::header;
+use crate::model::email_verification::EmailVerificationConfig;
+use crate::model::login_attempt::LoginAttemptConfig;
+use crate::model::otp::OtpConfig;
+use crate::model::token::{self, RefreshToken, TokenClaims, TokenKeys, TokenLifetime};
+use crate::model::user::User;
+use crate::model::webauthn::{ChallengeKind, PasskeyDecoy, WebauthnCredential};
+use crate::route::auth::login::{
+    check_login_lock, release_login_attempt, reserve_login_attempt, reset_login_failures,
+    LoginStatus, LoginUserReturn,
+};
+use crate::route::auth::webauthn::register::WebauthnChallenge;
+use actix_web::{post, web, HttpRequest, HttpResponse, Responder};
+use deadpool_postgres::Pool;
+use serde::{Deserialize, Serialize};
+use tracing::Instrument;
+use utoipa::ToSchema;
+use uuid::Uuid;
+use webauthn_rs::{
+    prelude::{PasskeyAuthentication, PublicKeyCredential, RequestChallengeResponse},
+    Webauthn,
+};
+
+#[derive(Serialize, Deserialize, ToSchema, Clone)]
+pub struct StartWebauthnLogin {
+    // The one time token returned by the login for the second step, or the
+    // email of the user to login with the passkey only
+    pub one_time_token: Option<String>,
+    pub email: Option<String>,
+}
+
+#[derive(Deserialize, ToSchema)]
+pub struct FinishWebauthnLogin {
+    pub challenge_id: Uuid,
+    #[schema(value_type = Object)]
+    pub credential: PublicKeyCredential,
+}
+
+fn challenge_response(challenge_id: Uuid, options: RequestChallengeResponse) -> HttpResponse {
+    match serde_json::to_value(options) {
+        Ok(options) => HttpResponse::Ok().json(WebauthnChallenge {
+            challenge_id,
+            options,
+        }),
+        Err(err) => {
+            tracing::error!(error = ?err ,"Error while serializing authentication options");
+            HttpResponse::InternalServerError().finish()
+        }
+    }
+}
+
+// Challenge returned to an unknown account, an oidc or unverified one or an account
+// without passkey, the login without password must not tell them apart
+fn decoy_response(passkey_decoy: &PasskeyDecoy, webauthn: &Webauthn, email: &str) -> HttpResponse {
+    match passkey_decoy.challenge(webauthn, email) {
+        Ok(options) => challenge_response(Uuid::new_v4(), options),
+        Err(err) => {
+            tracing::error!(error = ?err,user = ?email ,"Error while generating decoy challenge");
+            HttpResponse::InternalServerError().finish()
+        }
+    }
+}
+
+/// Start the login with a passkey
+///
+/// Either as the second step of the login with the one time token or without password with the email.
+/// Without password the answer is the same whether the account exists and has a passkey or not,
+/// the challenge of an unknown account can't be answered.
+#[utoipa::path(
+  tag = "Auth>Webauthn",
+  request_body = StartWebauthnLogin,
+  operation_id = "startwebauthnlogin",
+  path = "/api/auth/webauthn/login/start",
+  responses(
+      (status = 200, description = "Success", body = WebauthnChallenge),
+      (status = 400, description = "Bad request"),
+      (status = 401, description = "Invalid one time token"),
+      (status = 423, description = "Too many failed attempts", body = LoginLocked),
+      (status = 500, description = "Internal server error"),
+  )
+)]
+#[post("/login/start")]
+pub async fn start_login(
+    req: HttpRequest,
+    db_pool: web::Data<Pool>,
+    webauthn: web::Data<Webauthn>,
+    verification_config: web::Data<EmailVerificationConfig>,
+    passkey_decoy: web::Data<PasskeyDecoy>,
+    start_body: web::Json<StartWebauthnLogin>,
+) -> impl Responder {
+    let body = start_body.into_inner();
+    let pool: Pool = db_pool.into_inner().as_ref().clone();
+    let (_, ip) = header::extract_client_info(&req);
+    let find_user_span = tracing::info_span!("Find user");
+    // The user is None when the login without password get the decoy
+    let (user, kind, email) = match {
+        let pool_swap = pool.clone();
+        async move {
+            match (body.one_time_token, body.email) {
+                (Some(one_time_token), None) => {
+                    match User::get_one_by_one_time_token(pool_swap, one_time_token).await {
+                        Ok(user) => {
+                            let email = user.email.clone();
+                            Ok((Some(user), ChallengeKind::SecondFactor, email))
+                        }
+                        Err(err) => {
+                            tracing::error!(error = ?err ,"Error while getting user");
+                            Err(HttpResponse::Unauthorized().finish())
+                        }
+                    }
+                }
+                (None, Some(email)) => {
+                    match User::get_one_by_mail(pool_swap, email.clone()).await {
+                        Ok(Some(user))
+                            if !user.is_oauth
+                                && (user.email_verified || !verification_config.required) =>
+                        {
+                            Ok((Some(user), ChallengeKind::Passwordless, email))
+                        }
+                        Ok(_) => {
+                            tracing::debug!(user = ?email ,"User not found, oauth or email not verified");
+                            Ok((None, ChallengeKind::Passwordless, email))
+                        }
+                        Err(err) => {
+                            tracing::error!(error = ?err,user = ?email ,"Error while getting user");
+                            Err(HttpResponse::InternalServerError().finish())
+                        }
+                    }
+                }
+                _ => {
+                    tracing::debug!("Either a one time token or an email is needed");
+                    Err(HttpResponse::BadRequest().finish())
+                }
+            }
+        }
+        .instrument(find_user_span)
+    }
+    .await
+    {
+        Ok(found) => found,
+        Err(err) => return err,
+    };
+    if let Err(err) = check_login_lock(pool.clone(), &email, ip.as_deref()).await {
+        return err;
+    }
+    let user = match user {
+        Some(user) => user,
+        None => return decoy_response(&passkey_decoy, &webauthn, &email),
+    };
+
+    let get_credentials_span = tracing::info_span!("Get user passkeys");
+    let credentials = match {
+        let pool_swap = pool.clone();
+        let user_swap = user.clone();
+        async move {
+            match WebauthnCredential::get_all_by_user(pool_swap, user_swap.id).await {
+                Ok(credentials) => Ok(credentials),
+                Err(err) => {
+                    tracing::error!(error = ?err,user = ?user_swap.email ,"Error while getting passkeys");
+                    Err(HttpResponse::InternalServerError().finish())
+                }
+            }
+        }
+        .instrument(get_credentials_span)
+    }
+    .await
+    {
+        Ok(credentials) => credentials,
+        Err(err) => return err,
+    };
+    if credentials.is_empty() {
+        tracing::debug!(user = ?user.email ,"User has no passkey");
+        return match kind {
+            ChallengeKind::Passwordless => decoy_response(&passkey_decoy, &webauthn, &email),
+            _ => HttpResponse::BadRequest().finish(),
+        };
+    }
+
+    let passkeys: Vec<_> = credentials
+        .into_iter()
+        .map(|credential| credential.passkey)
+        .collect();
+    let (options, state) = match webauthn.start_passkey_authentication(&passkeys) {
+        Ok(challenge) => challenge,
+        Err(err) => {
+            tracing::error!(error = ?err,user = ?user.email ,"Error while starting passkey authentication");
+            return HttpResponse::InternalServerError().finish();
+        }
+    };
+
+    let save_challenge_span = tracing::info_span!("Save authentication challenge");
+    let challenge_id = match {
+        let user_swap = user.clone();
+        async move {
+            match WebauthnCredential::save_challenge(pool, user_swap.id, None, kind, &state).await {
+                Ok(challenge_id) => Ok(challenge_id),
+                Err(err) => {
+                    tracing::error!(error = ?err,user = ?user_swap.email ,"Error while saving authentication challenge");
+                    Err(HttpResponse::InternalServerError().finish())
+                }
+            }
+        }
+        .instrument(save_challenge_span)
+    }
+    .await
+    {
+        Ok(challenge_id) => challenge_id,
+        Err(err) => return err,
+    };
+
+    challenge_response(challenge_id, options)
+}
+
+/// End the login with a passkey
+///
+/// Check the assertion returned by navigator.credentials.get and return the tokens
+#[utoipa::path(
+  tag = "Auth>Webauthn",
+  request_body = FinishWebauthnLogin,
+  operation_id = "finishwebauthnlogin",
+  path = "/api/auth/webauthn/login/finish",
+  responses(
+      (status = 200, description = "Success", body = LoginUserReturn),
+      (status = 401, description = "Unauthorized"),
+      (status = 423, description = "Too many failed attempts", body = LoginLocked),
+      (status = 500, description = "Internal server error"),
+  )
+)]
+#[post("/login/finish")]
+#[allow(clippy::too_many_arguments)]
+pub async fn finish_login(
+    req: HttpRequest,
+    db_pool: web::Data<Pool>,
+    webauthn: web::Data<Webauthn>,
+    token_keys: web::Data<TokenKeys>,
+    token_lifetime: web::Data<TokenLifetime>,
+    otp_config: web::Data<OtpConfig>,
+    login_attempt_config: web::Data<LoginAttemptConfig>,
+    finish_body: web::Json<FinishWebauthnLogin>,
+) -> impl Responder {
+    let body = finish_body.into_inner();
+    let pool: Pool = db_pool.into_inner().as_ref().clone();
+    let (user_agent, ip) = header::extract_client_info(&req);
+    let consume_challenge_span = tracing::info_span!("Consume authentication challenge");
+    let (user_id, kind, state): (Uuid, ChallengeKind, PasskeyAuthentication) = match {
+        let pool_swap = pool.clone();
+        async move {
+            match WebauthnCredential::consume_challenge(
+                pool_swap,
+                body.challenge_id,
+                None,
+                &[ChallengeKind::SecondFactor, ChallengeKind::Passwordless],
+            )
+            .await
+            {
+                Ok(Some(challenge)) => Ok(challenge),
+                Ok(None) => {
+                    tracing::debug!(challenge = ?body.challenge_id ,"Authentication challenge not found");
+                    Err(HttpResponse::Unauthorized().finish())
+                }
+                Err(err) => {
+                    tracing::error!(error = ?err ,"Error while consuming authentication challenge");
+                    Err(HttpResponse::InternalServerError().finish())
+                }
+            }
+        }
+        .instrument(consume_challenge_span)
+    }
+    .await
+    {
+        Ok(challenge) => challenge,
+        Err(err) => return err,
+    };
+
+    let find_user_span = tracing::info_span!("Find user");
+    let mut user = match {
+        let pool_swap = pool.clone();
+        async move {
+            match User::get_one(pool_swap, user_id).await {
+                Ok(user) => Ok(user),
+                Err(err) => {
+                    tracing::error!(error = ?err,user = ?user_id ,"Error while getting user");
+                    Err(HttpResponse::Unauthorized().finish())
+                }
+            }
+        }
+        .instrument(find_user_span)
+    }
+    .await
+    {
+        Ok(user) => user,
+        Err(err) => return err,
+    };
+
+    let reservation = match reserve_login_attempt(
+        pool.clone(),
+        &login_attempt_config,
+        &user.email,
+        ip.as_deref(),
+    )
+    .await
+    {
+        Ok(reservation) => reservation,
+        Err(err) => return err,
+    };
+
+    // The one time token can have expired or been invalidated since the start
+    if kind == ChallengeKind::SecondFactor
+        && !(user.one_time_token.is_some()
+            && user
+                .one_time_token_expires_at
+                .is_some_and(|expires_at| expires_at > chrono::Utc::now()))
+    {
+        tracing::debug!(user = ?user.email ,"One time token expired");
+        return HttpResponse::Unauthorized().finish();
+    }
+
+    let result = match webauthn.finish_passkey_authentication(&body.credential, &state) {
+        Ok(result) if kind == ChallengeKind::SecondFactor || result.user_verified() => result,
+        verification => {
+            tracing::debug!(error = ?verification.err(),user = ?user.email ,"Invalid passkey assertion");
+            if kind == ChallengeKind::SecondFactor {
+                let fail_one_time_token_span = tracing::info_span!("Count failed attempt");
+                if let Err(err) = user
+                    .fail_one_time_token(pool.clone(), otp_config.one_time_token_max_attempts)
+                    .instrument(fail_one_time_token_span)
+                    .await
+                {
+                    tracing::error!(error = ?err,user = ?user.email ,"Error while counting failed attempt");
+                    return HttpResponse::InternalServerError().finish();
+                }
+            }
+            return HttpResponse::Unauthorized().finish();
+        }
+    };
+    release_login_attempt(pool.clone(), &reservation).await;
+
+    let update_credential_span = tracing::info_span!("Update passkey usage");
+    match {
+        let pool_swap = pool.clone();
+        let user_swap = user.clone();
+        async move {
+            let credentials = WebauthnCredential::get_all_by_user(pool_swap.clone(), user_swap.id)
+                .await
+                .map_err(|err| {
+                    tracing::error!(error = ?err,user = ?user_swap.email ,"Error while getting passkeys");
+                    HttpResponse::InternalServerError().finish()
+                })?;
+            let mut credential = match credentials
+                .into_iter()
+                .find(|credential| credential.passkey.cred_id() == result.cred_id())
+            {
+                Some(credential) => credential,
+                None => {
+                    tracing::debug!(user = ?user_swap.email ,"Passkey deleted during the login");
+                    return Err(HttpResponse::Unauthorized().finish());
+                }
+            };
+            credential.passkey.update_credential(&result);
+            match credential.update_usage(pool_swap).await {
+                Ok(_) => Ok(()),
+                Err(err) => {
+                    tracing::error!(error = ?err,user = ?user_swap.email ,"Error while updating passkey usage");
+                    Err(HttpResponse::InternalServerError().finish())
+                }
+            }
+        }
+        .instrument(update_credential_span)
+    }
+    .await
+    {
+        Ok(_) => (),
+        Err(err) => return err,
+    }
+
+    if kind == ChallengeKind::SecondFactor {
+        user.clear_one_time_token();
+        let update_user_span = tracing::info_span!("Clear one time token");
+        match {
+            let pool_swap = pool.clone();
+            let user_swap = user.clone();
+            async move {
+                match user_swap
+                    .update_otp_secret_url_token_enabled(pool_swap)
+                    .await
+                {
+                    Ok(_) => Ok(()),
+                    Err(err) => {
+                        tracing::error!(error = ?err,user = ?user_swap.email ,"Error while clearing one time token");
+                        Err(HttpResponse::InternalServerError().finish())
+                    }
+                }
+            }
+            .instrument(update_user_span)
+        }
+        .await
+        {
+            Ok(_) => (),
+            Err(err) => return err,
+        }
+    }
+
+    tracing::debug!(
+        user = user.email.clone(),
+        "User logged in with a passkey, generating refresh_token"
+    );
+    reset_login_failures(pool.clone(), &user.email).await;
+
+    let lifetime = token_lifetime.for_user(&user);
+    let refresh_token = match TokenClaims::new_tokens(
+        user.id,
+        user.email.clone(),
+        true,
+        &token_keys,
+        &lifetime,
+        chrono::Utc::now(),
+    ) {
+        Ok(token) => token,
+        Err(err) => {
+            tracing::error!(error = ?err,user = user.email.clone() ,"Error while generating token");
+            return HttpResponse::InternalServerError().finish();
+        }
+    };
+
+    let refresh_token_db =
+        match RefreshToken::new(user.id, &refresh_token, &token_keys, user_agent, ip) {
+            Ok(token) => token,
+            Err(err) => {
+                tracing::error!(error = ?err, "Error while hashing refresh token");
+                return HttpResponse::InternalServerError().finish();
+            }
+        };
+
+    {
+        let pool_swap = pool.clone();
+        let user_swap = user.clone();
+        let delete_old_token_span = tracing::info_span!("Delete old refresh token");
+        if let Err(err_response) = async move {
+            match token::RefreshToken::keep_only_last_sessions(pool_swap, user_swap.id, &lifetime)
+                .await
+            {
+                Ok(_) => {
+                    tracing::debug!(user = ?user_swap.email ,"Successfully deleted old refresh token");
+                    Ok(())
+                }
+                Err(err) => {
+                    tracing::error!(error = ?err,user = ?user_swap.email ,"Error while deleting old token");
+                    Err(HttpResponse::InternalServerError().finish())
+                }
+            }
+        }
+        .instrument(delete_old_token_span)
+        .await
+        {
+            return err_response;
+        }
+    }
+
+    {
+        let user_swap = user.clone();
+        let insert_new_token_span = tracing::info_span!("Insert new refresh token");
+        if let Err(err_response) = async move {
+            match refresh_token_db.create(pool).await {
+                Ok(_) => {
+                    tracing::debug!(user = ?user_swap.email ,"Refresh token saved to db");
+                    Ok(())
+                }
+                Err(err) => {
+                    tracing::error!(error = ?err,user = ?user_swap.email ,"Error while saving refresh token to db");
+                    Err(HttpResponse::InternalServerError().finish())
+                }
+            }
+        }
+        .instrument(insert_new_token_span)
+        .await
+        {
+            return err_response;
+        }
+    }
+
+    HttpResponse::Ok().json(LoginUserReturn {
+        user: Some(user),
+        token: Some(refresh_token),
+        status: LoginStatus::RefreshStep,
+        device_token: None,
+    })
+}
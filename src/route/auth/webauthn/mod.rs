@@ -0,0 +1,4 @@
+pub mod credentials;
+pub mod init;
+pub mod login;
+pub mod register;
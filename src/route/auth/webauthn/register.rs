@@ -0,0 +1,326 @@
+use crate::helper::header;
+use crate::model::{
+    login_attempt::LoginAttemptConfig,
+    otp::OtpConfig,
+    recovery_code::RecoveryCode,
+    user::{AuthClaims, User},
+    webauthn::{ChallengeKind, WebauthnCredential},
+};
+use crate::route::auth::login::{release_login_attempt, reserve_login_attempt};
+use actix_web::{post, web, HttpRequest, HttpResponse, Responder};
+use deadpool_postgres::Pool;
+use serde::{Deserialize, Serialize};
+use tracing::Instrument;
+use utoipa::ToSchema;
+use uuid::Uuid;
+use webauthn_rs::{
+    prelude::{PasskeyRegistration, RegisterPublicKeyCredential},
+    Webauthn,
+};
+
+#[derive(Serialize, Deserialize, ToSchema, Clone)]
+pub struct WebauthnChallenge {
+    pub challenge_id: Uuid,
+    // Options to give to navigator.credentials.create or navigator.credentials.get
+    #[schema(value_type = Object)]
+    pub options: serde_json::Value,
+}
+
+// Fresh proof of the identity of the user to add or remove a passkey
+#[derive(Serialize, Deserialize, ToSchema, Clone)]
+pub struct WebauthnProof {
+    // Only one of the password, the otp code or a recovery code is needed,
+    // the last two only if the otp is enabled
+    pub password: Option<String>,
+    pub otp_code: Option<String>,
+    pub recovery_code: Option<String>,
+}
+
+#[derive(Deserialize, ToSchema)]
+pub struct FinishWebauthnRegistration {
+    pub challenge_id: Uuid,
+    // Name displayed in the credential list, default to Passkey
+    pub name: Option<String>,
+    #[schema(value_type = Object)]
+    pub credential: RegisterPublicKeyCredential,
+}
+
+// Check the password, otp code or recovery code of the proof, a wrong one count as a
+// failed login
+pub async fn check_proof(
+    pool: Pool,
+    user: &mut User,
+    proof: WebauthnProof,
+    otp_config: &OtpConfig,
+    login_attempt_config: &LoginAttemptConfig,
+    ip: Option<&str>,
+) -> Result<(), HttpResponse> {
+    let reservation =
+        reserve_login_attempt(pool.clone(), login_attempt_config, &user.email, ip).await?;
+    let valid = match (proof.password, proof.otp_code, proof.recovery_code) {
+        (Some(password), None, None) => user
+            .compare_password(password)
+            .instrument(tracing::info_span!("Check if password is valid"))
+            .await
+            .map_err(|err| {
+                tracing::error!(error = ?err,user = ?user.email ,"Error while comparing password");
+                HttpResponse::InternalServerError().finish()
+            })?,
+        (None, Some(otp_code), None) if user.otp_enabled => user
+            .validate_otp(pool.clone(), otp_config, otp_code)
+            .instrument(tracing::info_span!("Validate otp"))
+            .await
+            .map_err(|err| {
+                tracing::error!(error = ?err,user = ?user.email ,"Error while validating otp");
+                HttpResponse::InternalServerError().finish()
+            })?,
+        (None, None, Some(recovery_code)) if user.otp_enabled => RecoveryCode::consume(
+            pool.clone(),
+            user.id,
+            &recovery_code,
+        )
+        .instrument(tracing::info_span!("Consume recovery code"))
+        .await
+        .map_err(|err| {
+            tracing::error!(error = ?err,user = ?user.email ,"Error while consuming recovery code");
+            HttpResponse::InternalServerError().finish()
+        })?,
+        _ => {
+            tracing::debug!(user = ?user.email ,"Either a password, an otp code or a recovery code is needed");
+            release_login_attempt(pool.clone(), &reservation).await;
+            return Err(HttpResponse::BadRequest().finish());
+        }
+    };
+    if !valid {
+        tracing::debug!(user = ?user.email ,"Password, otp code or recovery code invalid");
+        return Err(HttpResponse::BadRequest().finish());
+    }
+    release_login_attempt(pool, &reservation).await;
+    Ok(())
+}
+
+/// Start the registration of a passkey
+///
+/// Return the options to give to navigator.credentials.create. The password, an otp code
+/// or a recovery code is required, the challenge can then only be answered by the same session.
+/// A wrong proof count as a failed login, the account can be locked.
+#[utoipa::path(
+  tag = "Auth>Webauthn",
+  request_body = WebauthnProof,
+  operation_id = "startwebauthnregistration",
+  path = "/api/auth/webauthn/register/start",
+  responses(
+      (status = 200, description = "Success", body = WebauthnChallenge),
+      (status = 400, description = "Bad request or invalid proof"),
+      (status = 423, description = "Too many failed attempts", body = LoginLocked),
+      (status = 500, description = "Internal server error"),
+  ),
+  params(
+    ("Authorization-type" = Option<AuthType>, Header, description = "Type de token (oidc ou buildin), détecté depuis le token si absent")
+  ),
+  security(
+    ("access_token" = [])
+  )
+)]
+#[post("/register/start")]
+#[allow(clippy::too_many_arguments)]
+pub async fn start_registration(
+    req: HttpRequest,
+    mut user: User,
+    auth_claims: AuthClaims,
+    db_pool: web::Data<Pool>,
+    webauthn: web::Data<Webauthn>,
+    otp_config: web::Data<OtpConfig>,
+    login_attempt_config: web::Data<LoginAttemptConfig>,
+    proof_body: web::Json<WebauthnProof>,
+) -> impl Responder {
+    tracing::debug!(user = ?user.email ,"Starting passkey registration");
+    if user.is_oauth {
+        tracing::debug!(user = ?user.email ,"User is oauth");
+        return HttpResponse::BadRequest().finish();
+    }
+    let pool: Pool = db_pool.into_inner().as_ref().clone();
+    let (_, ip) = header::extract_client_info(&req);
+    if let Err(err) = check_proof(
+        pool.clone(),
+        &mut user,
+        proof_body.into_inner(),
+        &otp_config,
+        &login_attempt_config,
+        ip.as_deref(),
+    )
+    .await
+    {
+        return err;
+    }
+
+    let get_credentials_span = tracing::info_span!("Get user passkeys");
+    let credentials = match {
+        let pool_swap = pool.clone();
+        let user_swap = user.clone();
+        async move {
+            match WebauthnCredential::get_all_by_user(pool_swap, user_swap.id).await {
+                Ok(credentials) => Ok(credentials),
+                Err(err) => {
+                    tracing::error!(error = ?err,user = ?user_swap.email ,"Error while getting passkeys");
+                    Err(HttpResponse::InternalServerError().finish())
+                }
+            }
+        }
+        .instrument(get_credentials_span)
+    }
+    .await
+    {
+        Ok(credentials) => credentials,
+        Err(err) => return err,
+    };
+
+    let exclude_credentials = credentials
+        .iter()
+        .map(|credential| credential.passkey.cred_id().clone())
+        .collect();
+    let (options, state) = match webauthn.start_passkey_registration(
+        user.id,
+        &user.email,
+        &format!("{} {}", user.prenom, user.nom),
+        Some(exclude_credentials),
+    ) {
+        Ok(challenge) => challenge,
+        Err(err) => {
+            tracing::error!(error = ?err,user = ?user.email ,"Error while starting passkey registration");
+            return HttpResponse::InternalServerError().finish();
+        }
+    };
+
+    let save_challenge_span = tracing::info_span!("Save registration challenge");
+    let challenge_id = match {
+        let user_swap = user.clone();
+        async move {
+            match WebauthnCredential::save_challenge(
+                pool,
+                user_swap.id,
+                auth_claims.session_id,
+                ChallengeKind::Registration,
+                &state,
+            )
+            .await
+            {
+                Ok(challenge_id) => Ok(challenge_id),
+                Err(err) => {
+                    tracing::error!(error = ?err,user = ?user_swap.email ,"Error while saving registration challenge");
+                    Err(HttpResponse::InternalServerError().finish())
+                }
+            }
+        }
+        .instrument(save_challenge_span)
+    }
+    .await
+    {
+        Ok(challenge_id) => challenge_id,
+        Err(err) => return err,
+    };
+
+    match serde_json::to_value(options) {
+        Ok(options) => HttpResponse::Ok().json(WebauthnChallenge {
+            challenge_id,
+            options,
+        }),
+        Err(err) => {
+            tracing::error!(error = ?err,user = ?user.email ,"Error while serializing registration options");
+            HttpResponse::InternalServerError().finish()
+        }
+    }
+}
+
+/// Finish the registration of a passkey
+///
+/// Save the credential returned by navigator.credentials.create, it must be sent by the
+/// session which started the registration
+#[utoipa::path(
+  tag = "Auth>Webauthn",
+  request_body = FinishWebauthnRegistration,
+  operation_id = "finishwebauthnregistration",
+  path = "/api/auth/webauthn/register/finish",
+  responses(
+      (status = 200, description = "Success", body = WebauthnCredential),
+      (status = 400, description = "Bad request"),
+      (status = 500, description = "Internal server error"),
+  ),
+  params(
+    ("Authorization-type" = Option<AuthType>, Header, description = "Type de token (oidc ou buildin), détecté depuis le token si absent")
+  ),
+  security(
+    ("access_token" = [])
+  )
+)]
+#[post("/register/finish")]
+pub async fn finish_registration(
+    user: User,
+    auth_claims: AuthClaims,
+    db_pool: web::Data<Pool>,
+    webauthn: web::Data<Webauthn>,
+    finish_body: web::Json<FinishWebauthnRegistration>,
+) -> impl Responder {
+    tracing::debug!(user = ?user.email ,"Finishing passkey registration");
+    let body = finish_body.into_inner();
+    let pool: Pool = db_pool.into_inner().as_ref().clone();
+    let consume_challenge_span = tracing::info_span!("Consume registration challenge");
+    let state: PasskeyRegistration = match {
+        let pool_swap = pool.clone();
+        let user_swap = user.clone();
+        async move {
+            match WebauthnCredential::consume_challenge(
+                pool_swap,
+                body.challenge_id,
+                auth_claims.session_id,
+                &[ChallengeKind::Registration],
+            )
+            .await
+            {
+                Ok(Some((user_id, _, state))) if user_id == user_swap.id => Ok(state),
+                Ok(_) => {
+                    tracing::debug!(user = ?user_swap.email ,"Registration challenge not found");
+                    Err(HttpResponse::BadRequest().finish())
+                }
+                Err(err) => {
+                    tracing::error!(error = ?err,user = ?user_swap.email ,"Error while consuming registration challenge");
+                    Err(HttpResponse::InternalServerError().finish())
+                }
+            }
+        }
+        .instrument(consume_challenge_span)
+    }
+    .await
+    {
+        Ok(state) => state,
+        Err(err) => return err,
+    };
+
+    let passkey = match webauthn.finish_passkey_registration(&body.credential, &state) {
+        Ok(passkey) => passkey,
+        Err(err) => {
+            tracing::debug!(error = ?err,user = ?user.email ,"Invalid passkey registration");
+            return HttpResponse::BadRequest().finish();
+        }
+    };
+
+    let name = body
+        .name
+        .filter(|name| !name.trim().is_empty())
+        .unwrap_or_else(|| "Passkey".to_string());
+    let save_credential_span = tracing::info_span!("Save passkey");
+    async move {
+        match WebauthnCredential::create(pool, user.id, name, passkey).await {
+            Ok(credential) => {
+                tracing::debug!(user = ?user.email ,"Passkey saved");
+                HttpResponse::Ok().json(credential)
+            }
+            Err(err) => {
+                tracing::error!(error = ?err,user = ?user.email ,"Error while saving passkey");
+                HttpResponse::InternalServerError().finish()
+            }
+        }
+    }
+    .instrument(save_credential_span)
+    .await
+}
@@ -1,8 +1,12 @@
 use actix_web::{web, Scope};
 
+use super::admin::init::init_admin;
 use super::auth::init::init_auth;
 use super::user::init::init_user;
 
 pub fn init_api() -> Scope {
-    web::scope("/api").service(init_auth()).service(init_user())
+    web::scope("/api")
+        .service(init_auth())
+        .service(init_user())
+        .service(init_admin())
 }
@@ -1,6 +1,8 @@
+pub mod admin;
 pub mod apidoc;
 pub mod auth;
 pub mod health;
 pub mod init;
 pub mod security;
 pub mod user;
+pub mod well_known;
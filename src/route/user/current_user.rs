@@ -14,7 +14,7 @@ use actix_web::{get, HttpResponse, Responder};
       (status = 500, description = "Internal server error"),
   ),
   params(
-    ("Authorization-type" = AuthType, Header, description = "Type de token (oidc ou buildin)")
+    ("Authorization-type" = Option<AuthType>, Header, description = "Type de token (oidc ou buildin), détecté depuis le token si absent")
   ),
   security(
     ("access_token" = []),
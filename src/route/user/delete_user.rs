@@ -1,4 +1,8 @@
-use crate::model::user::User;
+use crate::model::{
+    revocation::{RevocationKind, TokenDenylist},
+    token::TokenLifetime,
+    user::User,
+};
 use actix_web::{delete, web, HttpResponse, Responder};
 use deadpool_postgres::Pool;
 use tracing::Instrument;
@@ -20,25 +24,41 @@ use tracing::Instrument;
     ("oidc" = [])
   ),
   params(
-    ("Authorization-type" = AuthType, Header, description = "Type de token (oidc ou buildin)")
+    ("Authorization-type" = Option<AuthType>, Header, description = "Type de token (oidc ou buildin), détecté depuis le token si absent")
   ),
 )]
 #[delete("")]
-pub async fn delete_user(user: User, db_pool: web::Data<Pool>) -> impl Responder {
+pub async fn delete_user(
+    user: User,
+    db_pool: web::Data<Pool>,
+    token_lifetime: web::Data<TokenLifetime>,
+    token_denylist: web::Data<TokenDenylist>,
+) -> impl Responder {
     tracing::debug!(user = ?user.email, "Suprression de l'uttilisateur courant");
     let pool: Pool = db_pool.into_inner().as_ref().clone();
     let delete_user_span = tracing::info_span!("Delete user and token");
     match {
         async move {
             let user_copy = user.clone();
-            match user.delete(pool.clone()).await {
+            let edit = match user.delete(pool.clone()).await {
                 Ok(edit) => {
                     tracing::debug!(user = ?user_copy.email,nbr_edit= ?edit ,"User and token deleted");
-                    Ok(edit)
+                    edit
                 }
                 Err(err) => {
                     tracing::error!(error = ?err,user = ?user_copy.email ,"Error while deleting user");
-                    Err(HttpResponse::NotFound().finish())
+                    return Err(HttpResponse::NotFound().finish());
+                }
+            };
+            let lifetime = token_lifetime.for_user(&user_copy);
+            match token_denylist
+                .revoke(pool, RevocationKind::User, user_copy.id, &lifetime)
+                .await
+            {
+                Ok(_) => Ok(edit),
+                Err(err) => {
+                    tracing::error!(error = ?err,user = ?user_copy.email ,"Error while revoking user access token");
+                    Err(HttpResponse::InternalServerError().finish())
                 }
             }
         }
@@ -1,11 +1,11 @@
-use crate::model::user::User;
+use crate::model::user::{MaybeUser, User};
 use actix_web::{get, web, HttpResponse, Responder};
 use deadpool_postgres::Pool;
 use tracing::Instrument;
 
 /// Get one by uid user
 ///
-/// Get one user by id
+/// Get one user by id, the email and creation date are only returned to authenticated users
 #[utoipa::path(
   tag = "User",
   operation_id = "getoneuser",
@@ -17,25 +17,27 @@ use tracing::Instrument;
   ),
   params(
     ("id" = uuid, Path, description = "Id de l'utilisateur"),
-    ("Authorization-type" = AuthType, Header, description = "Type de token (oidc ou buildin)")
+    ("Authorization-type" = Option<AuthType>, Header, description = "Type de token (oidc ou buildin), détecté depuis le token si absent")
   ),
   security(
+    (),
     ("access_token" = []),
     ("oidc" = [])
   )
 )]
 #[get("/{id}")]
 pub async fn get_one_user(
-    user: User,
+    user: MaybeUser,
     uid_user: web::Path<uuid::Uuid>,
     db_pool: web::Data<Pool>,
 ) -> impl Responder {
     let target_user_id = uid_user.into_inner();
-    if user.id == target_user_id {
+    let authenticated = user.0.is_some();
+    if let Some(user) = user.0.filter(|user| user.id == target_user_id) {
         tracing::debug!(user = ?user.email ,"User found, return public version of the current user");
-        return HttpResponse::Ok().json(user.to_public_user());
+        return HttpResponse::Ok().json(user.to_detailed_public_user());
     }
-    tracing::debug!(user = ?user.email, uid = ?target_user_id ,"Searching for user");
+    tracing::debug!(authenticated = authenticated, uid = ?target_user_id ,"Searching for user");
     let pool: Pool = db_pool.into_inner().as_ref().clone();
     let find_user_span = tracing::info_span!("Find user");
     let user_find = match {
@@ -46,7 +48,7 @@ pub async fn get_one_user(
                     Ok(user)
                 }
                 Err(err) => {
-                    tracing::error!(error = ?err,user = ?user.email ,"Error while getting user");
+                    tracing::error!(error = ?err,uid = ?target_user_id ,"Error while getting user");
                     Err(HttpResponse::NotFound().finish())
                 }
             }
@@ -58,5 +60,8 @@ pub async fn get_one_user(
         Ok(selected_user) => selected_user,
         Err(err) => return err,
     };
+    if authenticated {
+        return HttpResponse::Ok().json(user_find.to_detailed_public_user());
+    }
     HttpResponse::Ok().json(user_find.to_public_user())
 }
@@ -1,6 +1,6 @@
 use actix_web::{web, Scope};
 
-use super::{current_user, delete_user, get_one_user, update_user};
+use super::{current_user, delete_user, get_one_user, update_password, update_user};
 
 pub fn init_user() -> Scope {
     web::scope("/user")
@@ -8,4 +8,5 @@ pub fn init_user() -> Scope {
         .service(get_one_user::get_one_user)
         .service(delete_user::delete_user)
         .service(update_user::update_user)
+        .service(update_password::update_password)
 }
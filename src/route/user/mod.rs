@@ -2,4 +2,5 @@ pub mod current_user;
 pub mod delete_user;
 pub mod get_one_user;
 pub mod init;
+pub mod update_password;
 pub mod update_user;
@@ -0,0 +1,153 @@
+use crate::{
+    helper::header,
+    model::{
+        login_attempt::{LoginAttempt, LoginAttemptConfig},
+        password_hash::PasswordHashConfig,
+        password_policy::PasswordPolicy,
+        revocation::TokenDenylist,
+        token::TokenLifetime,
+        trusted_device::TrustedDevice,
+        user::{AuthClaims, User, UserPasswordUpdate},
+    },
+    route::auth::login::{release_login_attempt, reserve_login_attempt},
+};
+use actix_web::{http::header::ContentType, put, web, HttpRequest, HttpResponse, Responder};
+use deadpool_postgres::Pool;
+use tracing::Instrument;
+
+/// Update current user password
+///
+/// Require the current password, the new one must follow the password policy.
+/// A wrong current password count as a failed login, the account can be locked.
+/// The trusted devices and the failed logins of the user are forgotten.
+/// Not available for oidc users.
+#[utoipa::path(
+  tag = "User",
+  operation_id = "updatepassword",
+  request_body = UserPasswordUpdate,
+  path = "/api/user/password",
+  responses(
+      (status = 200, description = "success", body = String),
+      (status = 400, description = "Error message or failed password rules", body = PasswordPolicyError),
+      (status = 423, description = "Too many failed attempts", body = LoginLocked),
+      (status = 500, description = "Internal server error"),
+  ),
+  params(
+    ("Authorization-type" = Option<AuthType>, Header, description = "Type de token (oidc ou buildin), détecté depuis le token si absent")
+  ),
+  security(
+    ("access_token" = [])
+  )
+)]
+#[put("/password")]
+#[allow(clippy::too_many_arguments)]
+pub async fn update_password(
+    req: HttpRequest,
+    mut user: User,
+    auth_claims: AuthClaims,
+    db_pool: web::Data<Pool>,
+    token_lifetime: web::Data<TokenLifetime>,
+    token_denylist: web::Data<TokenDenylist>,
+    password_policy: web::Data<PasswordPolicy>,
+    password_hash_config: web::Data<PasswordHashConfig>,
+    login_attempt_config: web::Data<LoginAttemptConfig>,
+    body: web::Json<UserPasswordUpdate>,
+) -> impl Responder {
+    tracing::debug!(user = ?user.email, "Update du mot de passe de l'utilisateur courant");
+    let body = body.into_inner();
+    if user.is_oauth {
+        tracing::debug!(user = ?user.email ,"User is oauth");
+        return HttpResponse::BadRequest()
+            .content_type(ContentType::plaintext())
+            .body("Password can't be changed for an oidc user");
+    }
+    let pool: Pool = db_pool.into_inner().as_ref().clone();
+    let (_, ip) = header::extract_client_info(&req);
+    let reservation = match reserve_login_attempt(
+        pool.clone(),
+        &login_attempt_config,
+        &user.email,
+        ip.as_deref(),
+    )
+    .await
+    {
+        Ok(reservation) => reservation,
+        Err(err) => return err,
+    };
+    match user.compare_password(body.current_password).await {
+        Ok(true) => release_login_attempt(pool.clone(), &reservation).await,
+        Ok(false) => {
+            tracing::debug!(user = ?user.email ,"Current password invalid");
+            return HttpResponse::BadRequest()
+                .content_type(ContentType::plaintext())
+                .body("Current password invalid");
+        }
+        Err(err) => {
+            tracing::error!(error = ?err,user = ?user.email ,"Error while comparing password");
+            return HttpResponse::InternalServerError().finish();
+        }
+    }
+    if let Err(failed_rules) = password_policy
+        .validate(&body.new_password, &user.email)
+        .await
+    {
+        tracing::debug!(user = ?user.email, failed_rules = ?failed_rules ,"Password not valid");
+        return HttpResponse::BadRequest().json(PasswordPolicy::error(failed_rules));
+    }
+    if let Some(err) = user
+        .hash_password(body.new_password, &password_hash_config)
+        .await
+    {
+        tracing::error!(error = ?err,user = ?user.email ,"Error while hashing password");
+        return HttpResponse::InternalServerError().finish();
+    }
+
+    let update_password_span = tracing::info_span!("Update password");
+    match async move {
+        if let Err(err) = user.update_password(pool.clone()).await {
+            tracing::error!(error = ?err,user = ?user.email ,"Error while updating password");
+            return Err(HttpResponse::InternalServerError().finish());
+        }
+        if let Err(err) = TrustedDevice::delete_all_by_user(pool.clone(), user.id).await {
+            tracing::error!(error = ?err,user = ?user.email ,"Error while deleting trusted devices");
+            return Err(HttpResponse::InternalServerError().finish());
+        }
+        if let Err(err) = LoginAttempt::reset(pool.clone(), &user.email).await {
+            tracing::error!(error = ?err,user = ?user.email ,"Error while resetting failed logins");
+            return Err(HttpResponse::InternalServerError().finish());
+        }
+        if !body.revoke_other_sessions.unwrap_or(false) {
+            return Ok(());
+        }
+        let lifetime = token_lifetime.for_user(&user);
+        let revoked = match auth_claims.session_id {
+            Some(session_id) => {
+                token_denylist
+                    .revoke_other_sessions(pool.clone(), user.id, session_id, &lifetime)
+                    .await
+            }
+            None => {
+                token_denylist
+                    .revoke_all_sessions(pool.clone(), user.id, &lifetime)
+                    .await
+            }
+        };
+        let nbr = match revoked {
+            Ok(nbr) => nbr,
+            Err(err) => {
+                tracing::error!(error = ?err,user = ?user.email ,"Error while revoking sessions");
+                return Err(HttpResponse::InternalServerError().finish());
+            }
+        };
+        tracing::debug!(user = ?user.email, nbr_token = nbr ,"Other sessions revoked");
+        Ok(())
+    }
+    .instrument(update_password_span)
+    .await
+    {
+        Ok(_) => HttpResponse::Ok()
+            .content_type(ContentType::plaintext())
+            .body("Password updated"),
+        Err(err) => err,
+    }
+}
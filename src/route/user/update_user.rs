@@ -17,7 +17,7 @@ use tracing::Instrument;
       (status = 500, description = "Internal server error"),
   ),
   params(
-    ("Authorization-type" = AuthType, Header, description = "Type de token (oidc ou buildin)")
+    ("Authorization-type" = Option<AuthType>, Header, description = "Type de token (oidc ou buildin), détecté depuis le token si absent")
   ),
   security(
     ("access_token" = []),
@@ -0,0 +1,17 @@
+use crate::model::token::TokenKeys;
+use actix_web::{get, web, HttpResponse};
+
+/// Json Web Key Set
+///
+/// Public keys of the access token, allow other services to validate them without the signing secret
+#[utoipa::path(
+    tag = "Auth",
+    path = "/.well-known/jwks.json",
+    responses(
+        (status = 200, description = "Json Web Key Set", body = Object)
+    )
+)]
+#[get("/.well-known/jwks.json")]
+pub async fn jwks(token_keys: web::Data<TokenKeys>) -> HttpResponse {
+    HttpResponse::Ok().json(token_keys.jwks())
+}
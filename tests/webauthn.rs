@@ -0,0 +1,267 @@
+// Passkey registration and login against a software authenticator, the states are
+// round tripped through json like in the webauthn_challenges table
+use api::model::webauthn::{new_webauthn, PasskeyDecoy};
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use openssl::{
+    bn::{BigNum, BigNumContext},
+    ec::{EcGroup, EcKey},
+    ecdsa::EcdsaSig,
+    hash::{hash, MessageDigest},
+    nid::Nid,
+    pkey::Private,
+    rand::rand_bytes,
+};
+use serde_json::{json, Value};
+use webauthn_rs::prelude::{
+    Passkey, PasskeyAuthentication, PasskeyRegistration, PublicKeyCredential,
+    RegisterPublicKeyCredential,
+};
+
+const ORIGIN: &str = "http://localhost:5173";
+
+enum Cbor<'a> {
+    Int(i64),
+    Bytes(&'a [u8]),
+    Text(&'a str),
+    Map(Vec<(Cbor<'a>, Cbor<'a>)>),
+}
+
+impl Cbor<'_> {
+    fn encode(&self, out: &mut Vec<u8>) {
+        fn head(out: &mut Vec<u8>, major: u8, value: u64) {
+            match value {
+                0..=23 => out.push(major << 5 | value as u8),
+                24..=0xff => out.extend([major << 5 | 24, value as u8]),
+                _ => {
+                    out.push(major << 5 | 25);
+                    out.extend((value as u16).to_be_bytes());
+                }
+            }
+        }
+        match self {
+            Cbor::Int(value) if *value >= 0 => head(out, 0, *value as u64),
+            Cbor::Int(value) => head(out, 1, (-1 - *value) as u64),
+            Cbor::Bytes(bytes) => {
+                head(out, 2, bytes.len() as u64);
+                out.extend(*bytes);
+            }
+            Cbor::Text(text) => {
+                head(out, 3, text.len() as u64);
+                out.extend(text.as_bytes());
+            }
+            Cbor::Map(entries) => {
+                head(out, 5, entries.len() as u64);
+                for (key, value) in entries {
+                    key.encode(out);
+                    value.encode(out);
+                }
+            }
+        }
+    }
+}
+
+struct SoftAuthenticator {
+    key: EcKey<Private>,
+    credential_id: Vec<u8>,
+    counter: u32,
+}
+
+impl SoftAuthenticator {
+    fn new() -> SoftAuthenticator {
+        let group = EcGroup::from_curve_name(Nid::X9_62_PRIME256V1).unwrap();
+        let mut credential_id = vec![0; 32];
+        rand_bytes(&mut credential_id).unwrap();
+        SoftAuthenticator {
+            key: EcKey::generate(&group).unwrap(),
+            credential_id,
+            counter: 0,
+        }
+    }
+
+    fn client_data(kind: &str, challenge: &Value) -> Vec<u8> {
+        serde_json::to_vec(&json!({
+            "type": kind,
+            "challenge": challenge,
+            "origin": ORIGIN,
+            "crossOrigin": false,
+        }))
+        .unwrap()
+    }
+
+    fn authenticator_data(&self, rp_id: &str, flags: u8) -> Vec<u8> {
+        let mut data = hash(MessageDigest::sha256(), rp_id.as_bytes())
+            .unwrap()
+            .to_vec();
+        data.push(flags);
+        data.extend(self.counter.to_be_bytes());
+        data
+    }
+
+    // Answer navigator.credentials.create with a "none" attestation
+    fn create(&self, options: &Value) -> Value {
+        let public_key = &options["publicKey"];
+        let mut ctx = BigNumContext::new().unwrap();
+        let (mut x, mut y) = (BigNum::new().unwrap(), BigNum::new().unwrap());
+        self.key
+            .public_key()
+            .affine_coordinates(self.key.group(), &mut x, &mut y, &mut ctx)
+            .unwrap();
+        let (x, y) = (x.to_vec_padded(32).unwrap(), y.to_vec_padded(32).unwrap());
+        let mut cose_key = Vec::new();
+        Cbor::Map(vec![
+            (Cbor::Int(1), Cbor::Int(2)),
+            (Cbor::Int(3), Cbor::Int(-7)),
+            (Cbor::Int(-1), Cbor::Int(1)),
+            (Cbor::Int(-2), Cbor::Bytes(&x)),
+            (Cbor::Int(-3), Cbor::Bytes(&y)),
+        ])
+        .encode(&mut cose_key);
+
+        // User present, user verified and attested credential data
+        let mut auth_data = self.authenticator_data(public_key["rp"]["id"].as_str().unwrap(), 0x45);
+        auth_data.extend([0; 16]);
+        auth_data.extend((self.credential_id.len() as u16).to_be_bytes());
+        auth_data.extend(&self.credential_id);
+        auth_data.extend(cose_key);
+        let mut attestation = Vec::new();
+        Cbor::Map(vec![
+            (Cbor::Text("fmt"), Cbor::Text("none")),
+            (Cbor::Text("attStmt"), Cbor::Map(vec![])),
+            (Cbor::Text("authData"), Cbor::Bytes(&auth_data)),
+        ])
+        .encode(&mut attestation);
+
+        let client_data = Self::client_data("webauthn.create", &public_key["challenge"]);
+        json!({
+            "id": URL_SAFE_NO_PAD.encode(&self.credential_id),
+            "rawId": URL_SAFE_NO_PAD.encode(&self.credential_id),
+            "type": "public-key",
+            "response": {
+                "attestationObject": URL_SAFE_NO_PAD.encode(attestation),
+                "clientDataJSON": URL_SAFE_NO_PAD.encode(client_data),
+            },
+            "extensions": {},
+        })
+    }
+
+    // Answer navigator.credentials.get with the user verified
+    fn get(&mut self, options: &Value) -> Value {
+        let public_key = &options["publicKey"];
+        self.counter += 1;
+        let auth_data = self.authenticator_data(public_key["rpId"].as_str().unwrap(), 0x05);
+        let client_data = Self::client_data("webauthn.get", &public_key["challenge"]);
+        let mut signed = auth_data.clone();
+        signed.extend(
+            hash(MessageDigest::sha256(), &client_data)
+                .unwrap()
+                .to_vec(),
+        );
+        let digest = hash(MessageDigest::sha256(), &signed).unwrap();
+        let signature = EcdsaSig::sign(&digest, &self.key)
+            .unwrap()
+            .to_der()
+            .unwrap();
+        json!({
+            "id": URL_SAFE_NO_PAD.encode(&self.credential_id),
+            "rawId": URL_SAFE_NO_PAD.encode(&self.credential_id),
+            "type": "public-key",
+            "response": {
+                "authenticatorData": URL_SAFE_NO_PAD.encode(auth_data),
+                "clientDataJSON": URL_SAFE_NO_PAD.encode(client_data),
+                "signature": URL_SAFE_NO_PAD.encode(signature),
+                "userHandle": null,
+            },
+            "extensions": {},
+        })
+    }
+}
+
+fn register(authenticator: &SoftAuthenticator) -> Passkey {
+    let webauthn = new_webauthn().unwrap();
+    let (options, state) = webauthn
+        .start_passkey_registration(uuid::Uuid::new_v4(), "test@test.fr", "Test Test", None)
+        .unwrap();
+    let state: PasskeyRegistration =
+        serde_json::from_value(serde_json::to_value(state).unwrap()).unwrap();
+    let credential: RegisterPublicKeyCredential =
+        serde_json::from_value(authenticator.create(&serde_json::to_value(options).unwrap()))
+            .unwrap();
+    webauthn
+        .finish_passkey_registration(&credential, &state)
+        .unwrap()
+}
+
+#[test]
+fn register_and_login_with_passkey() {
+    let webauthn = new_webauthn().unwrap();
+    let mut authenticator = SoftAuthenticator::new();
+    let mut passkey = register(&authenticator);
+    assert_eq!(passkey.cred_id().0, authenticator.credential_id);
+
+    for _ in 0..2 {
+        let (options, state) = webauthn
+            .start_passkey_authentication(&[passkey.clone()])
+            .unwrap();
+        let state: PasskeyAuthentication =
+            serde_json::from_value(serde_json::to_value(state).unwrap()).unwrap();
+        let credential: PublicKeyCredential =
+            serde_json::from_value(authenticator.get(&serde_json::to_value(options).unwrap()))
+                .unwrap();
+        let result = webauthn
+            .finish_passkey_authentication(&credential, &state)
+            .unwrap();
+        assert!(result.user_verified());
+        assert_eq!(result.counter(), authenticator.counter);
+        passkey.update_credential(&result);
+    }
+}
+
+#[test]
+fn login_with_another_authenticator_fails() {
+    let webauthn = new_webauthn().unwrap();
+    let passkey = register(&SoftAuthenticator::new());
+    let mut other = SoftAuthenticator::new();
+    other.credential_id = passkey.cred_id().0.clone();
+
+    let (options, state) = webauthn.start_passkey_authentication(&[passkey]).unwrap();
+    let credential: PublicKeyCredential =
+        serde_json::from_value(other.get(&serde_json::to_value(options).unwrap())).unwrap();
+    assert!(webauthn
+        .finish_passkey_authentication(&credential, &state)
+        .is_err());
+}
+
+#[test]
+fn decoy_looks_like_a_real_challenge() {
+    let webauthn = new_webauthn().unwrap();
+    let decoy = PasskeyDecoy::new().unwrap();
+    let passkey = register(&SoftAuthenticator::new());
+    let (real, _) = webauthn.start_passkey_authentication(&[passkey]).unwrap();
+    let real = serde_json::to_value(real).unwrap();
+    let fake =
+        serde_json::to_value(decoy.challenge(&webauthn, "unknown@test.fr").unwrap()).unwrap();
+
+    fn shape(value: &Value) -> Value {
+        match value {
+            Value::Object(map) => map
+                .iter()
+                .map(|(key, value)| (key.clone(), shape(value)))
+                .collect(),
+            Value::Array(values) => values.iter().map(shape).collect(),
+            Value::String(value) => json!(value.len()),
+            _ => Value::Null,
+        }
+    }
+    assert_eq!(shape(&real), shape(&fake));
+
+    let allowed = |options: &Value| options["publicKey"]["allowCredentials"][0]["id"].clone();
+    let again =
+        serde_json::to_value(decoy.challenge(&webauthn, "Unknown@test.fr").unwrap()).unwrap();
+    let other = serde_json::to_value(decoy.challenge(&webauthn, "other@test.fr").unwrap()).unwrap();
+    assert_eq!(allowed(&fake), allowed(&again));
+    assert_ne!(allowed(&fake), allowed(&other));
+    assert_ne!(
+        fake["publicKey"]["challenge"],
+        again["publicKey"]["challenge"]
+    );
+}